@@ -12,7 +12,14 @@
 #include "net.h"
 
 #define SIZE_ETHERNET    14
-#define MAGIC 0xa387
+// size of the header of the Linux "cooked" capture, e.g. of the device "any"
+#define SIZE_LINUX_SLL   16
+#define SIZE_IPV6        40
+// next header value of ICMPv6 in an IPv6 header
+#define PROTO_ICMPV6     58
+// types of ICMPv6 echo requests and replies
+#define ICMPV6_REQUEST   128
+#define ICMPV6_REPLY     129
 
 #undef DEBUG_NETC
 
@@ -42,50 +49,82 @@ struct icmp
 };
 
 
-int send_icmp(const char* dstip, const char* buf, u_int16_t size)
+// Sends an ICMP echo message of the given type (8 = request, 0 = reply). If the
+// destination is an IPv6 address the message is sent as the ICMPv6 echo message of
+// the same type.
+static int send_echo(const char* dstip, u_int8_t type, u_int16_t id, u_int16_t seq, const char* buf, u_int16_t size)
 {
 	int ret = -1;
-	char*     packet = (char*) malloc(sizeof(struct icmp) + size);
+	int v6  = strchr(dstip, ':') != 0;
 
 	if (size > (1 << 14)) {
 		perror("packet too large.");
 		return ret;
 	}
 
+	char*     packet = (char*) malloc(sizeof(struct icmp) + size);
+
 	if (!packet) {
 		perror("malloc()");
 		return ret;
 	}
 
-	u_int16_t seq = rand();
-
 	// copy data into icmp packet
 	memcpy(packet + sizeof(struct icmp), buf, size);
 
 	struct icmp* i = (struct icmp*) packet;
-	i->type = 8; // echo request
-	i->code = 1;
+	i->type = v6 ? (type == 8 ? ICMPV6_REQUEST : ICMPV6_REPLY) : type;
+	i->code = 0;
 	i->sum = 0;
-	i->id = htons(MAGIC);
+	i->id = htons(id);
 	i->seq = htons(seq);
-	i->sum = chksum(packet, sizeof(struct icmp) + size);
+	// The checksum of ICMPv6 covers parts of the IPv6 header and is computed by the kernel.
+	if (!v6) {
+		i->sum = chksum(packet, sizeof(struct icmp) + size);
+	}
 
 	// open socket and send packet
-	int sd = socket(PF_INET, SOCK_RAW, IPPROTO_ICMP);
+	int sd = v6 ? socket(PF_INET6, SOCK_RAW, IPPROTO_ICMPV6) : socket(PF_INET, SOCK_RAW, IPPROTO_ICMP);
 	if (sd < 0) {
+		free(packet);
 		return ret;
 	}
 
-	struct sockaddr_in s;
-	s.sin_family = AF_INET;
-	s.sin_addr.s_addr = inet_addr(dstip);
+	struct sockaddr_storage s;
+	socklen_t slen;
+	int valid;
+	memset(&s, 0, sizeof(s));
+	if (v6) {
+		struct sockaddr_in6* s6 = (struct sockaddr_in6*) &s;
+		s6->sin6_family = AF_INET6;
+		valid = inet_pton(AF_INET6, dstip, &s6->sin6_addr) == 1;
+		slen = sizeof(struct sockaddr_in6);
+	} else {
+		struct sockaddr_in* s4 = (struct sockaddr_in*) &s;
+		s4->sin_family = AF_INET;
+		valid = inet_pton(AF_INET, dstip, &s4->sin_addr) == 1;
+		slen = sizeof(struct sockaddr_in);
+	}
 
-	if (sendto(sd, packet, sizeof(struct icmp) + size, 0, (struct sockaddr*) &s, sizeof(s)) < 0) {
-		return ret;
+	if (valid && sendto(sd, packet, sizeof(struct icmp) + size, 0, (struct sockaddr*) &s, slen) >= 0) {
+		ret = 0;
 	}
 	close(sd);
+	free(packet);
 
-	return 0;
+	return ret;
+}
+
+int send_icmp(const char* dstip, u_int16_t id, u_int16_t seq, const char* buf, u_int16_t size)
+{
+	return send_echo(dstip, 8, id, seq, buf, size);
+}
+
+int send_icmp_reply(const char* dstip, u_int32_t token, const char* buf, u_int16_t size)
+{
+	// The reply must carry the identifier and the sequence number of the request.
+	// Otherwise a NAT gateway does not forward it to the host which sent the request.
+	return send_echo(dstip, 0, token >> 16, token & 0xffff, buf, size);
 }
 
 pcap_t* setup_pcap(const char* dev, const char* filter)
@@ -124,167 +163,137 @@ struct arguments
 	pcap_t*  handle;
 	callback cb;
 	void*    target;
+	// number of bytes of the link-layer header in front of the IP packet
+	u_int32_t offset;
 };
 
-// TODO refactor: check_ip_packet and got_packet
-int check_ip_packet(const struct pcap_pkthdr* h, const u_char* packet)
+// An echo message which has been captured.
+struct echo
 {
-	// at least 20 bytes are required
-	if (h->len < 20) {
-		return -1;
-	}
-
-	u_int16_t iphdrlen = *packet & 0xf;       // little endian
-	u_int8_t  proto    = *(packet + 9);       // protocol (should be 1)
-	u_int16_t iplen    = ntohs(*(u_int16_t*)(packet + 2));
-
-	// check length of packet
-	if (iplen < iphdrlen * 4) {
-		return -1;
-	}
-	// check protocol
-	if (proto != 1) {
-		return -1;
-	}
-
-	if (h->len < iphdrlen * 4 + sizeof(struct icmp)) {
-		return -1;
-	}
-
-	packet += iphdrlen * 4;
-	struct icmp* i = (struct icmp*) packet;
-
-	if (i->type != 0 && i->type != 8) { // check that ping or pong
-		return -1;
-	}
+	const char* data;
+	int         datalen;
+	int         type;       // PING or PONG
+	u_int32_t   token;
+	char        srcip[INET6_ADDRSTRLEN];
+};
 
-	if (ntohs(i->id) != MAGIC) {
-		return -1;
+// Parses an ICMP or ICMPv6 echo message of len bytes. Returns 0 on success.
+static int parse_echo(const u_char* packet, int len, u_int8_t request, u_int8_t reply, struct echo* e)
+{
+	if (len < (int) sizeof(struct icmp)) {
+		return INVALID;
 	}
 
-	int datalen = iplen - iphdrlen * 4 - sizeof(struct icmp);
-	int type    = (i->type == 0 ? PONG : PING);
-	packet += sizeof(struct icmp);
+	const struct icmp* i = (const struct icmp*) packet;
 
-	if (iphdrlen * 4 + sizeof(struct icmp) > iplen) {
-		return -1;
-	}
-
-	if (h->len < iphdrlen * 4 + sizeof(struct icmp) + datalen) {
-		return -1;
+	// check that ping or pong; whether the packet has been sent by a peer is checked
+	// with the authentication tag of the packet
+	if (i->type != request && i->type != reply) {
+		return INVALID;
 	}
 
+	e->type    = (i->type == reply ? PONG : PING);
+	e->token   = ((u_int32_t) ntohs(i->id) << 16) | ntohs(i->seq);
+	e->data    = (const char*) packet + sizeof(struct icmp);
+	e->datalen = len - sizeof(struct icmp);
 	return 0;
 }
 
-void got_packet(u_char* args, const struct pcap_pkthdr* h, const u_char* packet)
+// Parses an IPv4 packet of len bytes which contains an ICMP echo message.
+static int parse_ipv4(const u_char* packet, u_int32_t len, struct echo* e)
 {
-	struct    arguments* a = (struct arguments*) args;
-	char      buf[128];
-	u_int32_t size_ethernet = SIZE_ETHERNET;
-
-#ifdef DEBUG_NETC
-    FILE* f;
-    int c;
-    f = fopen("/tmp/icmp.log", "a");
-#endif
-
-	// check if this is already an IP packet; otherwise it could be an etheret
-	// frame
-	if (check_ip_packet(h, packet) == 0) {
-		size_ethernet = 0;
-	}
-
 	// at least 20 bytes are required
-	if (h->len < 20) {
-		a->cb(a->target, 0, 0, INVALID_LENGTH, 0);
-		return;
+	if (len < 20) {
+		return INVALID_LENGTH;
 	}
 
-	packet += size_ethernet;
-
 	u_int16_t iphdrlen = *packet & 0xf;       // little endian
 	u_int8_t  proto    = *(packet + 9);       // protocol (should be 1)
 	u_int16_t iplen    = ntohs(*(u_int16_t*)(packet + 2));
-	u_int32_t srcip    = *(u_int32_t*)(packet + 12);
-
-	inet_ntop(AF_INET, &srcip, buf, sizeof(buf));
 
 	// check length of packet
-	if (iplen < iphdrlen * 4) {
-		a->cb(a->target, 0, 0, INVALID_IP_LENGTH, 0);
-#ifdef DEBUG_NETC
-		fprintf(f, "Packet has invalid length.\n");
-		fflush(f);
-		fclose(f);
-#endif
-		return;
+	if (iphdrlen < 5 || iplen < iphdrlen * 4 || len < iplen) {
+		return INVALID_IP_LENGTH;
 	}
 	// check protocol
 	if (proto != 1) {
-		a->cb(a->target, 0, 0, INVALID_PROTOCOL, 0);
-		return;
+		return INVALID_PROTOCOL;
 	}
 
-	if (h->len < size_ethernet + iphdrlen * 4 + sizeof(struct icmp)) {
-		a->cb(a->target, 0, 0, INVALID, 0);
-#ifdef DEBUG_NETC
-		fprintf(f, "Packet has invalid length (2).\n");
-		fflush(f);
-		fclose(f);
-#endif
-		return;
+	inet_ntop(AF_INET, packet + 12, e->srcip, sizeof(e->srcip));
+	return parse_echo(packet + iphdrlen * 4, iplen - iphdrlen * 4, 8, 0, e);
+}
+
+// Parses an IPv6 packet of len bytes which contains an ICMPv6 echo message. Packets
+// with extension headers are not supported.
+static int parse_ipv6(const u_char* packet, u_int32_t len, struct echo* e)
+{
+	if (len < SIZE_IPV6) {
+		return INVALID_LENGTH;
 	}
 
-	packet += iphdrlen * 4;
-	struct icmp* i = (struct icmp*) packet;
+	u_int16_t paylen = ntohs(*(u_int16_t*)(packet + 4));
+	u_int8_t  next   = *(packet + 6);
 
-	// check that ping or pong
-	if (i->type != 0 && i->type != 8) {
-		a->cb(a->target, 0, 0, INVALID, 0);
-		return;
+	if (len < (u_int32_t) SIZE_IPV6 + paylen) {
+		return INVALID_IP_LENGTH;
+	}
+	if (next != PROTO_ICMPV6) {
+		return INVALID_PROTOCOL;
 	}
 
-	if (ntohs(i->id) != MAGIC) {
-		a->cb(a->target, 0, 0, INVALID, 0);
-		return;
+	inet_ntop(AF_INET6, packet + 8, e->srcip, sizeof(e->srcip));
+	return parse_echo(packet + SIZE_IPV6, paylen, ICMPV6_REQUEST, ICMPV6_REPLY, e);
+}
+
+// Parses an IPv4 or IPv6 packet of len bytes. Returns 0 on success.
+static int parse_ip(const u_char* packet, u_int32_t len, struct echo* e)
+{
+	if (len < 1) {
+		return INVALID_LENGTH;
+	}
+	switch (*packet >> 4) {
+	case 4:
+		return parse_ipv4(packet, len, e);
+	case 6:
+		return parse_ipv6(packet, len, e);
+	default:
+		return INVALID;
 	}
+}
 
-	int datalen = iplen - iphdrlen * 4 - sizeof(struct icmp);
-	int type    = (i->type == 0 ? PONG : PING);
-	packet += sizeof(struct icmp);
+void got_packet(u_char* args, const struct pcap_pkthdr* h, const u_char* packet)
+{
+	struct arguments* a = (struct arguments*) args;
+	struct echo       e;
 
-	if (iphdrlen * 4 + sizeof(struct icmp) > iplen) {
-		a->cb(a->target, 0, 0, INVALID, 0);
-#ifdef DEBUG_NETC
-		fprintf(f, "Packet has invalid length (3).\n");
-		fflush(f);
-		fclose(f);
-#endif
-		return;
+	int r = INVALID_LENGTH;
+	if (h->caplen > a->offset) {
+		r = parse_ip(packet + a->offset, h->caplen - a->offset, &e);
 	}
 
-	if (h->len < size_ethernet + iphdrlen * 4 + sizeof(struct icmp) + datalen) {
-		a->cb(a->target, 0, 0, INVALID, 0);
-#ifdef DEBUG_NETC
-		fprintf(f, "Packet has invalid length (4).\n");
-		fflush(f);
-		fclose(f);
-#endif
+	if (r != 0) {
+		a->cb(a->target, 0, 0, r, 0, 0);
 		return;
 	}
 
-#ifdef DEBUG_NETC
-		fprintf(f, "Calling callback. type = %d, datalen = %d.\n", type, datalen);
-		fflush(f);
-		for (c = 0; c < datalen; c++) {
-		    fprintf(f, "%x ", packet[c]);
-		}
-		fprintf(f, "\n");
-		fflush(f);
-		fclose(f);
-#endif
-	a->cb(a->target, (const char*) packet, datalen, type, buf);
+	a->cb(a->target, e.data, e.datalen, e.type, (u8_ptr) e.srcip, e.token);
+}
+
+// Returns the size of the link-layer header of the given type or -1 if the type is
+// not supported.
+static int link_offset(int dlt)
+{
+	switch (dlt) {
+	case DLT_EN10MB:
+		return SIZE_ETHERNET;
+	case DLT_RAW:
+		return 0;
+	case DLT_LINUX_SLL:
+		return SIZE_LINUX_SLL;
+	default:
+		return -1;
+	}
 }
 
 static void* worker_thread(void* args)
@@ -300,7 +309,11 @@ static void* worker_thread(void* args)
 
 int recv_callback(void* target, const char* dev, callback cb) {
 
-	pcap_t* handle = setup_pcap(dev, "icmp && (icmp[icmptype] = 8 || icmp[icmptype] = 0)");
+	// Extension headers are not supported, so the type of an ICMPv6 message follows the
+	// IPv6 header directly.
+	pcap_t* handle = setup_pcap(dev,
+		"(icmp && (icmp[icmptype] = 8 || icmp[icmptype] = 0)) || "
+		"(icmp6 && (ip6[40] = 128 || ip6[40] = 129))");
 
 #ifdef DEBUG_NETC
     FILE* f;
@@ -311,11 +324,17 @@ int recv_callback(void* target, const char* dev, callback cb) {
 #endif
 
 	if (handle) {
+		int offset = link_offset(pcap_datalink(handle));
+		if (offset < 0) {
+			pcap_close(handle);
+			return -1;
+		}
 		pthread_t t;
 		struct arguments* args = (struct arguments*) malloc(sizeof(struct arguments));
 		args->handle = handle;
 		args->cb = cb;
 		args->target = target;
+		args->offset = offset;
 		int r = pthread_create(&t, NULL, &worker_thread, (void*) args);
 		if (r != 0) {
 			return -1;
@@ -11,10 +11,13 @@ enum {
 };
 
 typedef const unsigned char* u8_ptr;
-typedef void(*callback)(void*, const char* buf, u_int32_t len, u_int32_t type, u8_ptr srcip);
+// token: identifier (upper 16 bits) and sequence number (lower 16 bits) of the echo message
+typedef void(*callback)(void*, const char* buf, u_int32_t len, u_int32_t type, u8_ptr srcip, u_int32_t token);
 
-// returns 0 on success
-int         send_icmp(const char* dstip, const char* buf, u_int16_t size);
+// sends an echo request with the given identifier and sequence number; returns 0 on success
+int         send_icmp(const char* dstip, u_int16_t id, u_int16_t seq, const char* buf, u_int16_t size);
+// sends an echo reply to the echo request with the given token; returns 0 on success
+int         send_icmp_reply(const char* dstip, u_int32_t token, const char* buf, u_int16_t size);
 // returns 0 on success
 int         recv_callback(void* target, const char* dev, callback);
 
@@ -0,0 +1,246 @@
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::sha2::Sha256;
+use rand::rngs::OsRng;
+use rand::Rng;
+
+use crate::compress::{compress, decompress};
+
+/// Version of the envelope for data encrypted with ChaCha20-Poly1305.
+///
+/// The envelope has the following format:
+/// u8      : version { 2 } | flags
+/// [u8; 8] : nonce
+/// Vec<u8> : ciphertext
+/// [u8; 16]: authentication tag over the version and the ciphertext
+pub const VERSION: u8 = 2;
+
+/// Flag in the version byte which is set if the plaintext has been compressed before it
+/// has been encrypted.
+pub const COMPRESSED: u8 = 0x80;
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 8;
+pub const TAG_LEN: usize = 16;
+
+pub type ResultVec = Result<Vec<u8>, &'static str>;
+
+/// Authenticated encryption with ChaCha20-Poly1305.
+pub struct Aead {
+    key: Vec<u8>
+}
+
+impl Aead {
+
+    /// Returns a new instance with a random key.
+    #[cfg(test)]
+    pub fn new() -> Result<Aead, &'static str> {
+        Aead::from_key(random_u8(KEY_LEN)?)
+    }
+
+    /// Returns a new instance with the given key.
+    pub fn from_key(key: Vec<u8>) -> Result<Aead, &'static str> {
+        match key.len() {
+            KEY_LEN => Ok(Aead { key }),
+            _ => Err("Invalid key length.")
+        }
+    }
+
+    /// Returns a new instance with a key derived from the given secret.
+    pub fn from_secret(secret: &[u8], info: &str) -> Result<Aead, &'static str> {
+        Aead::from_key(derive_key(secret, info, KEY_LEN))
+    }
+
+    /// Returns the current key used by this instance.
+    pub fn key(&self) -> Vec<u8> {
+        self.key.clone()
+    }
+
+    /// Encrypts the data with the current key and a new nonce and returns the envelope.
+    pub fn encrypt(&self, data: &[u8]) -> ResultVec {
+        self.seal(data, VERSION)
+    }
+
+    /// Like `encrypt` but compresses the data before it is encrypted. If the data does not
+    /// shrink it is encrypted without compression.
+    pub fn encrypt_compressed(&self, data: &[u8]) -> ResultVec {
+        match compress(data) {
+            Some(c) => self.seal(&c, VERSION | COMPRESSED),
+            None => self.seal(data, VERSION)
+        }
+    }
+
+    fn seal(&self, data: &[u8], version: u8) -> ResultVec {
+
+        let nonce = random_u8(NONCE_LEN)?;
+        let mut cipher = vec![0; data.len()];
+        let mut tag = [0; TAG_LEN];
+
+        ChaCha20Poly1305::new(&self.key, &nonce, &[version]).encrypt(data, &mut cipher, &mut tag);
+
+        let mut v = vec![version];
+        v.extend(nonce);
+        v.extend(cipher);
+        v.extend(tag.iter());
+        Ok(v)
+    }
+
+    /// Verifies and decrypts the envelope. Compressed data is decompressed.
+    pub fn decrypt(&self, envelope: &[u8]) -> ResultVec {
+
+        if envelope.len() < 1 + NONCE_LEN + TAG_LEN {
+            return Err("Ciphertext has invalid length.");
+        }
+
+        let version = envelope[0];
+        if version & !COMPRESSED != VERSION {
+            return Err("Unsupported envelope version. The peer might use an old version of stealthy.");
+        }
+
+        let (nonce, rest) = envelope[1..].split_at(NONCE_LEN);
+        let (cipher, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let mut plain = vec![0; cipher.len()];
+
+        // The flags are authenticated as part of the version.
+        if !ChaCha20Poly1305::new(&self.key, nonce, &[version]).decrypt(cipher, &mut plain, tag) {
+            return Err("Authentication of ciphertext failed.");
+        }
+        match version & COMPRESSED {
+            0 => Ok(plain),
+            _ => decompress(&plain)
+        }
+    }
+}
+
+/// Derives a key with n bytes from the given secret via HKDF-SHA256. The info string is
+/// used to derive different keys for different purposes from the same secret.
+pub fn derive_key(secret: &[u8], info: &str, n: usize) -> Vec<u8> {
+    let mut prk = [0; 32];
+    hkdf_extract(Sha256::new(), b"stealthy", secret, &mut prk);
+    let mut okm = vec![0; n];
+    hkdf_expand(Sha256::new(), &prk, info.as_bytes(), &mut okm);
+    okm
+}
+
+/// Returns cryptographically secure pseudorandom numbers for keys and nonces.
+pub fn random_u8(n: usize) -> ResultVec {
+    match OsRng::new() {
+        Ok(mut r) => Ok((0..n).map(|_| { r.gen() }).collect()),
+        _ => Err("Could not get OsRng.")
+    }
+}
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Aead, derive_key, COMPRESSED, KEY_LEN, NONCE_LEN, TAG_LEN, VERSION};
+    use crate::blowfish::Blowfish;
+    use crate::cryp::from_hex;
+
+    #[test]
+    fn test_from_key() {
+        assert!(Aead::from_key(vec![1; KEY_LEN]).is_ok());
+        assert!(Aead::from_key(vec![1; 16]).is_err());
+        assert_eq!(Aead::new().unwrap().key().len(), KEY_LEN);
+    }
+
+    #[test]
+    fn test_encryption_decryption() {
+        let a = Aead::new().unwrap();
+        for n in [0, 1, 15, 16, 17, 1000] {
+            let plain = (0..n).map(|x| x as u8).collect::<Vec<_>>();
+            let cipher = a.encrypt(&plain).unwrap();
+
+            assert_eq!(cipher.len(), 1 + NONCE_LEN + n + TAG_LEN);
+            assert_eq!(cipher[0], VERSION);
+            assert_eq!(a.decrypt(&cipher).unwrap(), plain);
+        }
+    }
+
+    #[test]
+    fn test_random_nonce() {
+        let a = Aead::new().unwrap();
+        let plain = b"hello".to_vec();
+        assert!(a.encrypt(&plain).unwrap() != a.encrypt(&plain).unwrap());
+    }
+
+    #[test]
+    fn test_tampered_ciphertext() {
+        let a = Aead::new().unwrap();
+        let cipher = a.encrypt(b"hello world").unwrap();
+
+        // Flipping any bit must be detected.
+        for i in 0..cipher.len() {
+            let mut c = cipher.clone();
+            c[i] ^= 1;
+            assert!(a.decrypt(&c).is_err());
+        }
+        // Truncated ciphertexts.
+        assert!(a.decrypt(&cipher[..cipher.len() - 1]).is_err());
+        assert!(a.decrypt(&cipher[..5]).is_err());
+    }
+
+    #[test]
+    fn test_compression() {
+        let a = Aead::new().unwrap();
+        let text = b"hello hello hello hello hello hello hello hello hello".to_vec();
+        let cipher = a.encrypt_compressed(&text).unwrap();
+        assert_eq!(cipher[0], VERSION | COMPRESSED);
+        assert!(cipher.len() < a.encrypt(&text).unwrap().len());
+        assert_eq!(a.decrypt(&cipher).unwrap(), text);
+
+        // Data which does not shrink is sent without compression.
+        let plain = b"hi".to_vec();
+        let cipher = a.encrypt_compressed(&plain).unwrap();
+        assert_eq!(cipher[0], VERSION);
+        assert_eq!(a.decrypt(&cipher).unwrap(), plain);
+
+        // The flag is authenticated.
+        let mut c = a.encrypt_compressed(&text).unwrap();
+        c[0] = VERSION;
+        assert!(a.decrypt(&c).is_err());
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let cipher = Aead::new().unwrap().encrypt(b"hello").unwrap();
+        assert!(Aead::new().unwrap().decrypt(&cipher).is_err());
+    }
+
+    /// "hello from an old client" encrypted by a client of an older version of stealthy
+    /// with the key 11111111111111111111111111111111.
+    const LEGACY_CIPHER: [u8; 40] = [
+        190, 159, 157, 214, 208, 174, 141, 126, 43, 121, 233, 173, 122, 28, 24, 228,
+        106, 242, 123, 236, 191, 71, 218, 13, 93, 178, 128, 122, 79, 191, 0, 50,
+        79, 214, 204, 210, 96, 49, 119, 206
+    ];
+
+    #[test]
+    fn test_legacy_payload() {
+        // The payload of an old client is still read in legacy mode.
+        let legacy = Blowfish::from_key(from_hex("11111111111111111111111111111111".to_string()).unwrap()).unwrap();
+        assert_eq!(legacy.decrypt(&LEGACY_CIPHER).unwrap(), b"hello from an old client".to_vec());
+
+        // It does not have a valid envelope.
+        let a = Aead::from_secret(&[0x11; 16], "stealthy message key").unwrap();
+        assert_eq!(a.decrypt(&LEGACY_CIPHER), Err("Unsupported envelope version. The peer might use an old version of stealthy."));
+    }
+
+    #[test]
+    fn test_derive_key() {
+        let a = derive_key(b"secret", "a", 32);
+        assert_eq!(a.len(), 32);
+        assert_eq!(a, derive_key(b"secret", "a", 32));
+        assert!(a != derive_key(b"secret", "b", 32));
+        assert!(a != derive_key(b"secreT", "a", 32));
+
+        let x = Aead::from_secret(b"secret", "a").unwrap();
+        let y = Aead::from_secret(b"secret", "a").unwrap();
+        assert_eq!(y.decrypt(&x.encrypt(b"hello").unwrap()).unwrap(), b"hello".to_vec());
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::HashSet;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::delivery::{push_slice, push_value};
+use crate::download::set_metadata;
+use crate::message::sanitize_filename;
+use crate::transfer::mtime;
+
+const DIRECTORY: u8 = 1;
+const FILE: u8 = 2;
+
+/// Several files and directories which are sent as a single stream.
+///
+/// The stream is not written to disk. Each part of it is read from the files when it is
+/// sent, so the transfer of an archive can be resumed like the transfer of a single file.
+/// The entries are sorted by their paths, i.e. the same files always result in the same
+/// stream. The stream consists of one entry for each file and directory:
+///
+/// u8   : type { 1 = directory, 2 = file }
+/// u16  : length of the path
+/// path : path relative to the root of the archive with / as separator
+/// u32  : mode
+/// u64  : mtime
+/// file : u64 size, content
+pub struct Archive {
+    entries: Vec<Entry>,
+    size: u64,
+}
+
+struct Entry {
+    /// Position of the entry in the stream.
+    offset: u64,
+    header: Vec<u8>,
+    /// The file whose content follows the header and its size.
+    file: Option<(PathBuf, u64)>,
+}
+
+impl Entry {
+    fn len(&self) -> u64 {
+        self.header.len() as u64 + self.file.as_ref().map_or(0, |(_, size)| *size)
+    }
+}
+
+impl Archive {
+
+    /// Creates an archive of the given files and directories. Directories are added with
+    /// their content. Symbolic links and special files inside of directories are skipped.
+    /// Paths which are given more than once or which are inside of a given directory,
+    /// e.g. when a pattern matches both a directory and its content, are added only once.
+    pub fn new(paths: &[PathBuf]) -> Result<Archive, &'static str> {
+        let mut paths = paths.to_vec();
+        paths.sort();
+        paths.dedup();
+        let dirs = paths.iter().filter(|p| p.is_dir()).cloned().collect::<Vec<_>>();
+        paths.retain(|p| !dirs.iter().any(|d| p != d && p.starts_with(d)));
+
+        let mut names = HashSet::new();
+        let mut a = Archive { entries: vec![], size: 0 };
+        for path in &paths {
+            let meta = fs::metadata(path).map_err(|_| "Could not read file.")?;
+            let name = file_name(path)?;
+            if !names.insert(name.clone()) {
+                return Err("Several files have the same name.");
+            }
+            a.add(path, name, &meta)?;
+        }
+        Ok(a)
+    }
+
+    /// Creates an archive of the content of the given directory.
+    pub fn from_dir(dir: &Path) -> Result<Archive, &'static str> {
+        let mut a = Archive { entries: vec![], size: 0 };
+        a.add_content(dir, "")?;
+        Ok(a)
+    }
+
+    fn add(&mut self, path: &Path, name: String, meta: &Metadata) -> Result<(), &'static str> {
+        let typ = match meta {
+            m if m.is_dir() => DIRECTORY,
+            m if m.is_file() => FILE,
+            _ => return Ok(())
+        };
+        if name.len() > u16::MAX as usize {
+            return Err("File name is too long.");
+        }
+
+        let mut header = vec![typ];
+        push_value(&mut header, name.len() as u64, 2);
+        push_slice(&mut header, name.as_bytes());
+        push_value(&mut header, meta.permissions().mode() as u64, 4);
+        push_value(&mut header, mtime(meta), 8);
+        let file = match typ {
+            FILE => {
+                push_value(&mut header, meta.len(), 8);
+                Some((path.to_path_buf(), meta.len()))
+            },
+            _ => None
+        };
+
+        let e = Entry { offset: self.size, header, file };
+        self.size += e.len();
+        self.entries.push(e);
+
+        if typ == DIRECTORY {
+            self.add_content(path, &format!("{}/", name))?;
+        }
+        Ok(())
+    }
+
+    fn add_content(&mut self, dir: &Path, prefix: &str) -> Result<(), &'static str> {
+        let mut paths = fs::read_dir(dir).map_err(|_| "Could not read directory.")?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            let meta = fs::symlink_metadata(&path).map_err(|_| "Could not read file.")?;
+            self.add(&path, format!("{}{}", prefix, file_name(&path)?), &meta)?;
+        }
+        Ok(())
+    }
+
+    /// Fills the buffer with the bytes of the stream at the given offset.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        if offset + buf.len() as u64 > self.size {
+            return Err("Could not read file.");
+        }
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let mut i = match self.entries.binary_search_by_key(&offset, |e| e.offset) {
+            Ok(i) => i,
+            Err(i) => i - 1
+        };
+        let mut done = 0;
+        while done < buf.len() {
+            let e = &self.entries[i];
+            let pos = offset + done as u64 - e.offset;
+            let n = (e.len() - pos).min((buf.len() - done) as u64) as usize;
+            let dst = &mut buf[done..done + n];
+            if pos < e.header.len() as u64 {
+                let h = &e.header[pos as usize..];
+                let k = h.len().min(n);
+                dst[..k].copy_from_slice(&h[..k]);
+                if k < n {
+                    read_file(e, 0, &mut dst[k..])?;
+                }
+            } else {
+                read_file(e, pos - e.header.len() as u64, dst)?;
+            }
+            done += n;
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns a reader for the whole stream.
+    pub fn reader(&self) -> Reader<'_> {
+        Reader { archive: self, pos: 0 }
+    }
+}
+
+fn read_file(e: &Entry, offset: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+    let (path, _) = e.file.as_ref().ok_or("Could not read file.")?;
+    let mut f = File::open(path).map_err(|_| "Could not read file.")?;
+    f.seek(SeekFrom::Start(offset))
+        .and_then(|_| f.read_exact(buf))
+        .map_err(|_| "Could not read file.")
+}
+
+fn file_name(path: &Path) -> Result<String, &'static str> {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.to_string())
+        .ok_or("Invalid file name.")
+}
+
+pub struct Reader<'a> {
+    archive: &'a Archive,
+    pos: u64,
+}
+
+impl<'a> Read for Reader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (self.archive.size - self.pos).min(buf.len() as u64) as usize;
+        self.archive.read_at(self.pos, &mut buf[..n]).map_err(io::Error::other)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Extracts the archive in the file `src` into the new directory `dst`. The paths of the
+/// entries are sanitized, so no entry is written outside of this directory.
+pub fn extract(src: &str, dst: &Path) -> Result<(), &'static str> {
+    let mut r = BufReader::new(File::open(src).map_err(|_| "Could not open file.")?);
+    fs::create_dir(dst).map_err(|_| "Could not create directory.")?;
+
+    let mut dirs = vec![];
+    while !r.fill_buf().map_err(|_| "Could not read file.")?.is_empty() {
+        let typ = value(&mut r, 1)? as u8;
+        let len = value(&mut r, 2)? as usize;
+        let mut name = vec![0; len];
+        r.read_exact(&mut name).map_err(|_| "Invalid archive.")?;
+        let mode = value(&mut r, 4)? as u32;
+        let mtime = value(&mut r, 8)?;
+        let path = String::from_utf8(name).ok()
+            .and_then(|name| sanitize_path(&name))
+            .map(|p| dst.join(p))
+            .ok_or("Invalid archive.")?;
+
+        match typ {
+            DIRECTORY => {
+                fs::create_dir_all(&path).map_err(|_| "Could not create directory.")?;
+                dirs.push((path, mtime, mode));
+            },
+            FILE => {
+                let size = value(&mut r, 8)?;
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent).map_err(|_| "Could not create directory.")?;
+                }
+                let mut f = OpenOptions::new().write(true).create_new(true).open(&path).map_err(|_| "Could not write file.")?;
+                if io::copy(&mut (&mut r).take(size), &mut f).map_err(|_| "Could not write file.")? != size {
+                    return Err("Invalid archive.");
+                }
+                set_metadata(&path, mtime, mode)?;
+            },
+            _ => return Err("Invalid archive.")
+        }
+    }
+
+    // The permissions of a directory could prevent that its content is written.
+    for (path, mtime, mode) in dirs.iter().rev() {
+        set_metadata(path, *mtime, *mode)?;
+    }
+    Ok(())
+}
+
+fn value(r: &mut dyn Read, n: usize) -> Result<u64, &'static str> {
+    let mut buf = vec![0; n];
+    r.read_exact(&mut buf).map_err(|_| "Invalid archive.")?;
+    Ok(buf.iter().rev().fold(0, |r, x| (r << 8) + *x as u64))
+}
+
+/// Returns the relative path with sanitized components. Empty components, `.` and `..`
+/// are removed. Returns None if no component remains.
+fn sanitize_path(path: &str) -> Option<PathBuf> {
+    let p = path.split('/')
+        .filter(|c| !c.is_empty() && *c != "." && *c != "..")
+        .map(|c| sanitize_filename(c.to_string()))
+        .collect::<PathBuf>();
+    match p.as_os_str().is_empty() {
+        true => None,
+        false => Some(p)
+    }
+}
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    use super::{extract, sanitize_path, Archive};
+    use crate::tools::random_str;
+
+    #[test]
+    fn test_sanitize_path() {
+        assert_eq!(sanitize_path("a/b.txt"), Some(PathBuf::from("a/b.txt")));
+        assert_eq!(sanitize_path("../../etc/passwd"), Some(PathBuf::from("etc/passwd")));
+        assert_eq!(sanitize_path("/a/./b//c d"), Some(PathBuf::from("a/b/c_d")));
+        assert_eq!(sanitize_path("..."), Some(PathBuf::from("...")));
+        assert_eq!(sanitize_path("/../."), None);
+        assert_eq!(sanitize_path(""), None);
+    }
+
+    #[test]
+    fn test_archive() {
+        let dir = std::env::temp_dir().join(format!("stealthy_test_{}", random_str(10)));
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("sub/empty")).unwrap();
+        fs::write(src.join("a.txt"), b"hello").unwrap();
+        fs::write(src.join("sub/b.bin"), (0..10000).map(|i| i as u8).collect::<Vec<_>>()).unwrap();
+        fs::write(src.join("sub/c.txt"), b"").unwrap();
+        fs::set_permissions(src.join("sub/c.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", src.join("link")).unwrap();
+
+        let a = Archive::from_dir(&src).unwrap();
+        let mut stream = vec![];
+        a.reader().read_to_end(&mut stream).unwrap();
+        let size = stream.len() as u64;
+
+        // Any part of the stream can be read.
+        for (offset, len) in [(0, 1), (3, 20), (30, 5000), (size - 7, 7)] {
+            let mut buf = vec![0; len];
+            a.read_at(offset, &mut buf).unwrap();
+            assert_eq!(&buf[..], &stream[offset as usize..offset as usize + len]);
+        }
+        assert!(a.read_at(size - 1, &mut [0; 2]).is_err());
+
+        let file = dir.join("archive").to_str().unwrap().to_string();
+        fs::write(&file, &stream).unwrap();
+        let dst = dir.join("dst");
+        extract(&file, &dst).unwrap();
+        assert_eq!(fs::read(dst.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read(dst.join("sub/b.bin")).unwrap(), fs::read(src.join("sub/b.bin")).unwrap());
+        assert_eq!(fs::metadata(dst.join("sub/c.txt")).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(dst.join("sub/empty").is_dir());
+        // Symbolic links are not sent.
+        assert!(!dst.join("link").exists());
+
+        // The destination must not exist.
+        assert!(extract(&file, &dst).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_overlapping_paths() {
+        let dir = std::env::temp_dir().join(format!("stealthy_test_{}", random_str(10)));
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), b"hello").unwrap();
+        fs::write(src.join("sub/b.txt"), b"world").unwrap();
+
+        // Matches of a pattern like src/** contain the directory and its content.
+        let paths = vec![src.join("sub/b.txt"), src.clone(), src.join("a.txt"), src.join("sub"), src.clone()];
+        let mut stream = vec![];
+        Archive::new(&paths).unwrap().reader().read_to_end(&mut stream).unwrap();
+        let mut expected = vec![];
+        Archive::new(std::slice::from_ref(&src)).unwrap().reader().read_to_end(&mut expected).unwrap();
+        assert_eq!(stream, expected);
+
+        let file = dir.join("archive").to_str().unwrap().to_string();
+        fs::write(&file, &stream).unwrap();
+        let dst = dir.join("dst");
+        extract(&file, &dst).unwrap();
+        assert_eq!(fs::read(dst.join("src/a.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read(dst.join("src/sub/b.txt")).unwrap(), b"world");
+
+        // Different files with the same name cannot be extracted.
+        fs::write(src.join("b.txt"), b"other").unwrap();
+        assert!(Archive::new(&[src.join("b.txt"), src.join("sub/b.txt")]).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -3,14 +3,39 @@ use std::io::{BufRead, BufReader};
 use std::env;
 use getopts::Options;
 
+use crate::binding::NatMode;
+use crate::download::DEFAULT_MAX_SIZE;
+use crate::profile::Profile;
+use crate::relay::RelayOptions;
+
+static DEFAULT_SECRET_KEY: &str = "11111111111111111111111111111111";
+
 pub struct Arguments {
     pub device: String,
     pub dstip: String,
     pub hybrid_mode: bool,
+    pub legacy_mode: bool,
     pub secret_key: String,
     pub rcpt_pubkey_file: String,
     pub privkey_file: String,
     pub pubkey_file: String,
+    pub peers_file: String,
+    pub download_dir: String,
+    pub max_size: Option<u64>,
+    pub ask: bool,
+    pub compress: bool,
+    pub nat: NatMode,
+    pub profile: Profile,
+}
+
+/// Arguments of `stealthy relay`.
+pub struct RelayArguments {
+    pub device: String,
+    pub dstip: String,
+    pub secret_key: String,
+    pub peers_file: String,
+    pub options: RelayOptions,
+    pub profile: Profile,
 }
 
 fn get_key_from_home() -> Option<String> {
@@ -33,20 +58,66 @@ fn get_key_from_home() -> Option<String> {
     }
 }
 
-pub fn parse_arguments() -> Option<Arguments> {
+/// Parses a number of bytes with an optional suffix K, M or G.
+fn parse_size(s: &str) -> Option<u64> {
+    let (n, factor) = match s.chars().last()?.to_ascii_uppercase() {
+        'K' => (&s[..s.len() - 1], 1 << 10),
+        'M' => (&s[..s.len() - 1], 1 << 20),
+        'G' => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1)
+    };
+    n.parse::<u64>().ok()?.checked_mul(factor)
+}
 
-    static DEFAULT_SECRET_KEY: &'static str = "11111111111111111111111111111111";
+/// Parses a number of seconds with an optional suffix s, m, h or d.
+fn parse_duration(s: &str) -> Option<i64> {
+    let (n, factor) = match s.chars().last()?.to_ascii_lowercase() {
+        's' => (&s[..s.len() - 1], 1),
+        'm' => (&s[..s.len() - 1], 60),
+        'h' => (&s[..s.len() - 1], 3600),
+        'd' => (&s[..s.len() - 1], 24 * 3600),
+        _ => (s, 1)
+    };
+    n.parse::<i64>().ok()?.checked_mul(factor)
+}
+
+/// Parses the name of a traffic profile. Prints a message if the name is invalid.
+fn parse_profile(s: Option<String>) -> Option<Profile> {
+    match s.as_deref() {
+        None | Some("default") => Some(Profile::Default),
+        Some("ping") => Some(Profile::Ping),
+        Some(s) => {
+            println!("Invalid traffic profile '{}'. Use 'default' or 'ping'.", s);
+            None
+        }
+    }
+}
+
+/// Returns the key given with -e, the key in the home directory or the default key.
+fn secret_key(key: Option<String>) -> String {
+    key.unwrap_or(get_key_from_home().unwrap_or(DEFAULT_SECRET_KEY.to_string()))
+}
+
+pub fn parse_arguments() -> Option<Arguments> {
 
     // parse comand line options
     let args : Vec<String> = env::args().collect();
 
     let mut opts = Options::new();
     opts.optopt("i", "dev", "set the device where to listen for messages", "device");
-    opts.optopt("d", "dst", "set the IP where messages are sent to", "IP");
+    opts.optopt("d", "dst", "set the IPs or names of peers where messages are sent to", "IP");
     opts.optopt("e", "enc", "set the encryption key", "key");
-    opts.optopt("r", "recipient", "recipient's public key in PEM format used for encryption", "filename");
+    opts.optopt("r", "recipient", "recipient's public key in PEM format or a list ip=filename,... with one key per IP", "filename");
     opts.optopt("p", "priv", "your private key in PEM format used for decryption", "filename");
     opts.optopt("q", "pub", "your public key in PEM format", "filename");
+    opts.optopt("c", "peers", "peer directory (default: ~/.stealthy/peers.toml)", "filename");
+    opts.optopt("o", "downloads", "directory where received files are saved (default: /tmp)", "directory");
+    opts.optopt("m", "max-size", "reject received files with more bytes, e.g. 10G, or 0 for no limit (default: 1G)", "size");
+    opts.optflag("a", "ask", "ask before a received file is accepted");
+    opts.optopt("n", "nat", "'client' if you are behind a NAT, 'server' for the peer of such a client", "mode");
+    opts.optflag("z", "compress", "compress messages and files before they are encrypted");
+    opts.optopt("f", "profile", "'ping' to send packets like the ping utility, which is much slower (default: 'default')", "profile");
+    opts.optflag("l", "legacy", "use unauthenticated Blowfish encryption of older versions");
     opts.optflag("h", "help", "print this message");
 
     let matches = match opts.parse(&args[1..]) {
@@ -56,8 +127,32 @@ pub fn parse_arguments() -> Option<Arguments> {
 
     let hybrid_mode = matches.opt_present("r") || matches.opt_present("p");
 
+    let max_size = match matches.opt_str("m") {
+        Some(s) => match parse_size(&s) {
+            Some(0) => None,
+            Some(n) => Some(n),
+            None => {
+                println!("Invalid maximum size '{}'.", s);
+                return None;
+            }
+        },
+        None => Some(DEFAULT_MAX_SIZE)
+    };
+
+    let nat = match matches.opt_str("n").as_deref() {
+        None => NatMode::Off,
+        Some("client") => NatMode::Client,
+        Some("server") => NatMode::Server,
+        Some(s) => {
+            println!("Invalid NAT mode '{}'. Use 'client' or 'server'.", s);
+            return None;
+        }
+    };
+
+    let profile = parse_profile(matches.opt_str("f"))?;
+
     if matches.opt_present("h") ||
-        (hybrid_mode && !(matches.opt_present("r") && matches.opt_present("p") && matches.opt_present("q"))) {
+        (hybrid_mode && !(matches.opt_present("p") && matches.opt_present("q"))) {
 
         let brief = format!("Usage: {} [options]", args[0]);
         println!("{}", opts.usage(&brief));
@@ -67,16 +162,84 @@ pub fn parse_arguments() -> Option<Arguments> {
     // 1) If option -e is given use this key.
     // 2) If key exists in home directory use this key.
     // 3) Use default key.
-    let key = matches.opt_str("e")
-        .unwrap_or(get_key_from_home().unwrap_or(DEFAULT_SECRET_KEY.to_string()));
+    let key = secret_key(matches.opt_str("e"));
 
     Some(Arguments {
         device:       matches.opt_str("i").unwrap_or("lo".to_string()),
-        dstip:        matches.opt_str("d").unwrap_or("127.0.0.1".to_string()),
+        dstip:        matches.opt_str("d").unwrap_or("".to_string()),
         secret_key:   key,
         hybrid_mode:  hybrid_mode,
+        legacy_mode:  matches.opt_present("l"),
         rcpt_pubkey_file:  matches.opt_str("r").unwrap_or("".to_string()),
         privkey_file: matches.opt_str("p").unwrap_or("".to_string()),
         pubkey_file:  matches.opt_str("q").unwrap_or("".to_string()),
+        peers_file:   matches.opt_str("c").unwrap_or("".to_string()),
+        download_dir: matches.opt_str("o").unwrap_or("/tmp".to_string()),
+        max_size,
+        ask:          matches.opt_present("a"),
+        compress:     matches.opt_present("z"),
+        nat,
+        profile,
+    })
+}
+
+/// Parses the arguments of `stealthy relay`.
+pub fn parse_relay_arguments() -> Option<RelayArguments> {
+
+    let args : Vec<String> = env::args().collect();
+
+    let mut opts = Options::new();
+    opts.optopt("i", "dev", "set the device where to listen for messages", "device");
+    opts.optopt("d", "dst", "set the IPs or names of the peers which may leave messages", "IP");
+    opts.optopt("e", "enc", "set the encryption key", "key");
+    opts.optopt("c", "peers", "peer directory (default: ~/.stealthy/peers.toml)", "filename");
+    opts.optopt("s", "max-storage", "maximum number of bytes of the kept messages of each sender, e.g. 100M (default: 10M)", "size");
+    opts.optopt("t", "max-age", "drop messages which have not been fetched after this time, e.g. 3d (default: 7d)", "time");
+    opts.optopt("f", "profile", "'ping' to send packets like the ping utility, which is much slower (default: 'default')", "profile");
+    opts.optflag("h", "help", "print this message");
+
+    let matches = match opts.parse(&args[2..]) {
+        Ok(m) => { m }
+        Err(f) => {
+            println!("{}", f);
+            return None;
+        }
+    };
+
+    if matches.opt_present("h") {
+        let brief = format!("Usage: {} relay [options]", args[0]);
+        println!("{}", opts.usage(&brief));
+        return None;
+    }
+
+    let profile = parse_profile(matches.opt_str("f"))?;
+
+    let mut options = RelayOptions::default();
+    if let Some(s) = matches.opt_str("s") {
+        match parse_size(&s) {
+            Some(n) => options.max_size = n,
+            None => {
+                println!("Invalid maximum storage '{}'.", s);
+                return None;
+            }
+        }
+    }
+    if let Some(s) = matches.opt_str("t") {
+        match parse_duration(&s) {
+            Some(n) => options.max_age = n,
+            None => {
+                println!("Invalid maximum age '{}'.", s);
+                return None;
+            }
+        }
+    }
+
+    Some(RelayArguments {
+        device:     matches.opt_str("i").unwrap_or("lo".to_string()),
+        dstip:      matches.opt_str("d").unwrap_or("".to_string()),
+        secret_key: secret_key(matches.opt_str("e")),
+        peers_file: matches.opt_str("c").unwrap_or("".to_string()),
+        options,
+        profile,
     })
 }
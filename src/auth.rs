@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+
+use crate::packet::Packet;
+use crate::session::Sessions;
+
+/// Length of the authentication tag of a packet.
+pub const TAG_LEN: usize = 16;
+
+/// Keys used to authenticate packets.
+#[derive(Clone)]
+pub enum HeaderKeys {
+    /// A fixed key for each IP. Packets exchanged with IPs without a key are not
+    /// authenticated.
+    Fixed(HashMap<String, Vec<u8>>),
+    /// Keys of the sessions negotiated with the peers. Packets are not authenticated
+    /// before a session with the peer has been established.
+    Sessions(Arc<Sessions>),
+}
+
+impl HeaderKeys {
+    /// Creates the keys from a map from the IP of each peer to its key.
+    pub fn new(keys: HashMap<String, Vec<u8>>) -> HeaderKeys {
+        HeaderKeys::Fixed(keys)
+    }
+
+    /// Returns the key which is used to authenticate packets sent to the given IP.
+    pub fn get(&self, ip: &str) -> Option<Vec<u8>> {
+        match self {
+            HeaderKeys::Fixed(keys) => keys.get(ip).cloned(),
+            HeaderKeys::Sessions(s) => s.header_key(ip)
+        }
+    }
+
+    /// Deserializes the packet received from the given IP. Returns None if the packet is
+    /// invalid or not authentic.
+    pub fn open(&self, ip: &str, buf: &[u8]) -> Option<Packet> {
+        match self {
+            HeaderKeys::Fixed(keys) => {
+                let key = keys.get(ip).map(|k| k.as_slice());
+                match Packet::verify(buf, key) {
+                    true => Packet::deserialize(buf, ip.to_string(), key),
+                    false => None
+                }
+            },
+            HeaderKeys::Sessions(s) => match s.verify_header(ip, |key| Packet::verify(buf, Some(key))) {
+                Some(Some(key)) => Packet::deserialize(buf, ip.to_string(), Some(&key)),
+                None => Packet::deserialize(buf, ip.to_string(), None),
+                // A peer which has lost its sessions, e.g. because it has been restarted,
+                // sends its handshake without tag. The content of messages is authenticated
+                // by the session or by the signature of the handshake. Acks do not have any
+                // content, hence they must always be authentic.
+                Some(None) => Packet::deserialize(buf, ip.to_string(), None).filter(|p| !p.is_ack())
+            }
+        }
+    }
+}
+
+/// Computes the authentication tag (truncated HMAC-SHA256) of the data.
+pub fn tag(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut h = Hmac::new(Sha256::new(), key);
+    h.input(data);
+    h.result().code()[..TAG_LEN].to_vec()
+}
+
+/// Checks in constant time whether the tag is valid for the data.
+pub fn verify(key: &[u8], data: &[u8], t: &[u8]) -> bool {
+    fixed_time_eq(&tag(key, data), t)
+}
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::{tag, verify, TAG_LEN};
+
+    #[test]
+    fn test_tag() {
+        let t = tag(b"key", b"data");
+        assert_eq!(t.len(), TAG_LEN);
+        assert!(verify(b"key", b"data", &t));
+        assert!(!verify(b"key", b"date", &t));
+        assert!(!verify(b"kez", b"data", &t));
+        assert!(!verify(b"key", b"data", &t[1..]));
+    }
+}
@@ -1,110 +1,150 @@
 use std::thread;
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::time::Duration;
-use std::convert::From;
 
 use crate::message::{IncomingMessage, Message, MessageType};
 use crate::error::Errors;
-use crate::packet::{Packet, IdType};
+use crate::packet::{self, Packet, IdType};
 use crate::iptools::IpAddresses;
 use crate::tools;
+use crate::transport::{Datagram, Transport};
+use crate::auth::HeaderKeys;
+use crate::stats::Stats;
+use crate::window::Window;
+use crate::rtt::{self, RttEstimator, MIN_RTO, MAX_RETRIES};
+use crate::stats::LinkStats;
 use crate::Console;
 
-use std::collections::HashMap;
-use std::iter::repeat;
+use std::collections::{HashMap, HashSet, VecDeque};
 
-const RETRY_TIMEOUT: i64      = 15000;  // TODO
 const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 1024;
-
-
-pub fn string_from_cstr(cstr: *const u8) -> String {
-
-	let mut v: Vec<u8> = vec![];
-	let mut i = 0;
-	loop { unsafe {
-		let c = *cstr.offset(i);
-		if c == 0 { break; } else { v.push(c); }
-		i += 1;
-	}}
-	String::from_utf8(v).unwrap()
-}
-
-// Callback functions.------------------------------------------------------------------
-
-/// Callback function called by the ICMP C library.
-extern "C" fn callback(target: *mut Network, buf: *const u8, len: u32, typ: u32, srcip: *const u8) {
-
-	match typ {
-		// for values look into the enum in icmp/net.h
-		0 => { // ping
-			unsafe { (*target).recv_packet(buf, len, string_from_cstr(srcip)); }
-		},
-		1 => { // pong
-			unsafe { (*target).pong(buf, len, string_from_cstr(srcip)); }
-		},
-		2 => {
-			unsafe { (*target).recv_packet(buf, len, String::from("invalid length")); }
-		},
-		3 => {
-			unsafe { (*target).recv_packet(buf, len, String::from("invalid IP length")); }
-		},
-		4 => {
-			unsafe { (*target).recv_packet(buf, len, String::from("invalid protocol")); }
-		},
-		_ => { // invalid
-			unsafe { (*target).recv_packet(buf, len, String::from("unknown")); }
-		}
-	}
+// Time in milliseconds between two polls of a client behind a NAT.
+const POLL_INTERVAL: u64      = 1000;
+// Maximum number of packets for a peer which wait for its next echo request.
+const MAX_OUTBOX: usize       = 256;
+// Number of sent packets a client remembers to recognize the echoes of them.
+const MAX_SENT: usize         = 1024;
+
+/// Describes how packets are exchanged with the peers.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NatMode {
+	/// Packets are sent as echo requests. All peers must be reachable.
+	Off,
+	/// We sit behind a NAT. The peers are polled with echo requests regularly and send
+	/// their packets in the echo replies.
+	Client,
+	/// Packets are held back until the peer sends an echo request and are sent in the
+	/// echo reply. This way they pass the NAT behind which the peer sits.
+	Server,
 }
 
-#[link(name = "icmp")]
-extern {
-	fn send_icmp(ip: *const u8, buf: *const u8, siz: u16) -> libc::c_int;
-}
-
-// TODO warning about improper ctypes is disabled; we should enable it again
-// and try to eliminate all warnings
-#[link(name = "pcap")]
-#[allow(improper_ctypes)]
-extern {
-	fn recv_callback(
-        target: *mut Network,
-		dev: *const u8,
-		cb: extern fn(*mut Network, *const u8, u32, u32, *const u8)) -> libc::c_int;
-}
-
-// -------------------------------------------------------------------------------------
 
 struct PendingPacket {
 	p: Packet,
-	millis: i64,
+	/// Time in milliseconds when the packet has gone out or None while it is held back by
+	/// the transport.
+	millis: Option<i64>,
+	retries: u32,
 }
 
 impl PendingPacket {
-	pub fn new(p: Packet, millis: i64) -> PendingPacket {
+	pub fn new(p: Packet) -> PendingPacket {
 		PendingPacket {
 			p,
-			millis,
+			millis: None,
+			retries: 0,
+		}
+	}
+
+	/// Returns the round-trip time of the packet if it has been sent only once.
+	fn rtt(&self, now: i64) -> Option<i64> {
+		match (self.retries, self.millis) {
+			(0, Some(millis)) => Some(now - millis),
+			_ => None
 		}
 	}
+
+	/// Returns the time in milliseconds to wait for an ack before the packet is sent again.
+	fn timeout(&self, rto: i64) -> i64 {
+		rtt::backoff(rto, self.retries)
+	}
+}
+
+/// State of the connection to a peer.
+struct Link {
+	window: Window,
+	rtt: RttEstimator,
 }
 
 pub struct SharedData {
 	// Packets that have been transmitted and for which we
 	// are waiting for the acknowledge.
 	packets          : HashMap<u64, PendingPacket>,
+	// The transport which is used to transmit packets.
+	transport        : Arc<dyn Transport>,
+	// Keys to authenticate packets.
+	keys             : HeaderKeys,
+	// Number of received packets which have been dropped because they are not authentic.
+	rejected         : u64,
+	// Lower bound of the time in milliseconds after which a packet is sent again if no ack
+	// has been received.
+	min_rto          : i64,
+	// Number of retries after which a packet is given up.
+	max_retries      : u32,
+	// Congestion window and round-trip time of each peer.
+	links            : HashMap<String, Link>,
+	// Is notified when packets are no longer pending or when a window has changed.
+	window_changed   : Arc<Condvar>,
+	// How packets are exchanged with the peers.
+	mode             : NatMode,
+	// Serialized packets for each peer which wait for an echo request of the peer.
+	outbox           : HashMap<String, VecDeque<Vec<u8>>>,
+	// Is set if the peers should be polled without waiting for the poll interval.
+	poll_now         : bool,
+	// Is notified when poll_now is set.
+	poll             : Arc<Condvar>,
+	// Type and id of the last packets sent by a client. The echo replies of the peers to
+	// these packets must not be processed as packets of the peers.
+	sent             : VecDeque<(u8, u64)>,
+	// Identifier and sequence number of the last echo request for each peer.
+	echo             : HashMap<String, (u16, u16)>,
+}
+
+impl SharedData {
+	/// Returns the number of packets sent to the given IP which are not acknowledged.
+	fn in_flight(&self, ip: &str) -> usize {
+		self.packets.values().filter(|pp| pp.p.ip == ip).count()
+	}
+
+	/// Returns the identifier and the sequence number of the next echo request for the
+	/// given IP. The identifier and the first sequence number are chosen randomly.
+	fn next_echo(&mut self, ip: &str) -> (u16, u16) {
+		let e = self.echo.entry(ip.to_string()).or_insert_with(|| (rand::random::<u16>(), rand::random::<u16>()));
+		e.1 = e.1.wrapping_add(1);
+		*e
+	}
+
+	fn link(&mut self, ip: &str) -> &mut Link {
+		let min_rto = self.min_rto;
+		self.links.entry(ip.to_string()).or_insert_with(|| Link {
+			window: Window::new(),
+			rtt: RttEstimator::new(min_rto),
+		})
+	}
 }
 
 
-#[repr(C)]
+#[derive(Clone)]
 pub struct Network {
     tx_msg: Sender<IncomingMessage>,
 	shared: Arc<Mutex<SharedData>>,
+	transport: Arc<dyn Transport>,
 	console: Console,
 	accept_ip: Vec<String>,
-	pub current_siz: usize,
-	ping_id: u32,
+	current_siz: Arc<AtomicUsize>,
+	probe_id: u64,
 }
 
 fn current_millis() -> i64 {
@@ -113,65 +153,185 @@ fn current_millis() -> i64 {
 }
 
 impl Network {
-	pub fn new(dev: &String, tx_msg: Sender<IncomingMessage>, console: Console, accept_ip: &IpAddresses) -> Box<Network> {
+	pub fn new(transport: Box<dyn Transport>, keys: HeaderKeys, tx_msg: Sender<IncomingMessage>, console: Console, accept_ip: &IpAddresses) -> Box<Network> {
+
+		let transport: Arc<dyn Transport> = Arc::from(transport);
 
 		let s = Arc::new(Mutex::new(SharedData {
 			packets : HashMap::new(),
+			transport: transport.clone(),
+			keys,
+			rejected: 0,
+			// An ack can wait for the next echo request of a peer which paces its packets.
+			min_rto: MIN_RTO.max(2 * transport.interval()),
+			max_retries: MAX_RETRIES,
+			links: HashMap::new(),
+			window_changed: Arc::new(Condvar::new()),
+			mode: NatMode::Off,
+			outbox: HashMap::new(),
+			poll_now: false,
+			poll: Arc::new(Condvar::new()),
+			sent: VecDeque::new(),
+			echo: HashMap::new(),
 		}));
 
-		let ping_id = rand::random::<u32>();
+		let probe_id = rand::random::<u64>();
+		let probe_siz = transport.max_payload();
 
-		// Network must be on the heap because of the callback function.
-		let mut n = Box::new(Network {
+		let n = Box::new(Network {
 			shared: s.clone(),
             tx_msg,
+			transport: transport.clone(),
 			console: console.clone(),
 			accept_ip: accept_ip.as_strings().into_iter().collect(),
-			current_siz: 128,
-			ping_id,
+			current_siz: Arc::new(AtomicUsize::new(probe_siz.min(128))),
+			probe_id,
 		});
 
-		n.init_callback(dev);
-		n.init_retry_event_receiver(s.clone());
+		n.init_receiver();
+		n.init_retry_event_receiver(s.clone(), n.tx_msg.clone());
 
-		Network::ping(console, 8192, accept_ip.as_strings().pop().unwrap(), ping_id);
+		Network::probe(console, &s, probe_siz, accept_ip.as_strings().pop().unwrap(), probe_id);
 		n
 	}
 
-	fn init_retry_event_receiver(&mut self, k: Arc<Mutex<SharedData>>) {
+	fn init_retry_event_receiver(&self, k: Arc<Mutex<SharedData>>, tx: Sender<IncomingMessage>) {
 		thread::spawn(move || { loop {
-			thread::sleep(Duration::from_millis(1000));
+			let tick = k.lock().unwrap().min_rto / 4;
+			thread::sleep(Duration::from_millis(tick.clamp(10, 250) as u64));
 			let mut packets_for_resend = vec![];
+			let mut failed = vec![];
 			{
-				for pp in &mut k.lock().unwrap().packets.values_mut() {
-					if current_millis() > pp.millis + RETRY_TIMEOUT {
-						packets_for_resend.push(pp.p.clone());
-						pp.millis = current_millis();
+				let mut s = k.lock().unwrap();
+				let max_retries = s.max_retries;
+				let SharedData { packets, links, min_rto, .. } = &mut *s;
+				for pp in packets.values_mut() {
+					let rto = links.get(&pp.p.ip).map_or(*min_rto, |l| l.rtt.rto());
+					let expired = pp.millis.is_some_and(|millis| current_millis() > millis + pp.timeout(rto));
+					if expired {
+						if pp.retries >= max_retries {
+							failed.push(pp.p.id);
+						} else {
+							packets_for_resend.push(pp.p.clone());
+							pp.millis = None;
+							pp.retries += 1;
+						}
 					}
 				}
+				for id in &failed {
+					s.packets.remove(id);
+				}
+				// A missing ack is a sign of congestion.
+				for p in &packets_for_resend {
+					let link = s.link(&p.ip);
+					let srtt = link.rtt.srtt().unwrap_or(0);
+					link.window.on_loss(current_millis(), srtt);
+				}
+				if !failed.is_empty() {
+					s.window_changed.notify_all();
+				}
+			}
+			// Packets which have not been acknowledged after all retries are given up.
+			for id in failed {
+				if tx.send(IncomingMessage::Failed(id)).is_err() {
+					return;
+				}
 			}
 			for packet in packets_for_resend {
 				tools::log_to_file(format!("Resent package with id: {}\n", packet.id));
-				Network::transmit(packet);
+				let id = packet.id;
+				Network::transmit(&k, packet);
+				if tx.send(IncomingMessage::Retry(id)).is_err() {
+					break;
+				}
 			}
 		}});
 	}
 
-	fn init_callback(&mut self, dev: &String) {
-		let sdev = dev.clone() + "\0";
-		unsafe {
-			// call to C function in icmp/net.c
-			let r = recv_callback(&mut *self, sdev.as_ptr(), callback);
-			match r {
-				-1 => {
-					#[cfg(feature="debugout")]
-					self.console.send(String::from("[Network::init_callback] failed")).unwrap();
-				},
-				_ => {
-					#[cfg(feature="debugout")]
-					self.console.send(String::from("[Network::init_callback] network initialized)")).unwrap();
+	fn init_receiver(&self) {
+		let (tx, rx) = channel();
+		if self.transport.listen(tx).is_err() {
+			#[cfg(feature="debugout")]
+			self.console.send(String::from("[Network::init_receiver] failed")).unwrap();
+		} else {
+			#[cfg(feature="debugout")]
+			self.console.send(String::from("[Network::init_receiver] network initialized)")).unwrap();
+		}
+		self.recv_loop(rx);
+	}
+
+	/// Processes the datagrams received by the transport.
+	fn recv_loop(&self, rx: Receiver<Datagram>) {
+		let mut n = self.clone();
+		thread::spawn(move || {
+			for d in rx {
+				if d.reply {
+					n.pong(&d.buf, d.ip);
+				} else if n.recv_packet(&d.buf, d.ip.clone()) {
+					n.answer(&d.ip, d.token);
+				}
+			}
+		});
+	}
+
+	/// Sends a poll to all peers after each poll interval or when a poll has been requested
+	/// via `poll_now`. Each poll allows a peer to send one packet in the echo reply.
+	fn init_poll(&self) {
+		let shared = self.shared.clone();
+		let ips = self.accept_ip.clone();
+		thread::spawn(move || { loop {
+			{
+				let mut s = shared.lock().expect("Lock failed.");
+				if !s.poll_now {
+					let poll = s.poll.clone();
+					s = poll.wait_timeout(s, Duration::from_millis(POLL_INTERVAL)).expect("Wait failed.").0;
 				}
+				s.poll_now = false;
+			}
+			for ip in &ips {
+				Network::transmit(&shared, Packet::poll(ip.clone()));
 			}
+		}});
+	}
+
+	/// Sets how packets are exchanged with the peers.
+	pub fn set_mode(&self, mode: NatMode) {
+		self.shared.lock().expect("Lock failed.").mode = mode;
+		if mode == NatMode::Client {
+			self.init_poll();
+		}
+	}
+
+	fn mode(&self) -> NatMode {
+		self.shared.lock().expect("Lock failed.").mode
+	}
+
+	/// Answers an echo request of a peer with the next packet which waits for this peer.
+	fn answer(&self, ip: &str, token: u32) {
+		let buf = match self.shared.lock().expect("Lock failed.").outbox.get_mut(ip).and_then(|q| q.pop_front()) {
+			Some(buf) => buf,
+			None => return
+		};
+		self.transport.reply(ip, token, &buf);
+	}
+
+	/// Processes a packet which a peer has sent in an echo reply. As the peer can send the
+	/// next packet only with the reply to the next request, the peer is polled again
+	/// right away.
+	fn recv_reply(&mut self, buf: &[u8], ip: String) {
+		let p = self.shared.lock().expect("Lock failed.").keys.open(&ip, buf);
+		let (poll, echo) = match p {
+			Some(p) => (p.is_poll(), self.shared.lock().expect("Lock failed.").sent.contains(&(p.typ, p.id))),
+			None => return
+		};
+		// The kernel of the peer answers our requests with a copy of them.
+		if echo {
+			return;
+		}
+		if self.recv_packet(buf, ip) && !poll {
+			let mut s = self.shared.lock().expect("Lock failed.");
+			s.poll_now = true;
+			s.poll.notify_all();
 		}
 	}
 
@@ -182,64 +342,56 @@ impl Network {
 		});
 	}
 
-	fn ping(console: Console, n: usize, ip: String, ping_id: u32) {
-		let s = format!("PROBING:{:12}/", ping_id);
-		let b = s.as_bytes();
-		if n < b.len() {
-			panic!("Invalid n.");
-		}
-		let v = b.iter().cloned().chain(repeat(1 as u8).take(n - b.len())).collect();
-		if Network::send_data_as_ping(v, ip.clone()).is_err() {
+	/// Sends a probe of `n` bytes whose echo reply tells us that packets of this size
+	/// reach the peer.
+	fn probe(console: Console, shared: &Arc<Mutex<SharedData>>, n: usize, ip: String, id: u64) {
+		let p = Packet::probe(ip, id, n.saturating_sub(packet::OVERHEAD));
+		if !Network::transmit(shared, p) {
 			Network::msg(console, String::from("No permissions to send data. Please check the documentation for more information."))
 		}
 	}
 
-	fn is_probing(buf: &[u8]) -> bool {
-		buf.iter().cloned().take(8).collect::<Vec<_>>() == "PROBING:".as_bytes().to_vec()
+	/// Sets the lower bound of the time in milliseconds after which a packet is sent again
+	/// if no ack has been received. Only affects peers to which nothing has been sent yet.
+	#[cfg(test)]
+	pub fn set_min_rto(&self, millis: i64) {
+		self.shared.lock().expect("Lock failed.").min_rto = millis;
 	}
 
-	fn probing_id(buf: &[u8]) -> u32 {
-		String::from_utf8(buf.iter()
-			.cloned()
-			.skip(8)
-			.take(12)
-			.collect::<Vec<_>>()
-		).unwrap_or(String::from("0")).trim().parse::<u32>().unwrap_or(0)
+	/// Sets the number of retries after which a packet is given up.
+	#[cfg(test)]
+	pub fn set_max_retries(&self, n: u32) {
+		self.shared.lock().expect("Lock failed.").max_retries = n;
 	}
 
-	pub fn pong(&mut self, buf: *const u8, len: u32, ip: String) {
+	/// Returns the maximum payload size of a packet.
+	pub fn max_size(&self) -> usize {
+		self.current_siz.load(Ordering::SeqCst)
+	}
 
-		match Packet::deserialize(buf, len, ip.clone()) {
-			Some(p) => {
-				if p.data.len() < 10 {
-					return;
-				}
-				if !Network::is_probing(&p.data) {
-					return;
-				}
-				if Network::probing_id(&p.data) == self.ping_id {
-					self.current_siz = p.data.len();
-					Network::msg(self.console.clone(), format!("Maximum payload size is {}.", self.current_siz));
-				}
-			},
-			_ => {}
+	pub fn pong(&mut self, buf: &[u8], ip: String) {
+
+		if self.mode() == NatMode::Client {
+			self.recv_reply(buf, ip.clone());
+		}
+
+		let p = self.shared.lock().expect("Lock failed.").keys.open(&ip, buf);
+		if let Some(p) = p.filter(|p| p.is_probe() && p.id == self.probe_id) {
+			self.current_siz.store(p.data.len(), Ordering::SeqCst);
+			Network::msg(self.console.clone(), format!("Maximum payload size is {}.", p.data.len()));
 		}
 
 	}
 
-	// This method is called with the encrypted content in buf.
-	pub fn recv_packet(&mut self, buf: *const u8, len: u32, ip: String) {
+	// This method is called with the encrypted content in buf. Returns true if the packet
+	// comes from an accepted IP and is authentic.
+	pub fn recv_packet(&mut self, buf: &[u8], ip: String) -> bool {
 
 		#[cfg(feature="debugout")]
 		self.console.send(String::from("[Network::recv_packet()] ============= called =============")).expect("send failed");
 
-		if len == 0 {
-			// TODO: hack: ip is the reason for the invalid packet
-			/*
-			self.status_tx.send(
-				format!("[Network::recv_packet()] received invalid packet: {}", ip)).unwrap();
-			*/
-			return;
+		if buf.is_empty() {
+			return false;
 		}
 
 		if self.accept_ip.iter().find(|&x| *x == ip).is_none() {
@@ -247,22 +399,16 @@ impl Network {
 			#[cfg(feature = "show_dropped")]
 			self.console.send(format!("Dropped packet from {} / {:?}", ip, self.accept_ip)).expect("Send failed.");
 
-			return;
+			return false;
 		}
 
 		// TODO error handling
 		//self.status_tx.send(String::from("[Network::recv_packet()] receving packet")).unwrap();
 
 		#[cfg(feature="debugout")]
-		unsafe {
-			let mut vv: Vec<u8> = vec![];
-			for i in 0..len {
-				vv.push(*buf.offset(i as isize));
-			}
-			self.console.send(format!("[Network::recv_packet()] new message; len = {}, {:?}", len, vv)).unwrap();
-		}
+		self.console.send(format!("[Network::recv_packet()] new message; len = {}, {:?}", buf.len(), buf)).unwrap();
 
-		let r = Packet::deserialize(buf, len, ip);
+		let r = self.open(buf, &ip);
 		// The payload in the packet in r is still encrypted.
 		match r {
 			Some(p) => {
@@ -275,6 +421,15 @@ impl Network {
                 } else if p.is_ack() {
 					//self.status_tx.send(String::from("[Network::recv_packet()] ack")).expect("bindings:ack failed");
                     self.handle_ack(p);
+                } else if p.is_poll() {
+					// Polls only give us the opportunity to send a packet in the reply.
+                } else if p.is_probe() {
+					// Probes are only used to determine the payload size via the echo
+					// reply. A server cannot probe a client behind a NAT. Instead it uses
+					// the size of the probes of the client.
+					if self.mode() == NatMode::Server {
+						self.current_siz.store(p.data.len(), Ordering::SeqCst);
+					}
                 } else {
 					#[cfg(feature="debugout")]
 					self.console.send(String::from("[Network::recv_packet()] unknown packet type")).unwrap();
@@ -282,9 +437,37 @@ impl Network {
 			},
 			None => {
 				#[cfg(feature="debugout")]
-				self.console.send(String::from("[Network::recv_packet()] deserialization failed")).unwrap();
+				self.console.send(String::from("[Network::recv_packet()] authentication failed")).unwrap();
+				return false;
 			}
 		}
+		true
+	}
+
+	/// Deserializes a packet which is authenticated with the key of the sender. Packets
+	/// which are not authentic or invalid are counted and dropped.
+	fn open(&self, buf: &[u8], ip: &str) -> Option<Packet> {
+		let mut s = self.shared.lock().expect("Lock failed.");
+		let p = s.keys.open(ip, buf);
+		if p.is_none() {
+			s.rejected += 1;
+		}
+		p
+	}
+
+	/// Adds the statistics of the network layer.
+	pub fn stats(&self, stats: &mut Stats) {
+		let s = self.shared.lock().expect("Lock failed.");
+		stats.rejected_packets = s.rejected;
+		stats.links = s.links.iter().map(|(ip, l)| LinkStats {
+			ip: ip.clone(),
+			srtt: l.rtt.srtt(),
+			rttvar: l.rtt.rttvar(),
+			rto: l.rtt.rto(),
+			window: l.window.size(),
+			in_flight: s.in_flight(ip),
+		}).collect();
+		stats.links.sort_by(|a, b| a.ip.cmp(&b.ip));
 	}
 
     fn contains(&self, id: IdType) -> bool {
@@ -307,7 +490,7 @@ impl Network {
 				Err(_) => println!("handle_new_message: could not deliver message to upper layer"),
 				_      => { }
 			}
-			Network::transmit(Packet::create_ack(p));
+			Network::transmit(&self.shared, Packet::create_ack(p));
 			// TODO error
 		}
 	}
@@ -327,16 +510,34 @@ impl Network {
             }
 			#[cfg(feature="debugout")]
 			self.console.send(String::from("binding.rs::sending ack")).expect("Could not send.");
-            Network::transmit(Packet::create_ack(p));
+            Network::transmit(&self.shared, Packet::create_ack(p));
             // TODO error
         }
     }
 
     fn handle_ack(&mut self, p: Packet) {
-		if self.shared.lock()
-			.expect("Lock failed.")
-			.packets
-			.remove(&p.id).is_some() {
+		let acked = {
+			let mut s = self.shared.lock().expect("Lock failed.");
+			// Only the receiver of a packet can acknowledge it.
+			let pending = match s.packets.get(&p.id) {
+				Some(pp) if pp.p.ip == p.ip => s.packets.remove(&p.id),
+				_ => None
+			};
+			match pending {
+				Some(pp) => {
+					let rtt = pp.rtt(current_millis());
+					let link = s.link(&pp.p.ip);
+					if let Some(rtt) = rtt {
+						link.rtt.sample(rtt);
+					}
+					link.window.on_ack(rtt);
+					s.window_changed.notify_all();
+					true
+				},
+				None => false
+			}
+		};
+		if acked {
 			//tools::log_to_file(format!("Got ACK with id: {}\n", p.id));
 			self.tx_msg.send(IncomingMessage::Ack(p.id)).expect("Send failed.");
 		}
@@ -350,7 +551,7 @@ impl Network {
 
 	/// Sends a message to the receiver ip.
 	///
-	/// The message is send via an ICMP echo request and the function
+	/// The message is send via the transport of the network layer and the function
 	/// returns to the caller a handle which can be used by the caller
 	/// to identify the message. The message is now in the status
 	/// `transmitting`. As soon as an acknowledge is received the
@@ -372,14 +573,12 @@ impl Network {
 			_ => Packet::new(buf, ip, mini_id)
 		};
 
-		Network::wait_for_queue(shared.clone());
-
 		// Push message before sending it. Otherwise there could be a race condition that the ACK
 		// is received before message is sent.
-		Network::add_packet(shared.clone(), p.clone());
+		Network::add_packet(&shared, p.clone());
 
 		let id = p.id;
-		if Network::transmit(p) {
+		if Network::transmit(&shared, p) {
 			//tools::log_to_file(format!("Sent package with id: {}\n", id));
 			Ok(id)
 		} else {
@@ -392,55 +591,76 @@ impl Network {
 		self.shared.clone()
 	}
 
-	fn remove_packet(shared: Arc<Mutex<SharedData>>, id: u64) {
-		shared.lock()
-			.expect("binding::push_packet: lock failed")
-			.packets
-			.remove(&id);
+	/// Removes the given packets so that they are not sent again.
+	pub fn cancel(shared: &Arc<Mutex<SharedData>>, ids: &HashSet<u64>) {
+		let mut s = shared.lock().expect("binding::cancel: lock failed");
+		for id in ids {
+			s.packets.remove(id);
+		}
+		s.window_changed.notify_all();
 	}
 
-	fn add_packet(shared: Arc<Mutex<SharedData>>, p: Packet) {
-		shared.lock()
-			.expect("binding::push_packet: lock failed")
-			.packets
-			.insert(p.id, PendingPacket::new(p, current_millis()));
+	fn remove_packet(shared: Arc<Mutex<SharedData>>, id: u64) {
+		let mut s = shared.lock().expect("binding::push_packet: lock failed");
+		s.packets.remove(&id);
+		s.window_changed.notify_all();
 	}
 
-	fn queue_size(shared: Arc<Mutex<SharedData>>) -> usize {
-		shared.lock()
-			.expect("binding::queue_size failed")
-			.packets
-			.len()
-	}
 
-	fn wait_for_queue(shared: Arc<Mutex<SharedData>>) {
-		// IMPORTANT!
-		// It seems that sending too many ICMP packets in a short time results in ICMP echo request
-		// drops. Hence, we limit the number of pending ACKs to 8.
-		// TODO currently the poll mechanism is suboptimal. Ideally we send 8 packets and then
-		// TODO send the next packet when an ACK is received.
-		while Network::queue_size(shared.clone()) > 8 {
-			thread::sleep(Duration::from_millis(50));
+	/// Adds a packet to the pending packets. Waits until the congestion window of the
+	/// receiver allows to send another packet.
+	fn add_packet(shared: &Arc<Mutex<SharedData>>, p: Packet) {
+		let mut s = shared.lock().expect("binding::add_packet: lock failed");
+		let window_changed = s.window_changed.clone();
+		while s.in_flight(&p.ip) >= s.link(&p.ip).window.size() {
+			s = window_changed.wait(s).expect("binding::add_packet: wait failed");
 		}
+		s.packets.insert(p.id, PendingPacket::new(p));
 	}
 
-	fn transmit(packet: Packet) -> bool {
-		//tools::log_to_file(format!("transmit: sent package with id: {}\n", packet.id));
-		let v = packet.serialize();
-		let ip = packet.ip.clone() + "\0";
-		unsafe {
-			send_icmp(ip.as_ptr(), v.as_ptr(), v.len() as u16) == 0
+	/// Starts the timer of the pending packet with the given id when the packet has gone out.
+	fn sent(shared: &Arc<Mutex<SharedData>>, id: u64) {
+		let mut s = shared.lock().expect("binding::sent: lock failed");
+		if let Some(pp) = s.packets.get_mut(&id) {
+			pp.millis = Some(current_millis());
 		}
 	}
 
-	pub fn send_data_as_ping(buf: Vec<u8>, ip: String) -> Result<u64, ()> {
-
-		let id = rand::random::<u64>();
-		let p = Packet::new(buf, ip, id);
-		if Network::transmit(p) {
-			Ok(id)
-		} else {
-			Err(())
+	fn transmit(shared: &Arc<Mutex<SharedData>>, packet: Packet) -> bool {
+		//tools::log_to_file(format!("transmit: sent package with id: {}\n", packet.id));
+		let (transport, buf, (id, seq)) = {
+			let mut s = shared.lock().expect("binding::transmit: lock failed");
+			let key = s.keys.get(&packet.ip);
+			let buf = packet.serialize(key.as_deref());
+			if s.mode == NatMode::Server {
+				// The packet is sent with the reply to the next request of the peer. A packet
+				// which is sent again while it is still waiting is not queued twice.
+				let q = s.outbox.entry(packet.ip).or_default();
+				if !q.contains(&buf) {
+					if q.len() >= MAX_OUTBOX {
+						q.pop_front();
+					}
+					q.push_back(buf);
+				}
+				if let Some(pp) = s.packets.get_mut(&packet.id) {
+					pp.millis = Some(current_millis());
+				}
+				return true;
+			}
+			if s.mode == NatMode::Client {
+				if s.sent.len() >= MAX_SENT {
+					s.sent.pop_front();
+				}
+				s.sent.push_back((packet.typ, packet.id));
+			}
+			(s.transport.clone(), buf, s.next_echo(&packet.ip))
+		};
+		let (s, packet_id) = (shared.clone(), packet.id);
+		let sent = transport.send_echo_notify(&packet.ip, id, seq, &buf, Box::new(move || Network::sent(&s, packet_id)));
+		// A packet which could not be sent is sent again like a lost one.
+		if !sent {
+			Network::sent(shared, packet.id);
 		}
+		sent
 	}
 }
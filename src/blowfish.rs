@@ -44,6 +44,7 @@ pub type ResultVec = Result<Vec<u8>, &'static str>;
 impl Blowfish {
 
     /// Returns a new instance of Blowfish with a random key.
+    #[cfg(test)]
     pub fn new() -> Result<Blowfish, &'static str> { 
         Blowfish::from_key(Blowfish::new_key()?)
     }
@@ -77,6 +78,7 @@ impl Blowfish {
     }
 
     /// Generates a new key.
+    #[cfg(test)]
     fn new_key() -> ResultVec {
         Blowfish::random_u8(KEY_LEN)
     }
@@ -124,7 +126,8 @@ impl Blowfish {
             BF_set_key(&mut *schedule, key.len() as libc::c_uint, key.as_ptr());
         }
 
-        let result = src.clone();
+        // The data is copied because BF_cbc_encrypt writes the result into the buffer.
+        let mut result = src.to_vec();
 
         // We need to create a copy from the IV because it is modified by BF_cbc_encrypt.
         let i = iv.iter().cloned().collect::<Vec<u8>>();
@@ -132,14 +135,14 @@ impl Blowfish {
         unsafe {
             BF_cbc_encrypt(
                 src.as_ptr(), 
-                result.as_ptr() as *mut u8,
+                result.as_mut_ptr(),
                 src.len() as libc::c_long, 
                 &mut *schedule, 
                 i.as_ptr() as *mut u8,
                 mode
             );
         }
-        result.iter().cloned().collect()
+        result
     }
 
 
@@ -186,7 +189,7 @@ mod tests {
         s
     }
 
-    use ::cryp::{from_hex};
+    use crate::cryp::{from_hex};
     use super::Blowfish;
 
     #[test]
@@ -3,14 +3,20 @@ use crate::Item;
 use crate::Layers;
 use crate::IpAddresses;
 use crate::ItemType;
-use crate::Message;
 use crate::Source;
 use crate::uptime;
 use crate::send_message;
+use crate::send_direct_message;
+use crate::peers::Peers;
 use crate::outputs::help_message;
 use crate::Console;
+use crate::ArcModel;
+use crate::model::DeliveryStatus;
+use crate::transfer::{Content, FileInfo, Transfers};
 
-use crate::tools::{read_file, read_bin_file, decode_uptime, without_dirs};
+use std::thread;
+
+use crate::tools::{read_file, decode_uptime};
 
 fn parse_command_set(txt: String, o: Console) -> bool {
     let txt_parts = txt.split(' ').collect::<Vec<_>>();
@@ -26,7 +32,7 @@ fn parse_command_set(txt: String, o: Console) -> bool {
     false
 }
 
-pub fn parse_command(txt: String, o: Console, l: &Layers, dstips: &IpAddresses) {
+pub fn parse_command(txt: String, o: Console, l: &Layers, transfers: &Transfers, dstips: &IpAddresses, peers: &Peers, model: &ArcModel) {
     // TODO: find more elegant solution for this
     if txt.starts_with("/cat ") {
         // TODO split_at works on bytes not characters
@@ -46,6 +52,30 @@ pub fn parse_command(txt: String, o: Console, l: &Layers, dstips: &IpAddresses)
         return;
     }
 
+    if txt.starts_with("/msg ") {
+        let parts = txt.splitn(3, ' ').collect::<Vec<_>>();
+        if parts.len() != 3 || parts[2].trim().is_empty() {
+            o.msg(String::from("Usage: /msg <peer> <text>"), ItemType::Error, Source::System);
+            return;
+        }
+        match dstips.find(parts[1], peers) {
+            Some(ip) => send_direct_message(parts[2].to_string(), ip, o, l),
+            None => o.msg(format!("Unknown peer '{}'.", parts[1]), ItemType::Error, Source::System)
+        }
+        return;
+    }
+
+    if txt == "/status" || txt.starts_with("/status ") {
+        match txt[7..].trim() {
+            "" => delivery_status(1, o, peers, model),
+            n => match n.parse::<usize>() {
+                Ok(n) if n > 0 => delivery_status(n, o, peers, model),
+                _ => o.msg(String::from("Usage: /status [n]"), ItemType::Error, Source::System)
+            }
+        }
+        return;
+    }
+
     if txt.starts_with("/set ") {
         if !parse_command_set(txt, o.clone()) {
             o.send(ConsoleMessage::TextMessage(Item::new_system("Command not understood.")));
@@ -55,14 +85,11 @@ pub fn parse_command(txt: String, o: Console, l: &Layers, dstips: &IpAddresses)
 
     if txt.starts_with("/upload ") {
         let (_, b) = txt.as_str().split_at(8);
-        match read_bin_file(b) {
-            Ok(data) => {
-                send_file(data, b.to_string(), o, l, dstips);
-            },
-            Err(s) => {
-                o.msg(String::from(s), ItemType::Error, Source::System);
-            }
-        }
+        send_file(b.to_string(), o, transfers, dstips);
+        return;
+    }
+
+    if transfer_command(&txt, o.clone(), transfers) {
         return;
     }
 
@@ -73,54 +100,145 @@ pub fn parse_command(txt: String, o: Console, l: &Layers, dstips: &IpAddresses)
         "/uptime" | "/up" => {
             o.msg(format!("up {}", decode_uptime(uptime())), ItemType::Info, Source::System);
         },
+        "/transfers" => {
+            list_transfers(o, transfers, peers);
+        },
+        "/stats" => {
+            let s = l.stats();
+            o.msg(format!("rejected packets: {}, rejected fragments: {}", s.rejected_packets, s.rejected_fragments), ItemType::Info, Source::System);
+            for link in &s.links {
+                let rtt = match link.srtt {
+                    Some(srtt) => format!("{} ms ± {} ms", srtt, link.rttvar),
+                    None => String::from("unknown")
+                };
+                o.msg(format!("{:<20} rtt: {}, rto: {} ms, window: {}, unacked: {}",
+                              peers.display_name(&link.ip), rtt, link.rto, link.window, link.in_flight),
+                      ItemType::Info, Source::System);
+            }
+        },
         _ => {
             o.msg(String::from("Unknown command. Type /help to see a list of commands."), ItemType::Info, Source::System);
         }
     };
 }
 
-fn create_upload_data(dstip: String, fname: &String, data: &Vec<u8>) -> (Message, u64) {
-    (
-        Message::file_upload(dstip, without_dirs(fname), data),
-        rand::random::<u64>()
-    )
+/// Executes a command which refers to a file transfer by its number, e.g. `/cancel 2`.
+/// Returns false if the text is not such a command.
+fn transfer_command(txt: &str, o: Console, transfers: &Transfers) -> bool {
+    let parts = txt.split_whitespace().collect::<Vec<_>>();
+    let n = match parts.get(1).map(|n| n.parse::<u32>()) {
+        Some(Ok(n)) if parts.len() == 2 => Ok(n),
+        _ => Err(())
+    };
+    let r = match (parts.first().cloned().unwrap_or_default(), n) {
+        ("/accept", Ok(n)) => transfers.downloads.accept(n),
+        ("/reject", Ok(n)) => transfers.downloads.reject(n),
+        ("/cancel", Ok(n)) => transfers.uploads.cancel(n),
+        ("/pause", Ok(n)) => transfers.uploads.pause(n),
+        ("/resume", Ok(n)) => transfers.uploads.resume(n),
+        (cmd, _) if ["/accept", "/reject", "/cancel", "/pause", "/resume"].contains(&cmd) => {
+            o.msg(format!("Usage: {} <n>", cmd), ItemType::Error, Source::System);
+            return true;
+        },
+        _ => return false
+    };
+    if let Err(e) = r {
+        o.msg(e.to_string(), ItemType::Error, Source::System);
+    }
+    true
+}
+
+/// Shows the progress of all uploads.
+fn list_transfers(o: Console, transfers: &Transfers, peers: &Peers) {
+    let uploads = transfers.uploads.list();
+    if uploads.is_empty() {
+        o.msg(String::from("No active uploads."), ItemType::Info, Source::System);
+        return;
+    }
+    for u in uploads {
+        o.msg(format!("{:>3}: '{}' to {}: {} of {} parts delivered{}",
+                      u.number, u.name, peers.display_name(&u.peer), u.delivered, u.total,
+                      if u.paused { " (paused)" } else { "" }),
+              ItemType::Info, Source::System);
+    }
+}
+
+/// Shows the delivery status of the n-th last message sent for each recipient.
+fn delivery_status(n: usize, o: Console, peers: &Peers, model: &ArcModel) {
+
+    let item = match model.lock().unwrap().sent_item(n) {
+        Some(item) => item.clone(),
+        None => {
+            o.msg(String::from("No such message."), ItemType::Error, Source::System);
+            return;
+        }
+    };
+
+    o.msg(format!("Delivery status of '{}':", item.msg), ItemType::Info, Source::System);
+    for r in &item.receipts {
+        o.msg(format!("  {:<20} {}", peers.display_name(&r.ip), r.status), ItemType::Info, Source::System);
+    }
 }
 
-/// Sends a file in background.
+/// Sends a file in background. The file is never loaded into memory completely. Directories
+/// and files matching a pattern are sent as an archive.
 ///
 /// # Arguments
 ///
-/// * `data` - Content of the file (binary data).
-/// * `fname` - Name of the file.
+/// * `fname` - Name of the file, the directory or the pattern.
 /// * `o` - Sender object to which messages are sent to.
-fn send_file(data: Vec<u8>, fname: String, console: Console, l: &Layers, dstips: &IpAddresses) {
+fn send_file(fname: String, console: Console, transfers: &Transfers, dstips: &IpAddresses) {
 
-    let n = data.len();
+    let transfers = transfers.clone();
+    let ips = dstips.as_strings();
+
+    // Computing the hash of a big file takes some time.
+    thread::spawn(move || {
+        match Content::open(&fname).and_then(|mut c| c.info()) {
+            Ok(info) => start_upload(info, fname, console, &transfers, ips),
+            Err(s) => console.msg(String::from(s), ItemType::Error, Source::System)
+        }
+    });
+}
+
+fn start_upload(info: FileInfo, fname: String, console: Console, transfers: &Transfers, ips: Vec<String>) {
 
     // This is sent to the console to show the user information about the file upload.
     let mut item = Item::new(
-        format!("sending file '{}' with {} bytes...", fname, n),
+        match info.is_archive() {
+            true => format!("sending files '{}' with {} bytes...", fname, info.size),
+            false => format!("sending file '{}' with {} bytes...", fname, info.size)
+        },
         ItemType::UploadMessage,
         Source::You
-    ).add_size(n);
+    ).add_size(info.chunks() as usize);
 
-    // Create a tuple (Message, u64) for each destination IP. For each IP a unique ID is created.
-    let v = dstips.as_strings()
-        .iter()
-        .map(|dstip| create_upload_data(dstip.clone(), &fname, &data))
+    // Create an upload id for each destination IP.
+    let v = ips.into_iter()
+        .map(|dstip| (dstip, rand::random::<u64>()))
         .collect::<Vec<_>>();
 
     // Add the file upload id to the item which is shown to the user. This ID allows us to
     // update the status of this item, e.g. once the file upload is finished.
-    for (_, id) in &v {
-        item = item.add_id(*id);
+    for (ip, id) in &v {
+        item = item.add_recipient(ip.clone(), *id);
     }
 
     // Show the message.
     console.msg_item(item);
 
+    // The path is remembered to tell the user how to resume the upload after a restart.
+    let path = std::fs::canonicalize(&fname)
+        .or_else(|_| std::env::current_dir().map(|d| d.join(&fname)))
+        .ok()
+        .and_then(|p| p.to_str().map(|s| s.to_string()))
+        .unwrap_or(fname);
+
     // Now, start the file transfer in the background for each given IP.
-    for (msg, id) in v {
-        l.send(msg, id, true);
+    for (ip, id) in v {
+        if let Err(e) = transfers.uploads.start(id, ip, info.clone(), &path) {
+            console.error(e.to_string());
+            console.delivery_status(id, DeliveryStatus::Failed);
+        }
     }
 }
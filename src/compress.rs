@@ -0,0 +1,65 @@
+use std::io::{Read, Write};
+
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use crate::aead::ResultVec;
+
+/// Maximum size of decompressed data. Larger data is rejected so that a small message of
+/// a peer cannot fill up the memory.
+pub const MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Compresses the data with deflate. Returns None if the compressed data is not smaller
+/// than the data, e.g. because the data is random or has been compressed before.
+pub fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut e = DeflateEncoder::new(vec![], Compression::best());
+    e.write_all(data).ok()?;
+    e.finish().ok().filter(|v| v.len() < data.len())
+}
+
+/// Decompresses data compressed with `compress`.
+pub fn decompress(data: &[u8]) -> ResultVec {
+    let mut v = vec![];
+    DeflateDecoder::new(data)
+        .take(MAX_SIZE + 1)
+        .read_to_end(&mut v)
+        .map_err(|_| "Invalid compressed data.")?;
+    match v.len() as u64 > MAX_SIZE {
+        true => Err("Decompressed data is too large."),
+        false => Ok(v)
+    }
+}
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::{compress, decompress, MAX_SIZE};
+
+    #[test]
+    fn test_compress() {
+        let text = b"hello hello hello hello hello hello hello hello".to_vec();
+        let c = compress(&text).unwrap();
+        assert!(c.len() < text.len());
+        assert_eq!(decompress(&c).unwrap(), text);
+
+        // Data which does not shrink is not compressed.
+        assert!(compress(b"").is_none());
+        assert!(compress(b"hi").is_none());
+        assert!(compress(&(0..1000).map(|_| rand::random::<u8>()).collect::<Vec<_>>()).is_none());
+
+        assert!(decompress(b"\xff\xff\xff").is_err());
+    }
+
+    #[test]
+    fn test_decompress_limit() {
+        let c = compress(&vec![0; MAX_SIZE as usize + 1]).unwrap();
+        assert!(decompress(&c).is_err());
+        let c = compress(&vec![0; MAX_SIZE as usize]).unwrap();
+        assert_eq!(decompress(&c).unwrap().len() as u64, MAX_SIZE);
+    }
+}
@@ -3,6 +3,7 @@ use crate::message::Message;
 use crate::model::ItemType;
 use crate::model::Item;
 use crate::model::Source;
+use crate::model::DeliveryStatus;
 
 #[cfg(not(feature = "no_notify"))]
 use std::process::Command;
@@ -11,6 +12,7 @@ pub enum ConsoleMessage {
     TextMessage(Item),
     Ack(u64),
     AckProgress(u64, usize, usize),
+    Status(u64, DeliveryStatus),
     SetScrambleTimeout(u32),
     ScrambleTick,
     Exit,
@@ -28,8 +30,8 @@ impl Console {
         }
     }
 
-    pub fn new_file(&self, m: Message, filename: String) {
-        new_file(self.console.clone(), m, filename);
+    pub fn new_file(&self, ip: String, filename: String) {
+        new_file(self.console.clone(), ip, filename);
     }
 
     pub fn ack_msg(&self, id: u64) {
@@ -40,6 +42,11 @@ impl Console {
         ack_msg_progress(self.console.clone(), id, done, total);
     }
 
+    /// Updates the delivery status of the message with the given id.
+    pub fn delivery_status(&self, id: u64, status: DeliveryStatus) {
+        self.send(ConsoleMessage::Status(id, status));
+    }
+
     pub fn error(&self, s: String) {
         error(self.console.clone(), s);
     }
@@ -93,8 +100,8 @@ pub fn status(o: Sender<ConsoleMessage>, s: String) {
     msg(o, s, ItemType::Info, Source::System);
 }
 
-pub fn new_file(o: Sender<ConsoleMessage>, m: Message, filename: String) {
-    msg(o, format!("received file '{}'", filename), ItemType::NewFile, Source::Ip(m.get_ip()));
+pub fn new_file(o: Sender<ConsoleMessage>, ip: String, filename: String) {
+    msg(o, format!("received file '{}'", filename), ItemType::NewFile, Source::Ip(ip));
 }
 
 pub fn ack_msg(o: Sender<ConsoleMessage>, id: u64) {
@@ -1,54 +1,173 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::aead;
+use crate::auth::HeaderKeys;
 use crate::blowfish;
-use crate::rsa;
 use crate::rsatools;
-use crate::delivery::{push_value, pop_value, push_slice};
+use crate::session::{self, Sessions};
+use crate::iptools::IpAddresses;
+use crate::peers::Peers;
 use crate::read_file;
 
 pub type ResultVec = Result<Vec<u8>, &'static str>;
 
+/// Encryption of the messages exchanged with a peer. The peer is identified by its IP.
 pub trait Encryption : Send + Sync {
-    fn encrypt(&self, v: &Vec<u8>) -> ResultVec;
-    fn decrypt(&self, v: &Vec<u8>) -> ResultVec;
-    fn encryption_key(&self) -> Vec<u8>;
+    fn encrypt(&self, ip: &str, v: &[u8]) -> ResultVec;
+    /// Compresses the data if it shrinks and encrypts it. Encryptions whose envelope cannot
+    /// mark compressed data encrypt the data without compression.
+    fn encrypt_compressed(&self, ip: &str, v: &[u8]) -> ResultVec { self.encrypt(ip, v) }
+    fn decrypt(&self, ip: &str, v: &[u8]) -> ResultVec;
+    /// Returns the key used to encrypt messages for the given peer.
+    fn encryption_key(&self, ip: &str) -> Vec<u8>;
+    /// Returns the key used to authenticate the headers of packets exchanged with the
+    /// given peer or None if the headers are not authenticated.
+    fn header_key(&self, _ip: &str) -> Option<Vec<u8>> { None }
+    /// Returns the keys used to authenticate the headers of packets exchanged with the
+    /// given peers.
+    fn header_keys(&self, ips: Vec<String>) -> HeaderKeys {
+        HeaderKeys::new(ips.into_iter().filter_map(|ip| self.header_key(&ip).map(|k| (ip, k))).collect())
+    }
+
+    /// Returns a handshake message which has to be sent to the peer to establish a new
+    /// session or None if no handshake is required.
+    fn session_request(&self, _ip: &str) -> Result<Option<Vec<u8>>, &'static str> { Ok(None) }
+    /// Waits until data can be encrypted for the peer.
+    fn wait_session(&self, _ip: &str, _timeout: Duration) -> bool { true }
+    /// Returns true if the received data is a handshake message.
+    fn is_handshake(&self, _v: &Vec<u8>) -> bool { false }
+    /// Processes a handshake message and returns the response for the peer, if any.
+    fn handshake(&self, _ip: &str, _v: &Vec<u8>) -> Result<Option<Vec<u8>>, &'static str> {
+        Err("Handshakes are not supported.")
+    }
 }
 
+/// Legacy symmetric encryption with Blowfish in CBC mode. The ciphertext is not
+/// authenticated.
 pub struct SymmetricEncryption {
-    algorithm: blowfish::Blowfish
+    algorithm: blowfish::Blowfish,
+    /// Algorithms for peers which have their own key in the peer directory.
+    peers: HashMap<String, blowfish::Blowfish>
+}
+
+/// Symmetric authenticated encryption with ChaCha20-Poly1305.
+pub struct AeadEncryption {
+    keys: AeadKeys,
+    /// Keys for peers which have their own key in the peer directory.
+    peers: HashMap<String, AeadKeys>
+}
+
+struct AeadKeys {
+    algorithm: aead::Aead,
+    header_key: Vec<u8>
 }
 
+/// Hybrid encryption. The messages are encrypted with session keys which are negotiated
+/// via handshakes authenticated with RSA.
 pub struct AsymmetricEncryption {
-    pub_key: String,
-    priv_key: String
+    pub_keys: HashMap<String, String>,
+    sessions: Arc<Sessions>
 }
 
 // ---------------------------------
 
 impl SymmetricEncryption {
 
-    pub fn new(hexkey: &String) -> Result<SymmetricEncryption, &'static str> {
+    /// Creates a new instance. Peers with their own key in the peer directory use that key
+    /// instead of the given one.
+    pub fn new(hexkey: &str, peers: &Peers) -> Result<SymmetricEncryption, &'static str> {
 
+        let create = |k: &str| blowfish::Blowfish::from_key(from_hex(k.to_string())?);
         Ok(SymmetricEncryption {
-            algorithm: blowfish::Blowfish::from_key(from_hex(hexkey.clone())?)?
+            algorithm: create(hexkey)?,
+            peers: peer_keys(peers, create)?
         })
     }
+
+    fn algorithm(&self, ip: &str) -> &blowfish::Blowfish {
+        self.peers.get(ip).unwrap_or(&self.algorithm)
+    }
 }
 
 impl Encryption for SymmetricEncryption {
 
     /// Encrypts the given data stored in a vector and returns the concatenated
     /// IV and ciphertext.
-    fn encrypt(&self, v: &Vec<u8>) -> ResultVec {
-        self.algorithm.encrypt(v)
+    fn encrypt(&self, ip: &str, v: &[u8]) -> ResultVec {
+        self.algorithm(ip).encrypt(v)
     }
 
     /// Decrypts the given daa stored in a vector and returns the plaintext.
-    fn decrypt(&self, v: &Vec<u8>) -> ResultVec {
-        self.algorithm.decrypt(v)
+    fn decrypt(&self, ip: &str, v: &[u8]) -> ResultVec {
+        self.algorithm(ip).decrypt(v)
     }
 
     /// Returns the symmetric key used for encryption and decryption.
-    fn encryption_key(&self) -> Vec<u8> {
-        self.algorithm.key()
+    fn encryption_key(&self, ip: &str) -> Vec<u8> {
+        self.algorithm(ip).key()
+    }
+}
+
+// ---------------------------------
+
+impl AeadEncryption {
+
+    /// Creates a new instance. The key used for encryption is derived from the given key.
+    /// Peers with their own key in the peer directory use that key instead.
+    pub fn new(hexkey: &str, peers: &Peers) -> Result<AeadEncryption, &'static str> {
+
+        Ok(AeadEncryption {
+            keys: AeadKeys::new(hexkey)?,
+            peers: peer_keys(peers, AeadKeys::new)?
+        })
+    }
+
+    fn keys(&self, ip: &str) -> &AeadKeys {
+        self.peers.get(ip).unwrap_or(&self.keys)
+    }
+}
+
+impl AeadKeys {
+
+    fn new(hexkey: &str) -> Result<AeadKeys, &'static str> {
+
+        let secret = from_hex(hexkey.to_string())?;
+        Ok(AeadKeys {
+            algorithm: aead::Aead::from_secret(&secret, "stealthy message key")?,
+            header_key: aead::derive_key(&secret, "stealthy header key", aead::KEY_LEN)
+        })
+    }
+}
+
+impl Encryption for AeadEncryption {
+
+    /// Encrypts the given data and returns the envelope which contains the version,
+    /// the nonce, the ciphertext and the authentication tag.
+    fn encrypt(&self, ip: &str, v: &[u8]) -> ResultVec {
+        self.keys(ip).algorithm.encrypt(v)
+    }
+
+    fn encrypt_compressed(&self, ip: &str, v: &[u8]) -> ResultVec {
+        self.keys(ip).algorithm.encrypt_compressed(v)
+    }
+
+    /// Verifies the envelope and returns the plaintext. Returns an error if the
+    /// envelope has been modified.
+    fn decrypt(&self, ip: &str, v: &[u8]) -> ResultVec {
+        self.keys(ip).algorithm.decrypt(v)
+    }
+
+    /// Returns the key used for encryption and decryption.
+    fn encryption_key(&self, ip: &str) -> Vec<u8> {
+        self.keys(ip).algorithm.key()
+    }
+
+    /// Returns the key used to authenticate the headers of packets. It is derived from
+    /// the same secret as the encryption key but is independent of it.
+    fn header_key(&self, ip: &str) -> Option<Vec<u8>> {
+        Some(self.keys(ip).header_key.clone())
     }
 }
 
@@ -56,56 +175,110 @@ impl Encryption for SymmetricEncryption {
 
 impl AsymmetricEncryption {
 
-    pub fn new(pubkey_file: &str, privkey_file: &str) -> Result<AsymmetricEncryption, &'static str> {
+    /// Creates a new instance. `pubkeys` is either the name of the file which contains the
+    /// public key of all peers or a comma separated list with entries of the form
+    /// `ip=filename`, i.e. one public key for each peer. Public keys in the peer directory
+    /// are used for peers which have no entry in this list.
+    pub fn new(pubkeys: &str, privkey_file: &str, ips: &IpAddresses, peers: &Peers) -> Result<AsymmetricEncryption, &'static str> {
 
+        let pub_keys = AsymmetricEncryption::public_keys(pubkeys, ips, peers)?;
         Ok(AsymmetricEncryption {
-            pub_key: read_file(pubkey_file)?,
-            priv_key: read_file(privkey_file)?
+            sessions: Arc::new(Sessions::new(pub_keys.clone(), read_file(privkey_file)?)?),
+            pub_keys
         })
     }
+
+    /// Reads the public keys and returns a map from the IP of each peer to its key.
+    fn public_keys(pubkeys: &str, ips: &IpAddresses, peers: &Peers) -> Result<HashMap<String, String>, &'static str> {
+
+        let mut files = peers.iter()
+            .filter_map(|p| p.public_key.clone().map(|k| (p.ip.clone(), k)))
+            .collect::<HashMap<_, _>>();
+
+        if pubkeys.contains('=') {
+            for entry in pubkeys.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+                let v = entry.splitn(2, '=').map(|x| x.trim()).collect::<Vec<_>>();
+                let ip = match v.len() {
+                    2 => v[0].parse::<std::net::IpAddr>().map_err(|_| "Invalid public key entry. Expected ip=filename.")?,
+                    _ => return Err("Invalid public key entry. Expected ip=filename.")
+                };
+                files.insert(ip.to_string(), v[1].to_string());
+            }
+        } else if !pubkeys.is_empty() {
+            for ip in ips.as_strings() {
+                files.entry(ip).or_insert(pubkeys.to_string());
+            }
+        }
+
+        if ips.as_strings().iter().any(|ip| !files.contains_key(ip)) {
+            return Err("No public key for one of the IPs.");
+        }
+
+        let mut keys = HashMap::new();
+        for (ip, file) in files {
+            keys.insert(ip, read_file(&file)?);
+        }
+        Ok(keys)
+    }
+}
+
+/// Creates the keys for all peers which have their own key in the peer directory.
+fn peer_keys<T, F>(peers: &Peers, create: F) -> Result<HashMap<String, T>, &'static str>
+    where F: Fn(&str) -> Result<T, &'static str> {
+
+    let mut keys = HashMap::new();
+    for p in peers.iter() {
+        if let Some(k) = &p.key {
+            keys.insert(p.ip.clone(), create(k)?);
+        }
+    }
+    Ok(keys)
 }
 
 // ---------------------------------
 
 impl Encryption for AsymmetricEncryption {
 
-    fn encrypt(&self, v: &Vec<u8>) -> ResultVec {
-
-        // Encrypt the data with Blowfish.
-        let symenc = blowfish::Blowfish::new()?;
-        let cipher = symenc.encrypt(v)?;
+    /// Encrypts the data with the current session key of the peer.
+    fn encrypt(&self, ip: &str, v: &[u8]) -> ResultVec {
+        self.sessions.encrypt(ip, v, false)
+    }
 
-        // Encrypt the key used by Blowfish with RSA.
-        let ekey =
-            rsa::RSA::new(&self.pub_key, &self.priv_key)?.encrypt(&symenc.key())?;
+    fn encrypt_compressed(&self, ip: &str, v: &[u8]) -> ResultVec {
+        self.sessions.encrypt(ip, v, true)
+    }
 
-        let mut v: Vec<u8> = Vec::new();
-        push_value(&mut v, cipher.len() as u64, 8); // length of ciphertext
-        push_slice(&mut v, &cipher);                // ciphertext
-        push_slice(&mut v, &ekey);                  // with RSA encrypted key
-        Ok(v)
+    fn decrypt(&self, ip: &str, v: &[u8]) -> ResultVec {
+        self.sessions.decrypt(ip, v)
     }
- 
-    fn decrypt(&self, v: &Vec<u8>) -> ResultVec {
 
-        let mut data = v.clone();
-        let clen = pop_value(&mut data, 8)? as usize;
+    fn session_request(&self, ip: &str) -> Result<Option<Vec<u8>>, &'static str> {
+        self.sessions.request(ip)
+    }
 
-        if clen > data.len() {
-            return Err("Invalid ciphertext length.");
-        }
+    fn wait_session(&self, ip: &str, timeout: Duration) -> bool {
+        self.sessions.wait(ip, timeout)
+    }
 
-        let (cipher, cipher_key) = data.split_at(clen);
+    fn is_handshake(&self, v: &Vec<u8>) -> bool {
+        v.first() == Some(&session::HANDSHAKE)
+    }
 
+    fn handshake(&self, ip: &str, v: &Vec<u8>) -> Result<Option<Vec<u8>>, &'static str> {
+        self.sessions.handshake(ip, v)
+    }
 
-        blowfish::Blowfish::from_key(
-            rsa::RSA::new(&self.pub_key, &self.priv_key)?.decrypt(cipher_key)?
-        )?.decrypt(cipher)
+    /// Returns the keys of the sessions with the peers. They change with each session.
+    fn header_keys(&self, _ips: Vec<String>) -> HeaderKeys {
+        HeaderKeys::Sessions(self.sessions.clone())
     }
 
-    /// Returns the public key.
-    fn encryption_key(&self) -> Vec<u8> {
-        rsatools::key_as_der(&self.pub_key)
+    /// Returns the public key of the peer.
+    fn encryption_key(&self, ip: &str) -> Vec<u8> {
+        match self.pub_keys.get(ip) {
+            Some(k) => rsatools::key_as_der(k),
+            None => vec![]
+        }
     }
 }
 
@@ -163,32 +336,104 @@ mod tests {
 
     // --------------------------------------------------------------
  
-    use super::{Encryption, AsymmetricEncryption};
+    use super::{Encryption, AsymmetricEncryption, AeadEncryption};
+    use crate::iptools::IpAddresses;
+    use crate::peers::Peers;
+
+    #[test]
+    fn test_aead_encryption() {
+
+        let a = AeadEncryption::new("0001090A0F10", &Peers::new()).unwrap();
+        let b = AeadEncryption::new("0001090A0F11", &Peers::new()).unwrap();
+        assert!(AeadEncryption::new("xyz", &Peers::new()).is_err());
+
+        let plain  = "hello".to_string().into_bytes();
+        let cipher = a.encrypt("1.2.3.4", &plain).unwrap();
+        assert_eq!(a.decrypt("1.2.3.4", &cipher).unwrap(), plain);
+        assert!(b.decrypt("1.2.3.4", &cipher).is_err());
+    }
+
+    #[test]
+    fn test_aead_peer_keys() {
+
+        let peers = Peers::parse("[bob]\nip = \"1.2.3.4\"\nkey = \"0001090A0F11\"").unwrap();
+        let a = AeadEncryption::new("0001090A0F10", &peers).unwrap();
+        let b = AeadEncryption::new("0001090A0F11", &Peers::new()).unwrap();
+
+        // Bob uses his own key. All other peers use the default key.
+        let plain  = "hello".to_string().into_bytes();
+        let cipher = a.encrypt("1.2.3.4", &plain).unwrap();
+        assert_eq!(b.decrypt("5.6.7.8", &cipher).unwrap(), plain);
+        assert!(a.decrypt("5.6.7.8", &cipher).is_err());
+        assert_eq!(a.header_key("1.2.3.4"), b.header_key("5.6.7.8"));
+        assert!(a.header_key("1.2.3.4") != a.header_key("5.6.7.8"));
+
+        let peers = Peers::parse("[bob]\nip = \"1.2.3.4\"\nkey = \"xyz\"").unwrap();
+        assert!(AeadEncryption::new("0001090A0F10", &peers).is_err());
+    }
 
     #[test]
     fn test_asymmetric_encryption() {
         
-        let a = AsymmetricEncryption::new("tests/keys/rsa_pub.pem", "tests/keys/rsa_priv.pem");
+        let ips = IpAddresses::from_comma_list("10.0.0.2");
+        let a = AsymmetricEncryption::new("tests/keys/rsa_pub.pem", "tests/keys/rsa_priv.pem", &ips, &Peers::new());
         assert!(a.is_ok());
 
-        let b = AsymmetricEncryption::new("tests/keys/rsa_pub.pem", "abc");
+        let b = AsymmetricEncryption::new("tests/keys/rsa_pub.pem", "abc", &ips, &Peers::new());
         assert!(b.is_err());
 
     }
 
+    #[test]
+    fn test_public_keys_per_ip() {
+
+        let ips = IpAddresses::from_comma_list("10.0.0.2,10.0.0.3");
+        let priv_key = "tests/keys/rsa_priv.pem";
+
+        let a = AsymmetricEncryption::new("10.0.0.2=tests/keys/rsa_pub2.pem, 10.0.0.3=tests/keys/rsa_pub3.pem", priv_key, &ips, &Peers::new()).unwrap();
+        assert!(a.encryption_key("10.0.0.2") != a.encryption_key("10.0.0.3"));
+        assert!(a.encryption_key("10.0.0.4").is_empty());
+
+        // The same key for all IPs.
+        let a = AsymmetricEncryption::new("tests/keys/rsa_pub2.pem", priv_key, &ips, &Peers::new()).unwrap();
+        assert_eq!(a.encryption_key("10.0.0.2"), a.encryption_key("10.0.0.3"));
+
+        // Each IP requires a key.
+        assert!(AsymmetricEncryption::new("10.0.0.2=tests/keys/rsa_pub2.pem", priv_key, &ips, &Peers::new()).is_err());
+        assert!(AsymmetricEncryption::new("10.0.0.2=tests/keys/rsa_pub2.pem,10.0.0.3", priv_key, &ips, &Peers::new()).is_err());
+        assert!(AsymmetricEncryption::new("x=tests/keys/rsa_pub2.pem,10.0.0.3=tests/keys/rsa_pub3.pem", priv_key, &ips, &Peers::new()).is_err());
+
+        // Keys from the peer directory.
+        let peers = Peers::parse("[bob]\nip = \"10.0.0.2\"\npublic_key = \"tests/keys/rsa_pub2.pem\"").unwrap();
+        assert!(AsymmetricEncryption::new("", priv_key, &ips, &peers).is_err());
+        let a = AsymmetricEncryption::new("tests/keys/rsa_pub3.pem", priv_key, &ips, &peers).unwrap();
+        let b = AsymmetricEncryption::new("10.0.0.2=tests/keys/rsa_pub2.pem, 10.0.0.3=tests/keys/rsa_pub3.pem", priv_key, &ips, &Peers::new()).unwrap();
+        assert_eq!(a.encryption_key("10.0.0.2"), b.encryption_key("10.0.0.2"));
+        assert_eq!(a.encryption_key("10.0.0.3"), b.encryption_key("10.0.0.3"));
+    }
+
     #[test]
     fn test_asymmetric_encrypt_decrypt() {
         
-        let a = AsymmetricEncryption::new("tests/keys/rsa_pub.pem", "tests/keys/rsa_priv.pem");
-        assert!(a.is_ok());
-        match a {
-            Ok(a) => {
-                let plain  = "hello".to_string().into_bytes();
-                let cipher = a.encrypt(&plain).unwrap();
-                let p      = a.decrypt(&cipher).unwrap();
-                assert_eq!(plain, p);
-            }
-            _ => { }
-        }
+        let a = AsymmetricEncryption::new("tests/keys/rsa_pub2.pem", "tests/keys/rsa_priv.pem", &IpAddresses::from_comma_list("10.0.0.2"), &Peers::new()).unwrap();
+        let b = AsymmetricEncryption::new("tests/keys/rsa_pub.pem", "tests/keys/rsa_priv2.pem", &IpAddresses::from_comma_list("10.0.0.1"), &Peers::new()).unwrap();
+
+        // A session is required before data can be encrypted.
+        let plain  = "hello".to_string().into_bytes();
+        assert!(a.encrypt("10.0.0.2", &plain).is_err());
+
+        let init = a.session_request("10.0.0.2").unwrap().unwrap();
+        assert!(b.is_handshake(&init));
+        let resp = b.handshake("10.0.0.1", &init).unwrap().unwrap();
+        assert!(a.handshake("10.0.0.2", &resp).unwrap().is_none());
+
+        let cipher = a.encrypt("10.0.0.2", &plain).unwrap();
+        assert!(!b.is_handshake(&cipher));
+        assert_eq!(b.decrypt("10.0.0.1", &cipher).unwrap(), plain);
+
+        // Modified ciphertexts are rejected.
+        let mut c = cipher.clone();
+        c[20] ^= 1;
+        assert!(b.decrypt("10.0.0.1", &c).is_err());
     }
 }
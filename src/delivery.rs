@@ -1,11 +1,16 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::thread;
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{channel, Receiver, Sender};
 
 use crate::{Message, IncomingMessage};
-use crate::binding::Network;
+use crate::binding::{Network, NatMode};
+use crate::iptools::IpAddresses;
+use crate::transport::Transport;
+use crate::auth::HeaderKeys;
+use crate::stats::Stats;
 use crate::Console;
+use crate::model::DeliveryStatus;
 
 #[cfg(feature="debugout")]
 use crypto::sha2::Sha256;
@@ -42,9 +47,34 @@ pub struct SmallMessages {
     id: u64
 }
 
+/// Number of ids of completely received messages which are remembered to detect duplicates.
+const MAX_COMPLETED: usize = 4096;
+
+/// Received parts of messages which are not yet complete.
+struct Reassembly {
+    streams: HashMap<u64, HashMap<u32, SmallMessage>>,
+    /// Ids of the most recently completed messages. A part of a message can be received
+    /// more than once, e.g. if the ack got lost and the sender transmitted the part again.
+    completed: VecDeque<u64>,
+    /// Number of parts which have been dropped because of an invalid header.
+    rejected: u64,
+}
+
+impl Reassembly {
+
+    /// Remembers that the message with the given id must not be delivered again.
+    fn complete(&mut self, id: u64) {
+        self.streams.remove(&id);
+        self.completed.push_back(id);
+        if self.completed.len() > MAX_COMPLETED {
+            self.completed.pop_front();
+        }
+    }
+}
+
 pub struct Delivery {
     pub pending: Arc<Mutex<Vec<SmallMessages>>>,
-    incoming: Arc<Mutex<HashMap<u64, HashMap<u32, SmallMessage>>>>,
+    incoming: Arc<Mutex<Reassembly>>,
     tx: Sender<IncomingMessage>,
     network_layer: Box<Network>,
     _console: Console
@@ -54,15 +84,23 @@ pub struct Delivery {
 
 impl Delivery {
 
-    /// Via rx1 this layer receives incoming messages from the
-    /// network layer (message with encrypted payload).
-    pub fn new(n: Box<Network>, tx: Sender<IncomingMessage>, rx: Receiver<IncomingMessage>, console: Console) -> Delivery {
+    /// Creates the delivery layer on top of a network layer which uses the given transport.
+    /// Messages received from the network layer (messages with encrypted payload) are
+    /// reassembled and sent to the layer above via tx.
+    pub fn new(transport: Box<dyn Transport>, keys: HeaderKeys, tx: Sender<IncomingMessage>, console: Console, accept_ip: &IpAddresses) -> Delivery {
+
+        // network tx --- incoming message ---> rx delivery
+        let (tx_network, rx) = channel();
 
         let d = Delivery {
             pending: Arc::new(Mutex::new(vec![])),
             tx: tx,
-            network_layer: n,
-            incoming: Arc::new(Mutex::new(HashMap::new())),
+            network_layer: Network::new(transport, keys, tx_network, console.clone(), accept_ip),
+            incoming: Arc::new(Mutex::new(Reassembly {
+                streams: HashMap::new(),
+                completed: VecDeque::new(),
+                rejected: 0,
+            })),
             _console: console,
         };
 
@@ -70,11 +108,46 @@ impl Delivery {
         d
     }
 
-    fn insert_packet(incoming: Arc<Mutex<HashMap<u64, HashMap<u32, SmallMessage>>>>, small_msg: SmallMessage) -> Option<Vec<u8>> {
+    /// Deserializes a part of a message and adds it to the parts already received. If all
+    /// parts of the message have been received the complete message is returned. Parts with
+    /// an invalid header are counted and dropped.
+    fn reassemble(incoming: Arc<Mutex<Reassembly>>, buf: &Vec<u8>) -> Option<Vec<u8>> {
+        let r = match Delivery::deserialize(buf) {
+            Some(small_msg) => Delivery::insert_packet(incoming.clone(), small_msg),
+            None => Err("Invalid header.")
+        };
+        match r {
+            Ok(r) => r,
+            Err(_) => {
+                incoming.lock().unwrap().rejected += 1;
+                None
+            }
+        }
+    }
+
+    fn insert_packet(incoming: Arc<Mutex<Reassembly>>, small_msg: SmallMessage) -> Result<Option<Vec<u8>>, &'static str> {
         let id= small_msg.id;
         let n= small_msg.n;
         let seq = small_msg.seq;
-        let mut i = incoming.lock().unwrap();
+        let mut r = incoming.lock().unwrap();
+
+        if seq == 0 || seq > n {
+            return Err("Invalid sequence number.");
+        }
+
+        // Ignore parts of messages which have already been delivered.
+        if r.completed.contains(&id) {
+            return Ok(None);
+        }
+
+        let i = &mut r.streams;
+
+        // All parts of a message must agree on the number of parts.
+        if let Some(v) = i.get(&id) {
+            if v.values().any(|m| m.n != n) {
+                return Err("Invalid number of parts.");
+            }
+        }
 
         // If an id for the packet(s) does not already exist in the incoming data structure
         // insert an empty vector to collect all packets of this stream.
@@ -86,7 +159,7 @@ impl Delivery {
         let mut k = 0;
         if let Some(v) = i.get_mut(&id) {
             if v.contains_key(&seq) {
-                return None;
+                return Ok(None);
             }
             v.insert(seq, small_msg);
             k = v.len();
@@ -101,11 +174,11 @@ impl Delivery {
             if a == b {
                 // all packets received
                 let buf = b.iter().flat_map(|seq| i.get(&id).unwrap().get(&seq).unwrap().buf.iter()).map(|&x| x).collect();
-                i.remove(&id);
-                return Some(buf);
+                r.complete(id);
+                return Ok(Some(buf));
             }
         }
-        None
+        Ok(None)
     }
 
     fn init_rx(&self, rx: Receiver<IncomingMessage>) {
@@ -113,6 +186,7 @@ impl Delivery {
         let tx       = self.tx.clone();
         let queue    = self.pending.clone();
         let incoming = self.incoming.clone();
+        let shared   = self.network_layer.shared_data();
 
         #[cfg(feature="debugout")]
         let stx = self._console.clone();
@@ -126,39 +200,42 @@ impl Delivery {
                         },
                         // msg could be just one of many messages. The stream of single messages is merged in this struct.
                         IncomingMessage::FileUpload(m) => {
-                            match Delivery::deserialize(&m.buf) {
-                                Some(small_msg) => {
-                                    let r = Delivery::insert_packet(incoming.clone(), small_msg);
-                                    if r.is_some() {
-                                        // The payload is still encrypted.
-                                        //println!("TTT received all");
-                                        if tx.send(IncomingMessage::FileUpload(Message::new(m.ip, r.unwrap()))).is_err() {
-                                            // TODO error handling
-                                        }
-                                    }
+                            if let Some(buf) = Delivery::reassemble(incoming.clone(), &m.buf) {
+                                // The payload is still encrypted.
+                                if tx.send(IncomingMessage::FileUpload(Message::new(m.ip, buf))).is_err() {
+                                    // TODO error handling
                                 }
-                                _ => { } // TODO error handling
                             }
                         },
                         IncomingMessage::New(m) => { // TODO beautify
-                            match Delivery::deserialize(&m.buf) {
-                                Some(small_msg) => {
-                                    #[cfg(feature="debugout")]
-                                    stx.send(format!("delivery.rs::deserialize result hash: {} [{}]", small_msg.sha2(), small_msg.as_string())).unwrap();
-                                    let r = Delivery::insert_packet(incoming.clone(), small_msg);
-                                    if r.is_some() {
-                                        // The payload is still encrypted.
-                                        if tx.send(IncomingMessage::New(Message::new(m.ip, r.unwrap()))).is_err() {
-                                            // TODO error handling
-                                        }
-
-                                    }
+                            #[cfg(feature="debugout")]
+                            stx.send(format!("delivery.rs::received part of message: {}", m.buf.len())).unwrap();
+                            if let Some(buf) = Delivery::reassemble(incoming.clone(), &m.buf) {
+                                // The payload is still encrypted.
+                                if tx.send(IncomingMessage::New(Message::new(m.ip, buf))).is_err() {
+                                    // TODO error handling
                                 }
-                                _ => { } // TODO error handling
                             }
                         }
                         IncomingMessage::AckProgress(_id, _pending, _total) => {
 
+                        },
+                        IncomingMessage::Failed(id) => {
+                            // If one part of a message fails the whole message fails.
+                            if let Some(m) = Delivery::remove_pending(&queue, id) {
+                                Network::cancel(&shared, &m.acks);
+                                if tx.send(IncomingMessage::Failed(m.id)).is_err() {
+                                    // TODO error handling
+                                }
+                            }
+                        },
+                        IncomingMessage::Retry(id) => {
+                            let q = queue.lock().expect("delivery: lock failed");
+                            if let Some(m) = q.iter().find(|m| m.acks.contains(&id)) {
+                                if tx.send(IncomingMessage::Retry(m.id)).is_err() {
+                                    // TODO error handling
+                                }
+                            }
                         },
                         IncomingMessage::Ack(id) => { // TODO beautify + performance for uploads
                             let mut q = queue.lock().expect("delivery: lock failed");  // lock guard on Vec<SmallMessages>
@@ -193,8 +270,57 @@ impl Delivery {
         }});
     }
 
+    /// Removes the message which contains the part with the given id from the pending
+    /// messages.
+    fn remove_pending(queue: &Mutex<Vec<SmallMessages>>, mini_id: u64) -> Option<SmallMessages> {
+        let mut q = queue.lock().expect("delivery: lock failed");
+        q.iter().position(|m| m.acks.contains(&mini_id)).map(|idx| q.swap_remove(idx))
+    }
+
+    /// Stops sending the message with the given id. Its parts which have not been
+    /// acknowledged are not sent again.
+    pub fn cancel(&self, id: u64) {
+        let m = {
+            let mut q = self.pending.lock().expect("delivery: lock failed");
+            match q.iter().position(|m| m.id == id) {
+                Some(idx) => q.swap_remove(idx),
+                None => return
+            }
+        };
+        Network::cancel(&self.network_layer.shared_data(), &m.acks);
+    }
+
+    /// Drops the received parts of the message with the given id. Parts of this message
+    /// which are received later are ignored.
+    pub fn discard(&self, id: u64) {
+        self.incoming.lock().unwrap().complete(id);
+    }
+
+    /// Sets how packets are exchanged with the peers.
+    pub fn set_nat_mode(&self, mode: NatMode) {
+        self.network_layer.set_mode(mode);
+    }
+
+    #[cfg(test)]
+    pub fn set_min_rto(&self, millis: i64) {
+        self.network_layer.set_min_rto(millis);
+    }
+
+    #[cfg(test)]
+    pub fn set_max_retries(&self, n: u32) {
+        self.network_layer.set_max_retries(n);
+    }
+
+    /// Returns statistics about the received packets.
+    pub fn stats(&self) -> Stats {
+        let mut s = Stats::new();
+        self.network_layer.stats(&mut s);
+        s.rejected_fragments = self.incoming.lock().unwrap().rejected;
+        s
+    }
+
     pub fn max_size(&self) -> usize {
-        self.network_layer.current_siz
+        self.network_layer.max_size()
     }
 
     pub fn get_pending(&self) -> Arc<Mutex<Vec<SmallMessages>>> {
@@ -225,6 +351,8 @@ impl Delivery {
 
         let o = SendObject {
             msg,
+            id,
+            pending,
             small_messages,
             shared,
             console,
@@ -304,6 +432,8 @@ impl Delivery {
 
 pub struct SendObject {
     msg: Message,
+    id: u64,
+    pending: Arc<Mutex<Vec<SmallMessages>>>,
     small_messages: SmallMessages,
     shared: Arc<Mutex<SharedData>>,
     console: Console,
@@ -312,6 +442,10 @@ pub struct SendObject {
 impl SendObject {
     pub fn run(&self) {
         for i in &self.small_messages.messages {
+            // Stop if the message has failed in the meantime.
+            if !self.pending.lock().expect("Could not lock.").iter().any(|m| m.id == self.id) {
+                break;
+            }
             let message = self.msg.set_payload(Delivery::serialize(i));
             match Network::send_msg(message, self.shared.clone(), i.mini_id) {
                 Ok(_id) => {
@@ -323,7 +457,10 @@ impl SendObject {
                                 "Maybe you don't have the permission to create raw sockets. ",
                                 "Check the documentation for more details."
                         ));
-                    // TODO remove small_message from delivery.rs:Delivery:self.pending on error
+                    self.console.delivery_status(self.id, DeliveryStatus::Failed);
+                    if let Some(m) = Delivery::remove_pending(&self.pending, i.mini_id) {
+                        Network::cancel(&self.shared, &m.acks);
+                    }
                     break;
                 }
             }
@@ -368,8 +505,13 @@ pub fn pop_value(src: &mut Vec<u8>, n: usize) -> Result<u64, &'static str> {
 #[cfg(test)]
 mod tests {
 
-    use super::{Delivery, MAX_MESSAGE_PART_SIZE, SmallMessage};
-    use ::Message;
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::{Arc, Mutex};
+
+    use super::{Delivery, Reassembly, SmallMessage};
+    use crate::Message;
+
+    const MAX_MESSAGE_PART_SIZE: usize = 128;
 
     /*
     #[test]
@@ -386,11 +528,11 @@ mod tests {
         
         let data = "hallo".to_string().into_bytes();
         let msg  = Message::new("1.2.3.4".to_string(), data.clone());
-        let r    = Delivery::split_message(&msg);
+        let r    = Delivery::split_message(&msg, 17, MAX_MESSAGE_PART_SIZE);
 
 
-        // Check that a random id has been generated.
-        assert!(r.id != 0);
+        // Check that the id has been used.
+        assert_eq!(r.id, 17);
         // Check that there is one message.
         assert!(r.messages.len() == 1);
         // An empty vector for received acks.
@@ -409,10 +551,10 @@ mod tests {
 
         let v = (0..MAX_MESSAGE_PART_SIZE).map(|x| x as u8).collect::<Vec<_>>();
         let m = Message::new("1.2.3.4".to_string(), v.clone());
-        let r = Delivery::split_message(&m);
+        let r = Delivery::split_message(&m, 17, MAX_MESSAGE_PART_SIZE);
 
         assert_eq!(r.acks.len(), 0);
-        assert!(r.id != 0);
+        assert_eq!(r.id, 17);
         assert_eq!(r.messages.len(), 1);
         assert_eq!(r.messages[0].buf, v);
         assert_eq!(r.messages[0].seq, 1);
@@ -426,9 +568,9 @@ mod tests {
         // Create a message that should be divided into two pieces.
         let v = (0..MAX_MESSAGE_PART_SIZE + 1).map(|x| x as u8).collect::<Vec<_>>();
         let m = Message::new("1.2.3.4".to_string(), v.clone());
-        let r = Delivery::split_message(&m);
+        let r = Delivery::split_message(&m, 17, MAX_MESSAGE_PART_SIZE);
 
-        assert!(r.id != 0);
+        assert_eq!(r.id, 17);
         assert!(r.messages.len() == 2);
         assert!(r.messages[0].seq == 1);
         assert!(r.messages[0].id == r.id);
@@ -438,10 +580,13 @@ mod tests {
         assert!(r.messages[1].id == r.id);
         assert!(r.messages[1].n == 2);
 
-        assert!(r.messages[0].buf.len() == super::MAX_MESSAGE_PART_SIZE);
+        assert!(r.messages[0].buf.len() == MAX_MESSAGE_PART_SIZE);
         assert!(r.messages[1].buf.len() == 1);
 
-        let (v1, v2) = v.split_at(super::MAX_MESSAGE_PART_SIZE);
+        // Each part gets its own id for the ack.
+        assert!(r.messages[0].mini_id != r.messages[1].mini_id);
+
+        let (v1, v2) = v.split_at(MAX_MESSAGE_PART_SIZE);
         assert_eq!(r.messages[0].buf, v1);
         assert_eq!(r.messages[1].buf, v2);
     }
@@ -454,8 +599,8 @@ mod tests {
             seq: 211 * 256 + 189,
             n  : (99 * 256 + 134) * 256 + 177,
             id : (12 * 256 + 19) * 256 + 18,
+            mini_id: 0,
         };
-
         let v = Delivery::serialize(&mp);
         assert_eq!(v, vec![
                 1,                         // version
@@ -492,6 +637,53 @@ mod tests {
         assert!(Delivery::deserialize(&x).is_some());
     }
 
+    fn reassembly() -> Arc<Mutex<Reassembly>> {
+        Arc::new(Mutex::new(Reassembly {
+            streams: HashMap::new(),
+            completed: VecDeque::new(),
+            rejected: 0,
+        }))
+    }
+
+    fn part(id: u64, n: u32, seq: u32) -> Vec<u8> {
+        Delivery::serialize(&SmallMessage { buf: vec![seq as u8], seq, n, id, mini_id: 0 })
+    }
+
+    #[test]
+    fn test_reject_invalid_fragments() {
+        let r = reassembly();
+
+        // Invalid sequence numbers.
+        assert!(Delivery::reassemble(r.clone(), &part(1, 2, 0)).is_none());
+        assert!(Delivery::reassemble(r.clone(), &part(1, 2, 3)).is_none());
+        // Invalid header.
+        assert!(Delivery::reassemble(r.clone(), &vec![1, 2]).is_none());
+        assert_eq!(r.lock().unwrap().rejected, 3);
+
+        // The number of parts must not change.
+        assert!(Delivery::reassemble(r.clone(), &part(1, 2, 1)).is_none());
+        assert!(Delivery::reassemble(r.clone(), &part(1, 3, 2)).is_none());
+        assert_eq!(r.lock().unwrap().rejected, 4);
+
+        // Valid parts are not affected.
+        assert_eq!(Delivery::reassemble(r.clone(), &part(1, 2, 2)), Some(vec![1, 2]));
+        assert_eq!(r.lock().unwrap().rejected, 4);
+    }
+
+    #[test]
+    fn test_discard() {
+        let r = reassembly();
+        assert!(Delivery::reassemble(r.clone(), &part(1, 2, 1)).is_none());
+        r.lock().unwrap().complete(1);
+        assert!(r.lock().unwrap().streams.is_empty());
+
+        // Parts of a discarded message are ignored.
+        assert!(Delivery::reassemble(r.clone(), &part(1, 2, 2)).is_none());
+        assert!(Delivery::reassemble(r.clone(), &part(1, 2, 1)).is_none());
+        assert!(r.lock().unwrap().streams.is_empty());
+        assert_eq!(r.lock().unwrap().rejected, 0);
+    }
+
     // ========================================================================
 
     use super::{push_slice, push_value, pop_value};
@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::archive::extract;
+use crate::layer::Layers;
+use crate::manifest::{self, Manifest};
+use crate::message::Message;
+use crate::transfer::{sha256, FileInfo, Transfer};
+use crate::Console;
+
+/// Files with more bytes are rejected unless another limit is configured.
+pub const DEFAULT_MAX_SIZE: u64 = 1 << 30;
+
+/// Settings which control which files are accepted and where they are saved.
+#[derive(Clone)]
+pub struct DownloadOptions {
+    /// Directory in which received files are saved.
+    pub dir: PathBuf,
+    /// Files with more bytes are rejected. None accepts files of any size.
+    pub max_size: Option<u64>,
+    /// If true each file has to be accepted by the user before it is received.
+    pub ask: bool,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> DownloadOptions {
+        DownloadOptions {
+            dir: PathBuf::from("/tmp"),
+            max_size: Some(DEFAULT_MAX_SIZE),
+            ask: false,
+        }
+    }
+}
+
+/// An offer which waits for the decision of the user.
+struct PendingOffer {
+    id: u32,
+    ip: String,
+    info: FileInfo,
+}
+
+/// Files which are received from peers.
+///
+/// Received parts are written into a file in the given directory. Together with the
+/// manifest of the transfer this allows to resume a transfer after a restart.
+#[derive(Clone)]
+pub struct Downloads {
+    /// Manifests of the transfers in progress indexed by the names of the manifests.
+    manifests: Arc<Mutex<HashMap<String, Manifest>>>,
+    offers: Arc<Mutex<Vec<PendingOffer>>>,
+    dir: PathBuf,
+    options: DownloadOptions,
+    layers: Layers,
+    console: Console,
+}
+
+impl Downloads {
+
+    pub fn new(dir: PathBuf, options: DownloadOptions, layers: Layers, console: Console) -> Downloads {
+        Downloads {
+            manifests: Arc::new(Mutex::new(HashMap::new())),
+            offers: Arc::new(Mutex::new(vec![])),
+            dir,
+            options,
+            layers,
+            console,
+        }
+    }
+
+    /// Processes a message of a file transfer received from the given IP.
+    pub fn receive(&self, ip: String, t: Transfer) {
+        match t {
+            Transfer::Offer(info) => self.offer(ip, info),
+            Transfer::Chunk(hash, seq, data) => self.chunk(ip, &hash, seq, &data),
+            Transfer::Cancel(hash, ids) => self.stop(ip, &hash, &ids, true),
+            Transfer::Pause(hash, ids) => self.stop(ip, &hash, &ids, false),
+            Transfer::Missing(_, _) | Transfer::Reject(_) => { }
+        }
+    }
+
+    /// Checks an offer before anything is written to disk. Offers of files which are too
+    /// big are rejected. If the user has to decide the offer is kept until the user
+    /// accepts or rejects it. Transfers which have been accepted before are resumed.
+    fn offer(&self, ip: String, info: FileInfo) {
+        let name = manifest::name(&info.hash, &ip);
+        let accepted = self.manifests.lock().expect("Lock failed.").contains_key(&name) ||
+            Manifest::load(&self.dir, &info.hash, &ip).is_ok_and(|m| m.is_some());
+
+        if !accepted {
+            if let Some(max) = self.options.max_size.filter(|max| info.size > *max) {
+                self.console.error(format!("Rejected file '{}' with {} bytes from {}. Files with more than {} bytes are not accepted.",
+                                           info.name, info.size, ip, max));
+                self.send_reject(ip, info.hash);
+                return;
+            }
+            if self.options.ask {
+                let mut offers = self.offers.lock().expect("Lock failed.");
+                if !offers.iter().any(|o| o.ip == ip && o.info.hash == info.hash) {
+                    let id = offers.last().map_or(1, |o| o.id + 1);
+                    self.console.status(format!("{} wants to send file '{}' with {} bytes. Type /accept {} or /reject {}.",
+                                                ip, info.name, info.size, id, id));
+                    offers.push(PendingOffer { id, ip, info });
+                }
+                return;
+            }
+        }
+        self.start(ip, info);
+    }
+
+    /// Accepts the offer with the given id.
+    pub fn accept(&self, id: u32) -> Result<(), &'static str> {
+        let o = self.take_offer(id)?;
+        self.start(o.ip, o.info);
+        Ok(())
+    }
+
+    /// Rejects the offer with the given id.
+    pub fn reject(&self, id: u32) -> Result<(), &'static str> {
+        let o = self.take_offer(id)?;
+        self.console.status(format!("Rejected file '{}' from {}.", o.info.name, o.ip));
+        self.send_reject(o.ip, o.info.hash);
+        Ok(())
+    }
+
+    fn take_offer(&self, id: u32) -> Result<PendingOffer, &'static str> {
+        let mut offers = self.offers.lock().expect("Lock failed.");
+        match offers.iter().position(|o| o.id == id) {
+            Some(i) => Ok(offers.remove(i)),
+            None => Err("No such file.")
+        }
+    }
+
+    fn send_reject(&self, ip: String, hash: Vec<u8>) {
+        self.layers.send(Message::file_upload(ip, &Transfer::Reject(hash)), rand::random::<u64>(), true);
+    }
+
+    /// Answers an offer with the parts of the file which have not been received yet.
+    fn start(&self, ip: String, info: FileInfo) {
+        let mut manifests = self.manifests.lock().expect("Lock failed.");
+        let name = manifest::name(&info.hash, &ip);
+
+        if !manifests.contains_key(&name) {
+            let path = self.dir.join(format!("{}.part", name));
+            match Manifest::open(&self.dir, &info, &ip, path.to_str().unwrap_or_default()) {
+                Ok(m) => {
+                    match m.received() {
+                        0 => self.console.status(format!("Receiving file '{}' with {} bytes.", info.name, info.size)),
+                        n => self.console.status(format!("Resuming download of '{}' ({} of {} parts received).", info.name, n, info.chunks()))
+                    }
+                    manifests.insert(name.clone(), m);
+                },
+                Err(e) => {
+                    self.console.error(format!("Could not receive file '{}'. {}", info.name, e));
+                    return;
+                }
+            }
+        }
+
+        let missing = manifests[&name].missing();
+        self.layers.send(Message::file_upload(ip, &Transfer::Missing(info.hash, missing)), rand::random::<u64>(), true);
+    }
+
+    /// Is called when the sender has stopped a transfer. The parts of the messages with the
+    /// given ids which have been received so far are dropped. If the transfer has been
+    /// canceled the partially received file is deleted as well.
+    fn stop(&self, ip: String, hash: &[u8], ids: &[u64], cancel: bool) {
+        self.layers.discard(ids);
+        if !cancel {
+            if let Some(m) = self.manifests.lock().expect("Lock failed.").get(&manifest::name(hash, &ip)) {
+                self.console.status(format!("Transfer of '{}' from {} has been paused by the sender.", m.info.name, ip));
+            }
+            return;
+        }
+
+        let mut offers = self.offers.lock().expect("Lock failed.");
+        if let Some(i) = offers.iter().position(|o| o.ip == ip && o.info.hash == hash) {
+            let o = offers.remove(i);
+            self.console.status(format!("Transfer of '{}' from {} has been canceled by the sender.", o.info.name, ip));
+        }
+
+        let mut manifests = self.manifests.lock().expect("Lock failed.");
+        let m = match manifests.remove(&manifest::name(hash, &ip)) {
+            Some(m) => Some(m),
+            None => Manifest::load(&self.dir, hash, &ip).ok().and_then(|m| m)
+        };
+        if let Some(m) = m {
+            let _ = fs::remove_file(&m.path);
+            let name = m.info.name.clone();
+            m.remove();
+            self.console.status(format!("Transfer of '{}' from {} has been canceled by the sender.", name, ip));
+        }
+    }
+
+    /// Writes a received part into the file.
+    fn chunk(&self, ip: String, hash: &[u8], seq: u32, data: &[u8]) {
+        let mut manifests = self.manifests.lock().expect("Lock failed.");
+        let name = manifest::name(hash, &ip);
+
+        // The transfer could have been started before a restart.
+        if !manifests.contains_key(&name) {
+            match Manifest::load(&self.dir, hash, &ip) {
+                Ok(Some(m)) => { manifests.insert(name.clone(), m); },
+                _ => {
+                    self.console.error("Received part of an unknown file.".to_string());
+                    return;
+                }
+            }
+        }
+
+        let m = manifests.get_mut(&name).unwrap();
+        if m.contains(seq) {
+            return;
+        }
+        let offset = match m.info.chunk_range(seq) {
+            Some((offset, len)) if len == data.len() => offset,
+            _ => {
+                self.console.error(format!("Received invalid part of file '{}'.", m.info.name));
+                return;
+            }
+        };
+        if let Err(e) = write_at(&m.path, offset, data).and_then(|_| m.add(seq)) {
+            self.console.error(format!("Could not write part of file '{}'. {}", m.info.name, e));
+            return;
+        }
+
+        if m.is_complete() {
+            let m = manifests.remove(&name).unwrap();
+            self.finish(ip, m);
+        }
+    }
+
+    /// Verifies a completely received file and moves it to its destination. A file which
+    /// does not match the offer is discarded.
+    fn finish(&self, ip: String, m: Manifest) {
+        if let Err(e) = verify(&m.path, &m.info) {
+            let name = m.info.name.clone();
+            let _ = fs::remove_file(&m.path);
+            m.remove();
+            self.console.error(format!("Received file '{}' has been discarded. {}", name, e));
+            return;
+        }
+
+        let _ = fs::create_dir_all(&self.options.dir);
+        let dst = destination(&self.options.dir, &m.info.name);
+        self.console.new_file(ip, m.info.name.clone());
+
+        if m.info.is_archive() {
+            return self.finish_archive(m, &dst);
+        }
+
+        let dst = dst.to_str().unwrap_or_default();
+        if move_file(&m.path, dst) {
+            if set_metadata(Path::new(dst), m.info.mtime, m.info.mode).is_err() {
+                self.console.error(format!("Could not set permissions and modification time of '{}'.", dst));
+            }
+            m.remove();
+            self.console.status(format!("File written to '{}'.", dst));
+        } else {
+            self.console.error("Could not write data of received file upload.".to_string());
+        }
+    }
+
+    /// Extracts a received archive into the new directory `dst`.
+    fn finish_archive(&self, m: Manifest, dst: &Path) {
+        let r = extract(&m.path, dst).and_then(|_| set_metadata(dst, m.info.mtime, m.info.mode));
+        let _ = fs::remove_file(&m.path);
+        let name = m.info.name.clone();
+        m.remove();
+        match r {
+            Ok(_) => self.console.status(format!("Files written to '{}'.", dst.display())),
+            Err(e) => {
+                let _ = fs::remove_dir_all(dst);
+                self.console.error(format!("Received files '{}' have been discarded. {}", name, e));
+            }
+        }
+    }
+}
+
+/// Returns a path for a file with the given name in the directory which does not exist yet.
+/// If the name is taken a number is appended.
+fn destination(dir: &Path, name: &str) -> PathBuf {
+    let mut path = dir.join(name);
+    let mut n = 1;
+    while path.symlink_metadata().is_ok() {
+        path = dir.join(format!("{}.{}", name, n));
+        n += 1;
+    }
+    path
+}
+
+/// Checks that the file has the size and the SHA-256 hash of the offer.
+fn verify(path: &str, info: &FileInfo) -> Result<(), &'static str> {
+    let (hash, size) = sha256(&mut File::open(path).map_err(|_| "Could not open file.")?)?;
+    if size != info.size {
+        return Err("The size does not match.");
+    }
+    if hash != info.hash {
+        return Err("The SHA-256 checksum does not match.");
+    }
+    Ok(())
+}
+
+/// Sets the modification time and the permissions of a received file or directory. Special
+/// bits like setuid are not set.
+pub fn set_metadata(path: &Path, mtime: u64, mode: u32) -> Result<(), &'static str> {
+    File::open(path)
+        .and_then(|f| f.set_modified(UNIX_EPOCH + Duration::from_secs(mtime)))
+        .map_err(|_| "Could not set modification time.")?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))
+        .map_err(|_| "Could not set permissions.")
+}
+
+/// Writes data at the given offset into the file and waits until it is stored.
+fn write_at(path: &str, offset: u64, data: &[u8]) -> Result<(), &'static str> {
+    let mut f = OpenOptions::new().create(true).truncate(false).write(true).open(path).map_err(|_| "Could not open file.")?;
+    f.seek(SeekFrom::Start(offset)).map_err(|_| "Could not seek in file.")?;
+    f.write_all(data).map_err(|_| "Could not write file.")?;
+    // The part must be on disk before it is marked as received in the manifest.
+    f.sync_data().map_err(|_| "Could not write file.")
+}
+
+/// Moves a file. If it cannot be renamed, e.g. because the destination is on another
+/// device, it is copied.
+fn move_file(src: &str, dst: &str) -> bool {
+    fs::rename(src, dst).is_ok() || (fs::copy(src, dst).is_ok() && fs::remove_file(src).is_ok())
+}
@@ -6,4 +6,6 @@ pub enum Errors {
 pub enum ErrorType {
     DecryptionError,
     ReceiveError,
+    HandshakeError,
+    RelayError,
 }
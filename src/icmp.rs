@@ -0,0 +1,118 @@
+use std::sync::mpsc::Sender;
+
+use crate::transport::{Datagram, Transport};
+
+// Maximum size of the payload of an ICMP echo request we send.
+const MAX_PAYLOAD_SIZE: usize = 8192;
+
+pub fn string_from_cstr(cstr: *const u8) -> String {
+
+    let mut v: Vec<u8> = vec![];
+    let mut i = 0;
+    loop { unsafe {
+        let c = *cstr.offset(i);
+        if c == 0 { break; } else { v.push(c); }
+        i += 1;
+    }}
+    String::from_utf8(v).unwrap()
+}
+
+// Callback functions.------------------------------------------------------------------
+
+/// Callback function called by the ICMP C library.
+extern "C" fn callback(target: *mut Sender<Datagram>, buf: *const u8, len: u32, typ: u32, srcip: *const u8, token: u32) {
+
+    let reply = match typ {
+        // for values look into the enum in icmp/net.h
+        0 => false, // ping
+        1 => true,  // pong
+        // Invalid packets are ignored.
+        _ => return
+    };
+
+    if len == 0 {
+        return;
+    }
+
+    let data = unsafe { std::slice::from_raw_parts(buf, len as usize).to_vec() };
+    let ip = string_from_cstr(srcip);
+    unsafe {
+        // If the receiver has hung up there is nobody interested in the packet.
+        let _ = (*target).send(Datagram::new(data, ip, reply, token));
+    }
+}
+
+#[link(name = "icmp")]
+extern "C" {
+    fn send_icmp(ip: *const u8, id: u16, seq: u16, buf: *const u8, siz: u16) -> libc::c_int;
+    fn send_icmp_reply(ip: *const u8, token: u32, buf: *const u8, siz: u16) -> libc::c_int;
+}
+
+// TODO warning about improper ctypes is disabled; we should enable it again
+// and try to eliminate all warnings
+#[link(name = "pcap")]
+#[allow(improper_ctypes)]
+extern "C" {
+    fn recv_callback(
+        target: *mut Sender<Datagram>,
+        dev: *const u8,
+        cb: extern "C" fn(*mut Sender<Datagram>, *const u8, u32, u32, *const u8, u32)) -> libc::c_int;
+}
+
+// -------------------------------------------------------------------------------------
+
+/// Transmits datagrams as payload of ICMP echo requests. Replies are sent as ICMP echo replies.
+pub struct IcmpTransport {
+    dev: String,
+}
+
+impl IcmpTransport {
+    /// Creates a new transport which listens for ICMP packets on the given device.
+    pub fn new(dev: &str) -> IcmpTransport {
+        IcmpTransport {
+            dev: dev.to_string(),
+        }
+    }
+}
+
+impl Transport for IcmpTransport {
+
+    fn send(&self, ip: &str, buf: &[u8]) -> bool {
+        self.send_echo(ip, rand::random::<u16>(), rand::random::<u16>(), buf)
+    }
+
+    fn send_echo(&self, ip: &str, id: u16, seq: u16, buf: &[u8]) -> bool {
+        let ip = ip.to_string() + "\0";
+        unsafe {
+            send_icmp(ip.as_ptr(), id, seq, buf.as_ptr(), buf.len() as u16) == 0
+        }
+    }
+
+    fn reply(&self, ip: &str, token: u32, buf: &[u8]) -> bool {
+        let ip = ip.to_string() + "\0";
+        unsafe {
+            send_icmp_reply(ip.as_ptr(), token, buf.as_ptr(), buf.len() as u16) == 0
+        }
+    }
+
+    fn listen(&self, tx: Sender<Datagram>) -> Result<(), &'static str> {
+        let sdev = self.dev.clone() + "\0";
+        // The sender must be on the heap because it is used by the capture thread of the C
+        // library which lives as long as the application.
+        let target = Box::into_raw(Box::new(tx));
+        unsafe {
+            // call to C function in icmp/net.c
+            match recv_callback(target, sdev.as_ptr(), callback) {
+                -1 => {
+                    drop(Box::from_raw(target));
+                    Err("Could not listen on device.")
+                },
+                _ => Ok(())
+            }
+        }
+    }
+
+    fn max_payload(&self) -> usize {
+        MAX_PAYLOAD_SIZE
+    }
+}
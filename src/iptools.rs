@@ -1,7 +1,9 @@
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+
+use crate::peers::Peers;
 
 pub struct IpAddresses {
-    ips: Vec<Ipv4Addr>
+    ips: Vec<IpAddr>
 }
 
 impl IpAddresses {
@@ -15,9 +17,98 @@ impl IpAddresses {
         }
     }
 
+    /// Creates the list from a comma separated list of IPs and names of peers in the peer
+    /// directory. If the list is empty all peers of the directory are used.
+    pub fn from_peers(s: &str, peers: &Peers) -> Result<IpAddresses, &'static str> {
+        let mut ips = vec![];
+        for x in s.split(",").map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            ips.push(match peers.find(x) {
+                Some(p) => p.ip.clone(),
+                None => x.to_string()
+            });
+        }
+        if ips.is_empty() {
+            ips = peers.ips();
+        }
+
+        let mut r = vec![];
+        for ip in ips {
+            r.push(ip.parse().map_err(|_| "Found invalid IP address or unknown peer.")?);
+        }
+        Ok(IpAddresses {
+            ips: r
+        })
+    }
+
+    /// Returns the list with the given IPs appended if they are not in the list already.
+    pub fn with(&self, ips: &[String]) -> Result<IpAddresses, &'static str> {
+        let mut r = self.ips.clone();
+        for ip in ips {
+            let ip = ip.parse().map_err(|_| "Found invalid IP address.")?;
+            if !r.contains(&ip) {
+                r.push(ip);
+            }
+        }
+        Ok(IpAddresses {
+            ips: r
+        })
+    }
+
     pub fn as_strings(&self) -> Vec<String> {
         self.ips.iter().map(|x| x.to_string()).collect()
     }
+
+    /// Returns the IP of the given peer if it is in the list. The peer is given by its IP or
+    /// by its name in the peer directory.
+    pub fn find(&self, peer: &str, peers: &Peers) -> Option<String> {
+        let ip = peers.find(peer).map_or(peer.to_string(), |p| p.ip.clone());
+        let ip = ip.parse::<IpAddr>().map_or(ip, |x| x.to_string());
+        self.as_strings().into_iter().find(|x| *x == ip)
+    }
 }
 
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
 
+#[cfg(test)]
+mod tests {
+
+    use super::IpAddresses;
+    use crate::peers::Peers;
+
+    #[test]
+    fn test_from_peers() {
+        let peers = Peers::parse("[bob]\nip = \"1.2.3.4\"\n[carol]\nip = \"5.6.7.8\"").unwrap();
+
+        let ips = IpAddresses::from_peers("bob, 9.9.9.9", &peers).unwrap();
+        assert_eq!(ips.as_strings(), vec!["1.2.3.4", "9.9.9.9"]);
+        let ips = IpAddresses::from_peers("", &peers).unwrap();
+        assert_eq!(ips.as_strings(), vec!["1.2.3.4", "5.6.7.8"]);
+        assert!(IpAddresses::from_peers("dave", &peers).is_err());
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let ips = IpAddresses::from_comma_list("1.2.3.4, 2001:DB8:0::1");
+        assert_eq!(ips.as_strings(), vec!["1.2.3.4", "2001:db8::1"]);
+
+        let peers = Peers::parse("[bob]\nip = \"fd00::2\"").unwrap();
+        let ips = IpAddresses::from_peers("bob,::1", &peers).unwrap();
+        assert_eq!(ips.as_strings(), vec!["fd00::2", "::1"]);
+        assert_eq!(ips.with(&["FD00::2".to_string()]).unwrap().as_strings().len(), 2);
+        assert!(IpAddresses::from_peers("fd00::2::1", &peers).is_err());
+        assert_eq!(ips.find("FD00:0::2", &peers), Some("fd00::2".to_string()));
+    }
+
+    #[test]
+    fn test_find() {
+        let peers = Peers::parse("[bob]\nip = \"1.2.3.4\"\n[carol]\nip = \"5.6.7.8\"").unwrap();
+        let ips = IpAddresses::from_comma_list("1.2.3.4,9.9.9.9");
+
+        assert_eq!(ips.find("bob", &peers), Some("1.2.3.4".to_string()));
+        assert_eq!(ips.find("9.9.9.9", &peers), Some("9.9.9.9".to_string()));
+        assert_eq!(ips.find("carol", &peers), None);
+        assert_eq!(ips.find("dave", &peers), None);
+    }
+}
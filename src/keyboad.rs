@@ -19,6 +19,7 @@ pub enum UserInput {
     PageUp,
     CtrlR,
     CtrlS,
+    Tab,
 }
 
 /// Use to receive user input.
@@ -78,6 +79,8 @@ impl InputKeyboard {
             Some(UserInput::CtrlR)
         } else if buf == vec![19] {          // Ctrl + S
             Some(UserInput::CtrlS)
+        } else if buf == vec![9] {           // Tab
+            Some(UserInput::Tab)
         } else if buf.len() < 3 {            // Some character
             Some(UserInput::Character(buf))
         } else if buf == vec![27, 91, 53, 126] { // Page up
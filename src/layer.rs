@@ -1,13 +1,21 @@
+use std::collections::{HashMap, HashSet};
 use std::thread;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
 
-use crate::cryp::{Encryption, SymmetricEncryption, AsymmetricEncryption};  // Implemenation for encryption layer
+use crate::cryp::{Encryption, SymmetricEncryption, AeadEncryption, AsymmetricEncryption};  // Implemenation for encryption layer
+use crate::binding::NatMode;
 use crate::delivery::Delivery;
-use crate::binding::Network;
-use crate::message::{IncomingMessage, Message};
+use crate::packet;
+use crate::stats::Stats;
+use crate::transport::Transport;
+use crate::message::{IncomingMessage, Message, MessageType};
 use crate::error::ErrorType;
 use crate::iptools::IpAddresses;
+use crate::peers::Peers;
+use crate::model::DeliveryStatus;
+use crate::relay::Envelope;
 use crate::Console;
 
 pub struct Layer {
@@ -15,24 +23,50 @@ pub struct Layer {
     pub layers: Layers,
 }
 
+/// Time in milliseconds to wait for a session with a peer before a message is dropped.
+const HANDSHAKE_TIMEOUT: u64 = 20000;
+
+/// Queues of the messages which are sent in the background indexed by the IPs of the peers.
+type Queues = Arc<Mutex<HashMap<String, Sender<(Message, u64)>>>>;
+
+#[derive(Clone)]
 pub struct Layers {
     encryption_layer: Arc<Box<dyn Encryption>>,
     delivery_layer  : Arc<Box<Delivery>>,
     console: Console,
+    /// Ids of messages which are not sent by the application, i.e. handshakes and requests
+    /// to relays. Acks for these messages are not sent to the application.
+    internal: Arc<Mutex<HashSet<u64>>>,
+    /// Maximum size of the payload of a packet for peers which have a limit in the peer
+    /// directory.
+    max_payload: HashMap<String, usize>,
+    /// If true messages are compressed before they are encrypted.
+    compress: bool,
+    /// IPs of the relays via which messages are exchanged with peers that have a relay in
+    /// the peer directory.
+    relays: HashMap<String, String>,
+    /// Queues of the messages which are sent in the background, one for each peer.
+    queues: Queues,
 }
 
 impl Layers {
 
-    pub fn symmetric(hexkey: &String, device: &String, console: Console, accept_ip: &IpAddresses) -> Result<Layer, &'static str> {
+    pub fn symmetric(hexkey: &str, peers: &Peers, transport: Box<dyn Transport>, console: Console, accept_ip: &IpAddresses) -> Result<Layer, &'static str> {
 
-        Layers::init(Box::new(SymmetricEncryption::new(hexkey)?), device, console, accept_ip)
+        Layers::init(Box::new(AeadEncryption::new(hexkey, peers)?), peers, transport, console, accept_ip)
     }
 
-    pub fn asymmetric(pubkey_file: &String, privkey_file: &String, device: &String, console: Console, accept_ip: &IpAddresses) -> Result<Layer, &'static str> {
+    /// Uses the unauthenticated Blowfish encryption of older versions of stealthy.
+    pub fn legacy(hexkey: &str, peers: &Peers, transport: Box<dyn Transport>, console: Console, accept_ip: &IpAddresses) -> Result<Layer, &'static str> {
+
+        Layers::init(Box::new(SymmetricEncryption::new(hexkey, peers)?), peers, transport, console, accept_ip)
+    }
+
+    pub fn asymmetric(pubkey_file: &str, privkey_file: &str, peers: &Peers, transport: Box<dyn Transport>, console: Console, accept_ip: &IpAddresses) -> Result<Layer, &'static str> {
 
         Layers::init(Box::new(
-            AsymmetricEncryption::new(&pubkey_file, &privkey_file)?
-        ), device, console, accept_ip
+            AsymmetricEncryption::new(pubkey_file, privkey_file, accept_ip, peers)?
+        ), peers, transport, console, accept_ip
         )
     }
 
@@ -40,17 +74,40 @@ impl Layers {
 
         let console = self.console.clone();
         let e = self.encryption_layer.clone();
-        let p = self.delivery_layer.get_pending();
-        let shared = self.delivery_layer.get_shared();
-        let n = self.delivery_layer.max_size();
+        let d = self.delivery_layer.clone();
+        let internal = self.internal.clone();
+        let relay = self.relays.get(&msg.get_ip()).cloned();
+        let max_payload = self.max_payload.get(relay.as_ref().unwrap_or(&msg.ip)).cloned();
+        let compress = self.compress;
 
         let t = thread::spawn(move || {
-            match e.encrypt(&msg.buf) {
-                Ok(buf) => {
-                    Delivery::send_msg(msg.set_payload(buf), id, p, shared, console.clone(), n).run();
+            let ip = msg.get_ip();
+            if !Layers::establish(&e, &d, &internal, &ip, console.clone()) {
+                console.status(format!("Could not establish a session with {}.", ip));
+                console.delivery_status(id, DeliveryStatus::Failed);
+                return;
+            }
+            let r = match compress {
+                true => e.encrypt_compressed(&ip, &msg.buf),
+                false => e.encrypt(&ip, &msg.buf)
+            };
+            // Messages for a peer with a relay are encrypted a second time for the relay.
+            let r = match relay {
+                Some(relay) => r.and_then(|data| {
+                    let file = matches!(msg.typ, MessageType::FileUpload);
+                    let buf = e.encrypt(&relay, &Envelope::Forward { to: ip, file, data }.serialize())?;
+                    Ok(Message::new(relay, buf))
+                }),
+                None => r.map(|buf| msg.set_payload(buf))
+            };
+            match r {
+                Ok(m) => {
+                    let siz = max_payload.map_or(d.max_size(), |n| n.min(d.max_size()));
+                    Layers::transmit(&d, m, id, console.clone(), siz);
                 },
                 _ => {
                     console.status(format!("Encryption failed."));
+                    console.delivery_status(id, DeliveryStatus::Failed);
                 }
             }
         });
@@ -60,31 +117,152 @@ impl Layers {
         }
     }
 
-    pub fn encryption_key(&self) -> Vec<u8> {
-        self.encryption_layer.encryption_key()
+    /// Sends the message in the background, i.e. the caller is not blocked while a session
+    /// with the peer is established. Messages for the same peer are sent in the order in
+    /// which they have been queued. Failures are reported via the delivery status.
+    pub fn queue(&self, msg: Message, id: u64) {
+
+        let mut queues = self.queues.lock().expect("Lock failed.");
+        let tx = queues.entry(msg.get_ip()).or_insert_with(|| {
+            let (tx, rx) = channel::<(Message, u64)>();
+            let l = self.clone();
+            thread::spawn(move || {
+                for (msg, id) in rx {
+                    l.send(msg, id, false);
+                }
+            });
+            tx
+        });
+        tx.send((msg, id)).expect("Send failed.");
+    }
+
+    /// Enables or disables the compression of messages. Messages are only compressed if
+    /// this makes them smaller and if the encryption supports it, i.e. not in legacy mode.
+    pub fn set_compression(&mut self, on: bool) {
+        self.compress = on;
+    }
+
+    /// Sets how packets are exchanged with the peers. One side of a chat can sit behind a
+    /// NAT if it uses `NatMode::Client` and the other side uses `NatMode::Server`.
+    pub fn set_nat_mode(&self, mode: NatMode) {
+        self.delivery_layer.set_nat_mode(mode);
+    }
+
+    /// Asks the relays of the peers for the messages which have been left for us.
+    pub fn fetch(&self) {
+        let mut relays = self.relays.values().cloned().collect::<Vec<_>>();
+        relays.sort();
+        relays.dedup();
+        for relay in relays {
+            match self.encryption_layer.encrypt(&relay, &Envelope::Fetch.serialize()) {
+                Ok(buf) => Layers::send_internal(&self.delivery_layer, &self.internal, Message::new(relay, buf), self.console.clone()),
+                Err(_) => self.console.error(format!("Could not ask relay {} for messages.", relay))
+            }
+        }
+    }
+
+    /// Returns true if messages are exchanged with some peers via relays.
+    pub fn has_relays(&self) -> bool {
+        !self.relays.is_empty()
+    }
+
+    /// Stops sending the messages with the given ids. Parts which have not been
+    /// acknowledged are not sent again.
+    pub fn cancel(&self, ids: &[u64]) {
+        for id in ids {
+            self.delivery_layer.cancel(*id);
+        }
+    }
+
+    /// Drops the parts of the messages with the given ids which have been received so far.
+    pub fn discard(&self, ids: &[u64]) {
+        for id in ids {
+            self.delivery_layer.discard(*id);
+        }
+    }
+
+    /// Sets the lower bound of the retransmission timeout in milliseconds.
+    #[cfg(test)]
+    pub fn set_min_rto(&self, millis: i64) {
+        self.delivery_layer.set_min_rto(millis);
+    }
+
+    /// Sets the number of retries after which a packet is given up.
+    #[cfg(test)]
+    pub fn set_max_retries(&self, n: u32) {
+        self.delivery_layer.set_max_retries(n);
+    }
+
+    /// Returns the key used to encrypt messages for the given IP.
+    pub fn encryption_key(&self, ip: &str) -> Vec<u8> {
+        self.encryption_layer.encryption_key(ip)
+    }
+
+    /// Returns statistics about the received packets.
+    pub fn stats(&self) -> Stats {
+        self.delivery_layer.stats()
     }
 
     // ------ private functions
 
-    fn init(e: Box<dyn Encryption>, device: &String, console: Console, accept_ip: &IpAddresses) -> Result<Layer, &'static str> {
+    /// Sends a handshake to the peer if the encryption layer requires a new session and
+    /// waits until data can be encrypted for the peer.
+    fn establish(e: &Arc<Box<dyn Encryption>>, d: &Delivery, internal: &Mutex<HashSet<u64>>, ip: &str, console: Console) -> bool {
+
+        match e.session_request(ip) {
+            Ok(Some(buf)) => Layers::send_internal(d, internal, Message::new(ip.to_string(), buf), console),
+            Ok(None) => { },
+            Err(_) => return false
+        }
+        e.wait_session(ip, Duration::from_millis(HANDSHAKE_TIMEOUT))
+    }
 
-        // network  tx1 --- incoming message ---> rx1 delivery
-        // delivery tx2 --- incoming message ---> rx2 layers
-        let (tx1, rx1) = channel();
-        let (tx2, rx2) = channel();
-        Ok(Layers::new(e,
+    /// Sends a message whose acks are not sent to the application.
+    fn send_internal(d: &Delivery, internal: &Mutex<HashSet<u64>>, msg: Message, console: Console) {
+
+        let id = rand::random::<u64>();
+        internal.lock().expect("Lock failed.").insert(id);
+        Layers::transmit(d, msg, id, console, d.max_size());
+    }
+
+    /// Hands an encrypted message over to the delivery layer. `siz` is the maximum size of
+    /// the payload of a single packet.
+    fn transmit(d: &Delivery, msg: Message, id: u64, console: Console, siz: usize) {
+
+        Delivery::send_msg(msg, id, d.get_pending(), d.get_shared(), console, siz).run();
+    }
+
+    /// Creates all layers on top of the given transport.
+    pub fn init(e: Box<dyn Encryption>, peers: &Peers, transport: Box<dyn Transport>, console: Console, accept_ip: &IpAddresses) -> Result<Layer, &'static str> {
+
+        // delivery tx --- incoming message ---> rx layers
+        let (tx, rx) = channel();
+        let keys = e.header_keys(accept_ip.as_strings());
+        // The limit of the ICMP payload includes the headers of the transport and of the
+        // packets, which the delivery layer does not know about.
+        let max_payload = peers.iter()
+            .filter_map(|p| p.max_payload.map(|n| (p.ip.clone(), transport.datagram_size(n).saturating_sub(packet::OVERHEAD))))
+            .collect();
+        let relays = peers.iter()
+            .filter_map(|p| peers.relay(&p.ip).map(|r| (p.ip.clone(), r)))
+            .collect();
+        Ok(Layers::start(e,
                        Delivery::new(
-                           Network::new(device, tx1, console.clone(), accept_ip),
-                           tx2,
-                           rx1,
+                           transport,
+                           keys,
+                           tx,
                            console.clone(),
+                           accept_ip,
                        ),
-                       rx2,
-                       console
+                       rx,
+                       console,
+                       max_payload,
+                       relays
         ))
     }
 
-    fn new(e: Box<dyn Encryption>, d: Delivery, rx_network: Receiver<IncomingMessage>, console: Console) -> Layer {
+    /// Creates the layers and starts processing the messages received from the network.
+    fn start(e: Box<dyn Encryption>, d: Delivery, rx_network: Receiver<IncomingMessage>, console: Console, max_payload: HashMap<String, usize>, relays: HashMap<String, String>) -> Layer {
 
         // tx is used to send received messages to the application via rx
         let (tx, rx) = channel::<IncomingMessage>();
@@ -92,12 +270,17 @@ impl Layers {
         let l = Layers {
             encryption_layer: Arc::new(e),
             delivery_layer: Arc::new(Box::new(d)),
-            console: console
+            console,
+            internal: Arc::new(Mutex::new(HashSet::new())),
+            max_payload,
+            compress: false,
+            relays,
+            queues: Arc::new(Mutex::new(HashMap::new())),
         };
 
         l.recv_loop(tx, rx_network);
         Layer {
-            rx: rx,
+            rx,
             layers: l,
         }
     }
@@ -107,19 +290,84 @@ impl Layers {
 
         let enc = self.encryption_layer.clone();
         let console = self.console.clone();
+        let d = self.delivery_layer.clone();
+        let internal = self.internal.clone();
+        let relays = self.relays.values().cloned().collect::<HashSet<_>>();
 
         thread::spawn(move || { loop { match rx.recv() {
-            Ok(msg) => match Layers::handle_message(msg, enc.clone(), console.clone()) {
-                Some(m) => match tx.send(m) {
-                    Err(_) => panic!("Channel closed."),
-                    _ => { }
-                },
-                _ => Layers::err(ErrorType::DecryptionError, "Could not decrypt received message.", &tx)
+            Ok(IncomingMessage::New(msg)) if enc.is_handshake(&msg.buf) => {
+                match enc.handshake(&msg.ip, &msg.buf) {
+                    Ok(Some(buf)) => Layers::send_internal(&d, &internal, msg.set_payload(buf), console.clone()),
+                    Ok(None) => { },
+                    Err(s) => Layers::err(ErrorType::HandshakeError, s, &tx)
+                }
+            },
+            // Acks of internal messages are not sent to the application.
+            Ok(IncomingMessage::Ack(id)) if internal.lock().expect("Lock failed.").remove(&id) => { },
+            Ok(IncomingMessage::AckProgress(id, _, _)) if internal.lock().expect("Lock failed.").contains(&id) => { },
+            Ok(IncomingMessage::Retry(id)) if internal.lock().expect("Lock failed.").contains(&id) => { },
+            Ok(IncomingMessage::Failed(id)) if internal.lock().expect("Lock failed.").remove(&id) => { },
+            Ok(IncomingMessage::New(msg)) if relays.contains(&msg.ip) => {
+                match Layers::unwrap_relayed(&msg, &enc) {
+                    Some(m) => if tx.send(m).is_err() {
+                        panic!("Channel closed.");
+                    },
+                    None => Layers::err(ErrorType::RelayError, "Could not read message from relay.", &tx)
+                }
+            },
+            Ok(msg) => {
+                let ip = Layers::source(&msg);
+                match Layers::handle_message(msg, enc.clone(), console.clone()) {
+                    Some(m) => if tx.send(m).is_err() {
+                        panic!("Channel closed.");
+                    },
+                    _ => {
+                        Layers::err(ErrorType::DecryptionError, "Could not decrypt received message.", &tx);
+                        // If we do not have a session with the peer, e.g. because stealthy
+                        // has been restarted, a new session is negotiated.
+                        if let Some(ip) = ip {
+                            if let Ok(Some(buf)) = enc.session_request(&ip) {
+                                Layers::send_internal(&d, &internal, Message::new(ip, buf), console.clone());
+                            }
+                        }
+                    }
+                }
             },
             _ => Layers::err(ErrorType::ReceiveError, "Could not receive message.", &tx)
         }}});
     }
 
+    /// Returns the IP of the sender of a received message.
+    fn source(m: &IncomingMessage) -> Option<String> {
+
+        match m {
+            IncomingMessage::New(msg) | IncomingMessage::FileUpload(msg) => Some(msg.get_ip()),
+            _ => None
+        }
+    }
+
+    /// Decrypts a message received from a relay. A message which a peer has left at the
+    /// relay is decrypted with the key of this peer and returned as if it had been
+    /// received from the peer directly.
+    fn unwrap_relayed(msg: &Message, enc: &Arc<Box<dyn Encryption>>) -> Option<IncomingMessage> {
+
+        let buf = enc.decrypt(&msg.ip, &msg.buf).ok()?;
+        match Envelope::deserialize(&buf)? {
+            Envelope::Deliver { from, file, data } => {
+                let buf = enc.decrypt(&from, &data).ok()?;
+                Some(match file {
+                    true => IncomingMessage::FileUpload(Message { ip: from, typ: MessageType::FileUpload, buf }),
+                    false => IncomingMessage::New(Message::new(from, buf))
+                })
+            },
+            Envelope::Rejected(to) => Some(IncomingMessage::Error(
+                ErrorType::RelayError,
+                format!("Relay {} has not kept a message for {}.", msg.ip, to)
+            )),
+            _ => None
+        }
+    }
+
     /// Notifies the application about an error.
     fn err(e: ErrorType, msg: &str, tx: &Sender<IncomingMessage>) {
 
@@ -143,7 +391,7 @@ impl Layers {
                 #[cfg(feature="debugout")]
                     _console.send(format!("[Layers::handle_message()] new message {}", msg.buf.len())).unwrap();
 
-                match enc.decrypt(&msg.buf) {
+                match enc.decrypt(&msg.ip, &msg.buf) {
                     Ok(buf) => Some(IncomingMessage::New(msg.set_payload(buf))),
                     Err(_m) => {
                         #[cfg(feature="debugout")]
@@ -153,7 +401,7 @@ impl Layers {
                 }
             },
             IncomingMessage::FileUpload(msg) => {
-                match enc.decrypt(&msg.buf) {
+                match enc.decrypt(&msg.ip, &msg.buf) {
                     Ok(buf) => Some(IncomingMessage::FileUpload(msg.set_payload(buf))),
                     _ => {
                         println!("decryption failed");
@@ -163,7 +411,613 @@ impl Layers {
             },
             IncomingMessage::Ack(_) => Some(m),
             IncomingMessage::Error(_, _) => Some(m),
-            IncomingMessage::AckProgress(_, _, _) => Some(m)
+            IncomingMessage::AckProgress(_, _, _) => Some(m),
+            IncomingMessage::Retry(_) => Some(m),
+            IncomingMessage::Failed(_) => Some(m)
         }
     }
 }
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{Layer, Layers};
+    use crate::binding::NatMode;
+    use crate::console::{Console, ConsoleMessage};
+    use crate::iptools::IpAddresses;
+    use crate::loopback::LoopbackBus;
+    use crate::message::{IncomingMessage, Message};
+    use crate::packet::Packet;
+    use crate::peers::Peers;
+    use crate::profile::PingTransport;
+    use crate::transport::{Datagram, Transport};
+    use crate::transfer::Transfer;
+
+    const KEY: &str = "11111111111111111111111111111111";
+
+    struct Peer {
+        layer: Layer,
+        // The receiver must live as long as the peer. Otherwise the console cannot send.
+        _console: Receiver<ConsoleMessage>,
+    }
+
+    fn peer(bus: &LoopbackBus, ip: &str, peers: &str, key: &str) -> Peer {
+        directory_peer(bus, ip, peers, key, &Peers::new())
+    }
+
+    fn directory_peer(bus: &LoopbackBus, ip: &str, peers: &str, key: &str, directory: &Peers) -> Peer {
+        let (tx, rx) = channel();
+        let layer = Layers::symmetric(
+            key,
+            directory,
+            Box::new(bus.connect(ip)),
+            Console::new(tx),
+            &IpAddresses::from_comma_list(peers)
+        ).unwrap();
+
+        Peer {
+            layer,
+            _console: rx,
+        }
+    }
+
+    fn hybrid_peer(bus: &LoopbackBus, ip: &str, peers: &str, pubkeys: &str, privkey: &str) -> Peer {
+        let (tx, rx) = channel();
+        let layer = Layers::asymmetric(
+            &pubkeys.replace("rsa_", "tests/keys/rsa_"),
+            &format!("tests/keys/{}", privkey),
+            &Peers::new(),
+            Box::new(bus.connect(ip)),
+            Console::new(tx),
+            &IpAddresses::from_comma_list(peers)
+        ).unwrap();
+
+        Peer {
+            layer,
+            _console: rx,
+        }
+    }
+
+    /// Returns the next message received by the peer ignoring progress information.
+    fn next(p: &Peer) -> IncomingMessage {
+        loop {
+            match p.layer.rx.recv_timeout(Duration::from_secs(10)).expect("Timeout.") {
+                IncomingMessage::AckProgress(_, _, _) | IncomingMessage::Retry(_) => { },
+                m => return m
+            }
+        }
+    }
+
+    fn expect_text(p: &Peer, ip: &str, txt: &str) {
+        match next(p) {
+            IncomingMessage::New(m) => {
+                assert_eq!(m.ip, ip);
+                assert_eq!(m.buf, txt.as_bytes().to_vec());
+            },
+            _ => panic!("Expected new message.")
+        }
+    }
+
+    fn expect_ack(p: &Peer) -> u64 {
+        match next(p) {
+            IncomingMessage::Ack(id) => id,
+            _ => panic!("Expected ack.")
+        }
+    }
+
+    #[test]
+    fn test_send_message() {
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", KEY);
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1", KEY);
+
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"hello".to_vec()), 1, false);
+        expect_text(&b, "10.0.0.1", "hello");
+        assert_eq!(expect_ack(&a), 1);
+
+        b.layer.layers.send(Message::new("10.0.0.1".to_string(), b"hi".to_vec()), 2, false);
+        expect_text(&a, "10.0.0.2", "hi");
+        assert_eq!(expect_ack(&b), 2);
+    }
+
+    #[test]
+    fn test_file_upload() {
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", KEY);
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1", KEY);
+
+        // Requires several packets.
+        let data = (0..20000).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+        let chunk = Transfer::Chunk(vec![1; 32], 1, data);
+        a.layer.layers.send(Message::file_upload("10.0.0.2".to_string(), &chunk), 3, true);
+
+        match next(&b) {
+            IncomingMessage::FileUpload(m) => {
+                assert_eq!(m.ip, "10.0.0.1");
+                assert_eq!(m.get_transfer().unwrap(), chunk);
+            },
+            _ => panic!("Expected file upload.")
+        }
+        assert_eq!(expect_ack(&a), 3);
+    }
+
+    #[test]
+    fn test_group_chat() {
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2,10.0.0.3", KEY);
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1,10.0.0.3", KEY);
+        let c = peer(&bus, "10.0.0.3", "10.0.0.1,10.0.0.2", KEY);
+
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"hello all".to_vec()), 4, false);
+        a.layer.layers.send(Message::new("10.0.0.3".to_string(), b"hello all".to_vec()), 5, false);
+
+        expect_text(&b, "10.0.0.1", "hello all");
+        expect_text(&c, "10.0.0.1", "hello all");
+
+        let mut acks = vec![expect_ack(&a), expect_ack(&a)];
+        acks.sort();
+        assert_eq!(acks, vec![4, 5]);
+    }
+
+    #[test]
+    fn test_mixed_group_chat() {
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "fd00::2", KEY);
+        let b = peer(&bus, "fd00::2", "10.0.0.1", KEY);
+
+        a.layer.layers.send(Message::new("fd00::2".to_string(), b"hello".to_vec()), 4, false);
+        expect_text(&b, "10.0.0.1", "hello");
+        assert_eq!(expect_ack(&a), 4);
+
+        b.layer.layers.send(Message::new("10.0.0.1".to_string(), b"hi".to_vec()), 5, false);
+        expect_text(&a, "fd00::2", "hi");
+        assert_eq!(expect_ack(&b), 5);
+    }
+
+    #[test]
+    fn test_peer_directory() {
+        let bus = LoopbackBus::new();
+        let directory = Peers::parse(r#"
+            [bob]
+            ip = "10.0.0.2"
+            key = "22222222222222222222222222222222"
+            max_payload = 128
+        "#).unwrap();
+        let a = directory_peer(&bus, "10.0.0.1", "10.0.0.2,10.0.0.3", KEY, &directory);
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1", "22222222222222222222222222222222");
+        let c = peer(&bus, "10.0.0.3", "10.0.0.1", KEY);
+
+        // Bob uses his own key, Carol the default key.
+        let data = vec![b'x'; 1000];
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), data.clone()), 6, false);
+        expect_text(&b, "10.0.0.1", &String::from_utf8(data).unwrap());
+        a.layer.layers.send(Message::new("10.0.0.3".to_string(), b"hello".to_vec()), 7, false);
+        expect_text(&c, "10.0.0.1", "hello");
+
+        // The message for Bob is split into packets of at most 128 bytes.
+        let mut fragments = 0;
+        loop {
+            match a.layer.rx.recv_timeout(Duration::from_secs(10)).expect("Timeout.") {
+                IncomingMessage::AckProgress(6, _, total) => fragments = total,
+                IncomingMessage::Ack(6) => break,
+                _ => { }
+            }
+        }
+        assert!(fragments >= 1000 / 128);
+    }
+
+    #[test]
+    fn test_compression() {
+        let bus = LoopbackBus::new();
+        let directory = Peers::parse(r#"
+            [bob]
+            ip = "10.0.0.2"
+            max_payload = 128
+        "#).unwrap();
+        let mut a = directory_peer(&bus, "10.0.0.1", "10.0.0.2", KEY, &directory);
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1", KEY);
+        a.layer.layers.set_compression(true);
+
+        // Without compression the message would be split into at least ten packets.
+        let data = vec![b'x'; 1000];
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), data.clone()), 6, false);
+        expect_text(&b, "10.0.0.1", &String::from_utf8(data).unwrap());
+        let mut fragments = 0;
+        loop {
+            match a.layer.rx.recv_timeout(Duration::from_secs(10)).expect("Timeout.") {
+                IncomingMessage::AckProgress(6, _, total) => fragments = total,
+                IncomingMessage::Ack(6) => break,
+                _ => { }
+            }
+        }
+        assert_eq!(fragments, 1);
+
+        // Data which does not shrink is sent as it is.
+        let data = (0..20000).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+        let chunk = Transfer::Chunk(vec![1; 32], 1, data);
+        a.layer.layers.send(Message::file_upload("10.0.0.2".to_string(), &chunk), 7, true);
+        match next(&b) {
+            IncomingMessage::FileUpload(m) => assert_eq!(m.get_transfer().unwrap(), chunk),
+            _ => panic!("Expected file upload.")
+        }
+    }
+
+    #[test]
+    fn test_nat() {
+        let bus = LoopbackBus::new();
+        bus.hide("10.0.0.1");
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", KEY);
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1", KEY);
+        a.layer.layers.set_nat_mode(NatMode::Client);
+        b.layer.layers.set_nat_mode(NatMode::Server);
+
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"hello".to_vec()), 1, false);
+        expect_text(&b, "10.0.0.1", "hello");
+        assert_eq!(expect_ack(&a), 1);
+
+        // The packets for a are sent in the replies to its polls.
+        b.layer.layers.send(Message::new("10.0.0.1".to_string(), b"hi".to_vec()), 2, false);
+        expect_text(&a, "10.0.0.2", "hi");
+        assert_eq!(expect_ack(&b), 2);
+
+        let data = (0..20000).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+        let chunk = Transfer::Chunk(vec![1; 32], 1, data);
+        b.layer.layers.send(Message::file_upload("10.0.0.1".to_string(), &chunk), 3, true);
+        match next(&a) {
+            IncomingMessage::FileUpload(m) => assert_eq!(m.get_transfer().unwrap(), chunk),
+            _ => panic!("Expected file upload.")
+        }
+        assert_eq!(expect_ack(&b), 3);
+    }
+
+    #[test]
+    fn test_nat_without_server() {
+        let bus = LoopbackBus::new();
+        bus.hide("10.0.0.1");
+        let _a = peer(&bus, "10.0.0.1", "10.0.0.2", KEY);
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1", KEY);
+        b.layer.layers.set_min_rto(50);
+        b.layer.layers.set_max_retries(1);
+
+        // Echo requests do not pass the NAT.
+        b.layer.layers.send(Message::new("10.0.0.1".to_string(), b"hi".to_vec()), 4, true);
+        match next(&b) {
+            IncomingMessage::Failed(4) => { },
+            _ => panic!("Expected failure.")
+        }
+    }
+
+    #[test]
+    fn test_retry_reported() {
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", KEY);
+        a.layer.layers.set_min_rto(100);
+
+        // Nobody answers so the message is sent again.
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"hello".to_vec()), 8, false);
+        loop {
+            if let IncomingMessage::Retry(id) = a.layer.rx.recv_timeout(Duration::from_secs(10)).expect("Timeout.") {
+                assert_eq!(id, 8);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_give_up() {
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", KEY);
+        a.layer.layers.set_min_rto(50);
+        a.layer.layers.set_max_retries(3);
+
+        // Nobody answers. More messages than packets which can be pending at the same time
+        // are sent. Each of them fails.
+        for id in 0..10 {
+            a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"hello".to_vec()), id, true);
+        }
+        let mut failed = vec![];
+        while failed.len() < 10 {
+            match next(&a) {
+                IncomingMessage::Failed(id) => failed.push(id),
+                _ => panic!("Expected failure.")
+            }
+        }
+        failed.sort();
+        assert_eq!(failed, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_unreachable_peer_does_not_block() {
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2,10.0.0.3", KEY);
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1", KEY);
+
+        // 10.0.0.3 does not answer, so its window is filled up.
+        for id in 0..20 {
+            a.layer.layers.send(Message::new("10.0.0.3".to_string(), b"hello".to_vec()), id, true);
+        }
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"hi".to_vec()), 20, false);
+        expect_text(&b, "10.0.0.1", "hi");
+        assert_eq!(expect_ack(&a), 20);
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", KEY);
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1", "22222222222222222222222222222222");
+
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"secret".to_vec()), 6, false);
+
+        // The packets are not authentic for b. Hence, they are dropped and not acknowledged.
+        assert!(b.layer.rx.recv_timeout(Duration::from_millis(500)).is_err());
+        assert!(a.layer.rx.recv_timeout(Duration::from_millis(100)).is_err());
+        assert!(b.layer.layers.stats().rejected_packets > 0);
+    }
+
+    /// Returns a packet with the id of the sniffed packet. Without the key the rest of the
+    /// packet cannot be read.
+    fn sniffed_packet(buf: &[u8]) -> Packet {
+        let id = buf[..8].iter().rev().fold(0, |r, b| (r << 8) + *b as u64);
+        Packet::new(vec![], "10.0.0.2".to_string(), id)
+    }
+
+    #[test]
+    fn test_forged_ack() {
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", KEY);
+
+        // The attacker uses the IP of the receiver and sniffs the packet sent by a.
+        let (tx, sniffed) = channel();
+        let attacker = bus.connect("10.0.0.2");
+        attacker.listen(tx).unwrap();
+
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"hello".to_vec()), 8, false);
+        let d = sniffed.recv_timeout(Duration::from_secs(10)).unwrap();
+        let p = sniffed_packet(&d.buf);
+
+        // Unauthenticated ack.
+        attacker.send("10.0.0.1", &Packet::create_ack(p.clone()).serialize(None));
+        // Ack with an invalid tag.
+        attacker.send("10.0.0.1", &Packet::create_ack(p).serialize(Some(&[0; 32])));
+
+        assert!(a.layer.rx.recv_timeout(Duration::from_millis(500)).is_err());
+        assert_eq!(a.layer.layers.stats().rejected_packets, 2);
+    }
+
+    #[test]
+    fn test_hybrid_session() {
+        let bus = LoopbackBus::new();
+        let a = hybrid_peer(&bus, "10.0.0.1", "10.0.0.2", "rsa_pub2.pem", "rsa_priv.pem");
+        let b = hybrid_peer(&bus, "10.0.0.2", "10.0.0.1", "rsa_pub.pem", "rsa_priv2.pem");
+
+        // The first message triggers the handshake. Its ack is not visible.
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"hello".to_vec()), 9, false);
+        expect_text(&b, "10.0.0.1", "hello");
+        assert_eq!(expect_ack(&a), 9);
+
+        // The session is used in both directions.
+        b.layer.layers.send(Message::new("10.0.0.1".to_string(), b"hi".to_vec()), 10, false);
+        expect_text(&a, "10.0.0.2", "hi");
+        assert_eq!(expect_ack(&b), 10);
+    }
+
+    #[test]
+    fn test_hybrid_forged_ack() {
+        let bus = LoopbackBus::new();
+        let a = hybrid_peer(&bus, "10.0.0.1", "10.0.0.2", "rsa_pub2.pem", "rsa_priv.pem");
+        let b = hybrid_peer(&bus, "10.0.0.2", "10.0.0.1", "rsa_pub.pem", "rsa_priv2.pem");
+
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"hello".to_vec()), 15, false);
+        expect_text(&b, "10.0.0.1", "hello");
+        assert_eq!(expect_ack(&a), 15);
+        let rejected = a.layer.layers.stats().rejected_packets;
+
+        // The attacker takes over the IP of b once the session has been established.
+        let (tx, sniffed) = channel();
+        let attacker = bus.connect("10.0.0.2");
+        attacker.listen(tx).unwrap();
+
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"secret".to_vec()), 16, false);
+        let d = sniffed.recv_timeout(Duration::from_secs(10)).unwrap();
+        let p = sniffed_packet(&d.buf);
+        // The packet is authenticated with the key of the session.
+        assert!(Packet::deserialize(&d.buf, d.ip, None).is_none());
+
+        attacker.send("10.0.0.1", &Packet::create_ack(p.clone()).serialize(None));
+        attacker.send("10.0.0.1", &Packet::create_ack(p).serialize(Some(&[0; 32])));
+
+        assert!(a.layer.rx.recv_timeout(Duration::from_millis(500)).is_err());
+        assert_eq!(a.layer.layers.stats().rejected_packets, rejected + 2);
+    }
+
+    #[test]
+    fn test_hybrid_wrong_key() {
+        let bus = LoopbackBus::new();
+        // b expects another public key of a.
+        let a = hybrid_peer(&bus, "10.0.0.1", "10.0.0.2", "rsa_pub2.pem", "rsa_priv.pem");
+        let b = hybrid_peer(&bus, "10.0.0.2", "10.0.0.1", "rsa_pub2.pem", "rsa_priv2.pem");
+
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"hello".to_vec()), 11, true);
+        match next(&b) {
+            IncomingMessage::Error(_, _) => { },
+            _ => panic!("Expected error.")
+        }
+        // No message is sent without a session.
+        assert!(b.layer.rx.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn test_hybrid_group_chat() {
+        let bus = LoopbackBus::new();
+        let a = hybrid_peer(&bus, "10.0.0.1", "10.0.0.2,10.0.0.3", "10.0.0.2=rsa_pub2.pem,10.0.0.3=rsa_pub3.pem", "rsa_priv.pem");
+        let b = hybrid_peer(&bus, "10.0.0.2", "10.0.0.1,10.0.0.3", "10.0.0.1=rsa_pub.pem,10.0.0.3=rsa_pub3.pem", "rsa_priv2.pem");
+        let c = hybrid_peer(&bus, "10.0.0.3", "10.0.0.1,10.0.0.2", "10.0.0.1=rsa_pub.pem,10.0.0.2=rsa_pub2.pem", "rsa_priv3.pem");
+
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"hello all".to_vec()), 12, false);
+        a.layer.layers.send(Message::new("10.0.0.3".to_string(), b"hello all".to_vec()), 13, false);
+        expect_text(&b, "10.0.0.1", "hello all");
+        expect_text(&c, "10.0.0.1", "hello all");
+
+        c.layer.layers.send(Message::new("10.0.0.2".to_string(), b"hi b".to_vec()), 14, false);
+        expect_text(&b, "10.0.0.3", "hi b");
+
+        let mut acks = vec![expect_ack(&a), expect_ack(&a)];
+        acks.sort();
+        assert_eq!(acks, vec![12, 13]);
+        assert_eq!(expect_ack(&c), 14);
+    }
+
+    #[test]
+    fn test_queue() {
+        let bus = LoopbackBus::new();
+        // b is offline, hence no session can be established with b.
+        let a = hybrid_peer(&bus, "10.0.0.1", "10.0.0.2,10.0.0.3", "10.0.0.2=rsa_pub2.pem,10.0.0.3=rsa_pub3.pem", "rsa_priv.pem");
+        let c = hybrid_peer(&bus, "10.0.0.3", "10.0.0.1", "10.0.0.1=rsa_pub.pem", "rsa_priv3.pem");
+
+        // Queuing does not wait for the handshake with b and messages for c are not
+        // blocked by b.
+        let start = Instant::now();
+        a.layer.layers.queue(Message::new("10.0.0.2".to_string(), b"hello b".to_vec()), 17);
+        a.layer.layers.queue(Message::new("10.0.0.3".to_string(), b"hello c".to_vec()), 18);
+        a.layer.layers.queue(Message::new("10.0.0.3".to_string(), b"hi c".to_vec()), 19);
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // Messages for the same peer keep their order.
+        expect_text(&c, "10.0.0.1", "hello c");
+        expect_text(&c, "10.0.0.1", "hi c");
+        assert_eq!(expect_ack(&a), 18);
+        assert_eq!(expect_ack(&a), 19);
+    }
+
+    /// IP, identifier, sequence number and size of a sent echo request.
+    type Echo = (String, u16, u16, usize);
+
+    /// Records the identifiers, the sequence numbers and the sizes of the sent echo
+    /// requests.
+    struct EchoRecorder {
+        inner: Box<dyn Transport>,
+        echo: Arc<Mutex<Vec<Echo>>>,
+    }
+
+    impl Transport for EchoRecorder {
+        fn send(&self, ip: &str, buf: &[u8]) -> bool {
+            self.inner.send(ip, buf)
+        }
+
+        fn send_echo(&self, ip: &str, id: u16, seq: u16, buf: &[u8]) -> bool {
+            self.echo.lock().unwrap().push((ip.to_string(), id, seq, buf.len()));
+            self.inner.send(ip, buf)
+        }
+
+        fn reply(&self, ip: &str, token: u32, buf: &[u8]) -> bool {
+            self.inner.reply(ip, token, buf)
+        }
+
+        fn listen(&self, tx: Sender<Datagram>) -> Result<(), &'static str> {
+            self.inner.listen(tx)
+        }
+
+        fn max_payload(&self) -> usize {
+            self.inner.max_payload()
+        }
+    }
+
+    #[test]
+    fn test_echo_ident() {
+        let bus = LoopbackBus::new();
+        let _b = peer(&bus, "10.0.0.2", "10.0.0.1", KEY);
+        let session = || {
+            let (tx, rx) = channel();
+            let echo = Arc::new(Mutex::new(vec![]));
+            let t = EchoRecorder { inner: Box::new(bus.connect("10.0.0.1")), echo: echo.clone() };
+            let a = Peer {
+                layer: Layers::symmetric(KEY, &Peers::new(), Box::new(t), Console::new(tx), &IpAddresses::from_comma_list("10.0.0.2")).unwrap(),
+                _console: rx,
+            };
+            for id in 1..3 {
+                a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"hello".to_vec()), id, false);
+                assert_eq!(expect_ack(&a), id);
+            }
+            let v = echo.lock().unwrap().clone();
+            v
+        };
+
+        // All echo requests of a session have the same identifier and consecutive
+        // sequence numbers.
+        let v = session();
+        assert!(v.len() >= 3);
+        for (i, (ip, id, seq, _)) in v.iter().enumerate() {
+            assert_eq!(ip, "10.0.0.2");
+            assert_eq!(*id, v[0].1);
+            assert_eq!(*seq, v[0].2.wrapping_add(i as u16));
+        }
+
+        // Another session chooses other random values.
+        let w = session();
+        assert!((w[0].1, w[0].2) != (v[0].1, v[0].2));
+    }
+
+    #[test]
+    fn test_max_payload() {
+        let directory = Peers::parse(r#"
+            [bob]
+            ip = "10.0.0.2"
+            max_payload = 128
+        "#).unwrap();
+        for &ping in &[false, true] {
+            let bus = LoopbackBus::new();
+            let transport = |t: Box<dyn Transport>| -> Box<dyn Transport> {
+                match ping {
+                    true => Box::new(PingTransport::with_interval(t, Duration::from_millis(1))),
+                    false => t
+                }
+            };
+            let echo = Arc::new(Mutex::new(vec![]));
+            let t = EchoRecorder { inner: Box::new(bus.connect("10.0.0.1")), echo: echo.clone() };
+            let (tx, rx) = channel();
+            let a = Peer {
+                layer: Layers::symmetric(KEY, &directory, transport(Box::new(t)), Console::new(tx), &IpAddresses::from_comma_list("10.0.0.2")).unwrap(),
+                _console: rx,
+            };
+            let (tx, rx) = channel();
+            let b = Peer {
+                layer: Layers::symmetric(KEY, &Peers::new(), transport(Box::new(bus.connect("10.0.0.2"))), Console::new(tx), &IpAddresses::from_comma_list("10.0.0.1")).unwrap(),
+                _console: rx,
+            };
+            // The probe which determines the maximum size of packets is not limited.
+            thread::sleep(Duration::from_millis(100));
+            echo.lock().unwrap().clear();
+
+            let data = vec![b'x'; 1000];
+            a.layer.layers.send(Message::new("10.0.0.2".to_string(), data.clone()), 1, false);
+            expect_text(&b, "10.0.0.1", &String::from_utf8(data).unwrap());
+            assert_eq!(expect_ack(&a), 1);
+
+            // The ICMP payloads of the packets including all headers do not exceed the
+            // limit.
+            let sizes = echo.lock().unwrap().iter().map(|e| e.3).collect::<Vec<_>>();
+            assert!(sizes.iter().all(|n| *n <= 128), "{:?}", sizes);
+            assert!(sizes.iter().any(|n| *n > 100), "{:?}", sizes);
+        }
+    }
+
+    #[test]
+    fn test_drop_unknown_ip() {
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", KEY);
+        let b = peer(&bus, "10.0.0.2", "10.0.0.3", KEY);
+
+        a.layer.layers.send(Message::new("10.0.0.2".to_string(), b"hello".to_vec()), 7, false);
+        assert!(b.layer.rx.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+}
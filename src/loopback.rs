@@ -0,0 +1,118 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+
+use crate::transport::{Datagram, Transport};
+
+// Maximum size of a datagram on the bus.
+const MAX_PAYLOAD_SIZE: usize = 8192;
+
+/// A bus which connects simulated peers in the same process.
+///
+/// Each peer is identified by its IP. A datagram sent to an IP is delivered to the peer
+/// which has connected to the bus with this IP. Similar to the kernel of a real host which
+/// answers ICMP echo requests, the receiving side of the bus sends a copy of each datagram
+/// back to the sender as reply.
+///
+/// Peers can be put behind a simulated NAT. Datagrams sent to them are lost. Only a single
+/// reply to each datagram they have sent is let through.
+#[derive(Clone)]
+pub struct LoopbackBus {
+    peers: Arc<Mutex<HashMap<String, Sender<Datagram>>>>,
+    nat: Arc<Mutex<Nat>>,
+}
+
+#[derive(Default)]
+struct Nat {
+    /// IPs of the peers behind the NAT.
+    ips: HashSet<String>,
+    /// Datagrams sent by peers behind the NAT which have not been answered yet.
+    open: HashSet<(String, u32)>,
+    next_token: u32,
+}
+
+impl LoopbackBus {
+    pub fn new() -> LoopbackBus {
+        LoopbackBus {
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            nat: Arc::new(Mutex::new(Nat::default())),
+        }
+    }
+
+    /// Creates a transport for the peer with the given IP.
+    pub fn connect(&self, ip: &str) -> LoopbackTransport {
+        LoopbackTransport {
+            ip: ip.to_string(),
+            bus: self.clone(),
+        }
+    }
+
+    /// Puts the peer with the given IP behind a NAT.
+    pub fn hide(&self, ip: &str) {
+        self.nat.lock().expect("Lock failed.").ips.insert(ip.to_string());
+    }
+
+    /// Delivers a datagram to the peer with the IP `dst`. Returns false if there is no
+    /// such peer.
+    fn deliver(&self, dst: &str, d: Datagram) -> bool {
+        match self.peers.lock().expect("Lock failed.").get(dst) {
+            Some(tx) => tx.send(d).is_ok(),
+            None => false
+        }
+    }
+}
+
+/// Transport of a single peer connected to a `LoopbackBus`.
+pub struct LoopbackTransport {
+    ip: String,
+    bus: LoopbackBus,
+}
+
+impl Transport for LoopbackTransport {
+
+    fn send(&self, ip: &str, buf: &[u8]) -> bool {
+        if buf.len() > MAX_PAYLOAD_SIZE {
+            return false;
+        }
+        let token = {
+            let mut nat = self.bus.nat.lock().expect("Lock failed.");
+            if nat.ips.contains(ip) {
+                return true;
+            }
+            nat.next_token += 1;
+            let token = nat.next_token;
+            if nat.ips.contains(&self.ip) {
+                nat.open.insert((self.ip.clone(), token));
+            }
+            token
+        };
+        if self.bus.deliver(ip, Datagram::new(buf.to_vec(), self.ip.clone(), false, token)) {
+            self.bus.deliver(&self.ip, Datagram::new(buf.to_vec(), ip.to_string(), true, token));
+        }
+        // Like on a real network a datagram to an unknown peer is lost silently.
+        true
+    }
+
+    fn reply(&self, ip: &str, token: u32, buf: &[u8]) -> bool {
+        if buf.len() > MAX_PAYLOAD_SIZE {
+            return false;
+        }
+        {
+            let mut nat = self.bus.nat.lock().expect("Lock failed.");
+            if nat.ips.contains(ip) && !nat.open.remove(&(ip.to_string(), token)) {
+                return true;
+            }
+        }
+        self.bus.deliver(ip, Datagram::new(buf.to_vec(), self.ip.clone(), true, token));
+        true
+    }
+
+    fn listen(&self, tx: Sender<Datagram>) -> Result<(), &'static str> {
+        self.bus.peers.lock().expect("Lock failed.").insert(self.ip.clone(), tx);
+        Ok(())
+    }
+
+    fn max_payload(&self) -> usize {
+        MAX_PAYLOAD_SIZE
+    }
+}
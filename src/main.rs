@@ -13,40 +13,70 @@ mod delivery;
 mod binding;
 mod iptools;
 mod blowfish;
+mod aead;
+mod compress;
 mod packet;
 mod rsa;
 mod error;
 mod commands;
 mod upload;
-
+mod download;
+mod transfer;
+mod manifest;
+mod archive;
+mod transport;
+mod auth;
+mod stats;
+mod session;
+mod peers;
+mod window;
+mod rtt;
+mod icmp;
+mod relay;
+mod profile;
+#[cfg(test)]
+mod loopback;
+#[cfg(test)]
+mod simulator;
+
+use std::env;
 use std::thread;
 use std::sync::mpsc::{channel, Receiver};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use crate::message::{Message, IncomingMessage};
 use crate::layer::{Layers, Layer};
-use crate::tools::write_data;
 use crate::iptools::IpAddresses;
-use crate::arguments::{parse_arguments, Arguments};
+use crate::arguments::{parse_arguments, parse_relay_arguments, Arguments};
 use crate::console::ConsoleMessage;
 use crate::view::View;
 use crate::keyboad::{InputKeyboard, UserInput};
 use crate::model::{ItemType, Model, Item};
-use crate::model::Source;
+use crate::model::{Source, DeliveryStatus};
 use crate::console::Console;
 use crate::tools::read_file;
 use crate::outputs::WelcomeData;
+use crate::icmp::IcmpTransport;
+use crate::peers::Peers;
+use crate::transfer::Transfers;
+use crate::download::DownloadOptions;
+use crate::relay::{Mailbox, Relay};
+use crate::profile::{PingTransport, Profile};
+use crate::transport::Transport;
 
 type ArcModel = Arc<Mutex<Model>>;
 type ArcView = Arc<Mutex<View>>;
 
-/// Listens for incoming messages from the network.
-fn recv_loop(o: Console, rx: Receiver<IncomingMessage>) {
+/// Listens for incoming messages from the network. Messages of file transfers are
+/// processed by `transfers`.
+fn recv_loop(o: Console, rx: Receiver<IncomingMessage>, transfers: Transfers) {
 
     thread::spawn(move || {
-        loop { match rx.recv() {
-            Ok(msg) => {
+        loop { match rx.recv().map(|msg| transfers.handle(msg)) {
+            Ok(None) => { },
+            Ok(Some(msg)) => {
                 match msg {
                     IncomingMessage::New(msg) => {
                         o.new_msg(msg);
@@ -57,12 +87,16 @@ fn recv_loop(o: Console, rx: Receiver<IncomingMessage>) {
                     IncomingMessage::Error(_, s) => {
                         o.error(s);
                     }
-                    IncomingMessage::FileUpload(msg) => {
-                        upload::save_upload(o.clone(), msg)
-                    }
+                    IncomingMessage::FileUpload(_) => { }
                     IncomingMessage::AckProgress(id, done, total) => {
                         o.ack_msg_progress(id, done, total);
                     }
+                    IncomingMessage::Retry(id) => {
+                        o.delivery_status(id, DeliveryStatus::Retrying);
+                    }
+                    IncomingMessage::Failed(id) => {
+                        o.delivery_status(id, DeliveryStatus::Failed);
+                    }
                 }
             },
             Err(e) =>  {
@@ -100,6 +134,7 @@ fn create_data(dstip: String, txt: &String) -> (Message, u64) {
     (Message::new(dstip, txt.clone().into_bytes()), rand::random::<u64>())
 }
 
+/// Sends a message to all peers.
 fn send_message(txt: String, o: Console, l: &Layers, dstips: &IpAddresses) {
 
     let mut item = Item::new(format!("{}", txt), ItemType::MyMessage, model::Source::You);
@@ -109,29 +144,63 @@ fn send_message(txt: String, o: Console, l: &Layers, dstips: &IpAddresses) {
         .map(|dstip| create_data(dstip.clone(), &txt))
         .collect::<Vec<_>>();
 
-    for (_, id) in &v {
-        item = item.add_id(*id);
+    for (msg, id) in &v {
+        item = item.add_recipient(msg.get_ip(), *id);
     }
     o.msg_item(item);
 
     for (msg, id) in v {
-        l.send(msg, id, false);
+        l.queue(msg, id);
+    }
+}
+
+/// Sends a message only to the peer with the given IP.
+fn send_direct_message(txt: String, ip: String, o: Console, l: &Layers) {
+
+    let (msg, id) = create_data(ip.clone(), &txt);
+    o.msg_item(Item::new(txt, ItemType::MyMessage, model::Source::You).to(ip.clone()).add_recipient(ip, id));
+    l.queue(msg, id);
+}
+
+/// Sends a message to the target selected by the user or to all peers if no target is
+/// selected.
+fn send_to_target(txt: String, target: Option<String>, o: Console, l: &Layers, dstips: &IpAddresses) {
+    match target {
+        Some(ip) => send_direct_message(txt, ip, o, l),
+        None => send_message(txt, o, l, dstips)
     }
 }
 
-fn init_network_layer(args: &Arguments, console: Console, dstips: &IpAddresses) -> Layer {
+/// Creates the transport which sends ICMP echo requests via the given device.
+fn create_transport(device: &str, profile: Profile) -> Box<dyn Transport> {
+    let icmp = Box::new(IcmpTransport::new(device));
+    match profile {
+        Profile::Default => icmp,
+        Profile::Ping => Box::new(PingTransport::new(icmp))
+    }
+}
+
+fn init_network_layer(args: &Arguments, peers: &Peers, console: Console, accept_ip: &IpAddresses) -> Layer {
+    // Messages are transmitted via ICMP echo requests.
+    let transport = create_transport(&args.device, args.profile);
     let ret =
         if args.hybrid_mode {
             // use asymmetric encryption
-            Layers::asymmetric(&args.rcpt_pubkey_file, &args.privkey_file, &args.device, console, dstips)
+            Layers::asymmetric(&args.rcpt_pubkey_file, &args.privkey_file, peers, transport, console, accept_ip)
+        } else if args.legacy_mode {
+            // use symmetric encryption of older versions
+            Layers::legacy(&args.secret_key, peers, transport, console, accept_ip)
         } else {
             // use symmetric encryption
-            Layers::symmetric(&args.secret_key, &args.device, console, dstips)
+            Layers::symmetric(&args.secret_key, peers, transport, console, accept_ip)
         };
-    ret.expect("Initialization failed.")
+    let mut l = ret.expect("Initialization failed.");
+    l.layers.set_compression(args.compress);
+    l.layers.set_nat_mode(args.nat);
+    l
 }
 
-fn keyboard_loop(o: Console, l: Layers, dstips: IpAddresses, model: ArcModel, view: ArcView) {
+fn keyboard_loop(o: Console, l: Layers, transfers: Transfers, dstips: IpAddresses, peers: Peers, model: ArcModel, view: ArcView) {
     let mut input = InputKeyboard::new();
 
     loop {
@@ -143,8 +212,10 @@ fn keyboard_loop(o: Console, l: Layers, dstips: IpAddresses, model: ArcModel, vi
                 for c in buf {
                     let mut m = model.lock().unwrap();
                     if c == 13 {
-                        let s = m.apply_enter();
-                        send_message(s, o.clone(), &l, &dstips);
+                        let (s, target) = (m.apply_enter(), m.target.clone());
+                        // The console needs the model to show the message.
+                        drop(m);
+                        send_to_target(s, target, o.clone(), &l, &dstips);
                     } else {
                         v.push(c);
                         if String::from_utf8(v.clone()).is_ok() {
@@ -189,14 +260,21 @@ fn keyboard_loop(o: Console, l: Layers, dstips: IpAddresses, model: ArcModel, vi
                 model.lock().unwrap().toggle_scramble();
                 view.lock().unwrap().refresh();
             },
+            UserInput::Tab => {
+                model.lock().unwrap().next_target(&dstips.as_strings());
+                view.lock().unwrap().refresh();
+            },
             UserInput::Enter => {
-                let s = model.lock().unwrap().apply_enter();
+                let (s, target) = {
+                    let mut m = model.lock().unwrap();
+                    (m.apply_enter(), m.target.clone())
+                };
                 view.lock().unwrap().refresh();
                 if s.len() > 0 {
                     if s.starts_with("/") {
-                        commands::parse_command(s, o.clone(), &l, &dstips);
+                        commands::parse_command(s, o.clone(), &l, &transfers, &dstips, &peers, &model);
                     } else {
-                        send_message(s, o.clone(), &l, &dstips);
+                        send_to_target(s, target, o.clone(), &l, &dstips);
                     }
                 }
             }
@@ -225,6 +303,11 @@ fn create_console(model: ArcModel, view: ArcView) -> Console {
                     view.lock().unwrap().refresh();
                 }
             },
+            ConsoleMessage::Status(id, status) => {
+                if model.lock().unwrap().set_status(id, status) {
+                    view.lock().unwrap().refresh();
+                }
+            },
             // We need this as otherwise "out" is not dropped and the terminal state
             // is not restored.
             ConsoleMessage::Exit => {
@@ -263,18 +346,71 @@ fn scramble_trigger(o: Console) {
     });
 }
 
-fn welcome_data(args: &Arguments, network_layer: &Layer) -> WelcomeData {
-    let mut hashed_encryption_key = String::new();
+/// Asks the relays for messages which have been left for us at startup and regularly
+/// afterwards.
+fn fetch_trigger(l: Layers) {
+    thread::spawn(move || {
+        loop {
+            l.fetch();
+            thread::sleep(Duration::from_secs(relay::FETCH_INTERVAL));
+        }
+    });
+}
+
+fn load_peers(filename: &str) -> Peers {
+    match filename.len() {
+        0 => Peers::from_home(),
+        _ => Peers::load(filename)
+    }.expect("Cannot load peer directory")
+}
+
+/// Runs stealthy as relay which keeps messages for the registered peers. Status messages
+/// are written to stdout.
+fn relay_mode() {
+    let args = match parse_relay_arguments() {
+        Some(args) => args,
+        None => return
+    };
+    let peers = load_peers(&args.peers_file);
+    let ips = IpAddresses::from_peers(&args.dstip, &peers).expect("Cannot parse destination");
+    if ips.as_strings().is_empty() {
+        println!("No peers registered. Use -d or the peer directory.");
+        return;
+    }
+
+    let (tx, rx) = channel::<ConsoleMessage>();
+    thread::spawn(move || {
+        for m in rx {
+            if let ConsoleMessage::TextMessage(item) = m {
+                println!("{}", item.msg);
+            }
+        }
+    });
+    let c = Console::new(tx);
+
+    let transport = create_transport(&args.device, args.profile);
+    let l = Layers::symmetric(&args.secret_key, &peers, transport, c.clone(), &ips)
+        .expect("Initialization failed.");
+    let mailbox = Mailbox::open(&relay::mailbox_dir(), args.options).expect("Cannot open mailbox");
+    c.status(format!("Keeping messages for {}.", ips.as_strings().join(", ")));
+    Relay::new(mailbox, &ips, l.layers, c).run(l.rx);
+}
+
+fn welcome_data(args: &Arguments, network_layer: &Layer, dstips: &IpAddresses) -> WelcomeData {
+    let mut hashed_encryption_keys = vec![];
     let mut hashed_public_key = String::new();
 
     if args.hybrid_mode {
-        hashed_encryption_key = tools::sha1(&network_layer.layers.encryption_key());
+        hashed_encryption_keys = dstips.as_strings()
+            .into_iter()
+            .map(|ip| { let h = tools::sha1(&network_layer.layers.encryption_key(&ip)); (ip, h) })
+            .collect();
         hashed_public_key = tools::sha1(&rsatools::key_as_der(&read_file(&args.pubkey_file).unwrap()));
     }
 
     WelcomeData {
         hybrid_mode: args.hybrid_mode,
-        hashed_hybrid_encryption_key: hashed_encryption_key,
+        hashed_hybrid_encryption_keys: hashed_encryption_keys,
         hashed_hybrid_public_key: hashed_public_key
     }
 }
@@ -282,31 +418,61 @@ fn welcome_data(args: &Arguments, network_layer: &Layer) -> WelcomeData {
 fn main() {
     init_global_state();
 
+    if env::args().nth(1).is_some_and(|a| a == "relay") {
+        relay_mode();
+        return;
+    }
+
     // Parse command line arguments.
 	let args = parse_arguments().expect("Cannot parse arguments");
 
-    let dstips = IpAddresses::from_comma_list(&args.dstip);
+    // Load the peer directory.
+    let peers = load_peers(&args.peers_file);
+
+    let dstips = match args.dstip.is_empty() && peers.ips().is_empty() {
+        true => IpAddresses::from_comma_list("127.0.0.1"),
+        false => IpAddresses::from_peers(&args.dstip, &peers).expect("Cannot parse destination")
+    };
 
     // The model stores all information which is required to show the screen.
     let model = Arc::new(Mutex::new(Model::new()));
 
-    let view = Arc::new(Mutex::new(View::new(model.clone())));
+    let view = Arc::new(Mutex::new(View::new(model.clone(), peers.clone())));
 
     let c = create_console(model.clone(), view.clone());
 
-    let network_layer = init_network_layer(&args, c.clone(), &dstips);
+    // Messages of peers with a relay are received from the relay.
+    let relays = dstips.as_strings().iter().filter_map(|ip| peers.relay(ip)).collect::<Vec<_>>();
+    if args.hybrid_mode && !relays.is_empty() {
+        println!("Relays are not supported with hybrid encryption.");
+        return;
+    }
+    let accept_ip = dstips.with(&relays).expect("Cannot parse relay");
+
+    let network_layer = init_network_layer(&args, &peers, c.clone(), &accept_ip);
 
     // Show welchome message.
-    outputs::welcome(&args, c.clone(), welcome_data(&args, &network_layer), &dstips);
+    outputs::welcome(&args, c.clone(), welcome_data(&args, &network_layer, &dstips), &dstips);
 
     scramble_trigger(c.clone());
 
+    if network_layer.layers.has_relays() {
+        fetch_trigger(network_layer.layers.clone());
+    }
+
+    let options = DownloadOptions {
+        dir: PathBuf::from(&args.download_dir),
+        max_size: args.max_size,
+        ask: args.ask,
+    };
+    let transfers = Transfers::new(options, &network_layer.layers, c.clone());
+
     // This is the loop which handles messages received from the network.
-    recv_loop(c.clone(), network_layer.rx);
+    recv_loop(c.clone(), network_layer.rx, transfers.clone());
 
     // Waits for data from the keyboard.
     // If data is received the model and the view will be updated.
-    keyboard_loop(c.clone(), network_layer.layers, dstips, model, view);
+    keyboard_loop(c.clone(), network_layer.layers, transfers, dstips, peers, model, view);
 
     // IMPORTANT! If the are threads which are using a clone of the view, the view isn't destroyed
     // properly and the terminal state is not restored.
@@ -0,0 +1,299 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::cryp::from_hex;
+use crate::delivery::push_value;
+use crate::tools::{read_file, to_hex};
+use crate::transfer::{chunk_count, FileInfo};
+
+/// Persisted state of a file transfer with a peer.
+///
+/// The manifest consists of two files in the directory of the transfers. The file
+/// `<hash>-<ip>.toml` describes the file and the file `<hash>-<ip>.seq` is a log to which
+/// the sequence number (u32) of each part is appended once it has been transferred.
+pub struct Manifest {
+    pub info: FileInfo,
+    pub peer: String,
+    /// The file which is sent or the file to which received parts are written.
+    pub path: String,
+    /// Sorted and disjoint ranges (inclusive) of the sequence numbers of the parts which
+    /// have been transferred.
+    received: Vec<(u32, u32)>,
+    count: usize,
+    /// Path of the manifest files without extension.
+    base: String,
+    log: File,
+}
+
+/// Returns the name of the manifest files without extension.
+pub fn name(hash: &[u8], peer: &str) -> String {
+    format!("{}-{}", to_hex(hash), peer)
+}
+
+impl Manifest {
+
+    /// Loads the manifest of the transfer of the given file with the peer or creates a new
+    /// one if the transfer has not been started before.
+    pub fn open(dir: &Path, info: &FileInfo, peer: &str, path: &str) -> Result<Manifest, &'static str> {
+        match Manifest::load(dir, &info.hash, peer)? {
+            Some(m) => Ok(m),
+            None => Manifest::create(dir, info, peer, path)
+        }
+    }
+
+    /// Loads the manifest of the transfer of the file with the given hash with the peer.
+    /// Returns None if there is no such manifest.
+    pub fn load(dir: &Path, hash: &[u8], peer: &str) -> Result<Option<Manifest>, &'static str> {
+        let base = dir.join(name(hash, peer)).to_str().ok_or("Invalid path of manifest.")?.to_string();
+        match Path::new(&format!("{}.toml", base)).exists() {
+            true => Manifest::read(base).map(Some),
+            false => Ok(None)
+        }
+    }
+
+    /// Returns all manifests in the given directory which can be read.
+    pub fn list(dir: &Path) -> Vec<Manifest> {
+        let mut r = vec![];
+        if let Ok(entries) = fs::read_dir(dir) {
+            for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                if path.extension().is_some_and(|x| x == "toml") {
+                    if let Some(base) = path.with_extension("").to_str() {
+                        if let Ok(m) = Manifest::read(base.to_string()) {
+                            r.push(m);
+                        }
+                    }
+                }
+            }
+        }
+        r
+    }
+
+    fn create(dir: &Path, info: &FileInfo, peer: &str, path: &str) -> Result<Manifest, &'static str> {
+        fs::create_dir_all(dir).map_err(|_| "Could not create directory for file transfers.")?;
+        let base = dir.join(name(&info.hash, peer)).to_str().ok_or("Invalid path of manifest.")?.to_string();
+
+        let mut t = Table::new();
+        t.insert("name".to_string(), Value::String(info.name.clone()));
+        t.insert("hash".to_string(), Value::String(info.id()));
+        t.insert("size".to_string(), Value::Integer(info.size as i64));
+        t.insert("mtime".to_string(), Value::Integer(info.mtime as i64));
+        t.insert("mode".to_string(), Value::Integer(info.mode as i64));
+        t.insert("peer".to_string(), Value::String(peer.to_string()));
+        t.insert("path".to_string(), Value::String(path.to_string()));
+        let s = toml::to_string(&Value::Table(t)).map_err(|_| "Could not serialize manifest.")?;
+        fs::write(format!("{}.toml", base), s).map_err(|_| "Could not write manifest.")?;
+
+        let log = File::create(format!("{}.seq", base)).map_err(|_| "Could not write manifest.")?;
+        Ok(Manifest {
+            info: info.clone(),
+            peer: peer.to_string(),
+            path: path.to_string(),
+            received: vec![],
+            count: 0,
+            base,
+            log,
+        })
+    }
+
+    fn read(base: String) -> Result<Manifest, &'static str> {
+        let t = match read_file(&format!("{}.toml", base))?.parse::<Value>() {
+            Ok(Value::Table(t)) => t,
+            _ => return Err("Could not parse manifest.")
+        };
+        let str_value = |key: &str| t.get(key).and_then(|x| x.as_str()).map(|x| x.to_string()).ok_or("Invalid manifest.");
+        let int_value = |key: &str| t.get(key).and_then(|x| x.as_integer()).filter(|n| *n >= 0).ok_or("Invalid manifest.");
+
+        let info = FileInfo {
+            hash: from_hex(str_value("hash")?)?,
+            name: str_value("name")?,
+            size: int_value("size")? as u64,
+            mtime: int_value("mtime")? as u64,
+            mode: int_value("mode")? as u32,
+        };
+        chunk_count(info.size).ok_or("Invalid manifest.")?;
+
+        let mut v = vec![];
+        let mut log = OpenOptions::new().read(true).append(true).open(format!("{}.seq", base)).map_err(|_| "Could not open manifest.")?;
+        log.read_to_end(&mut v).map_err(|_| "Could not read manifest.")?;
+        // An incomplete entry at the end is removed.
+        log.set_len((v.len() - v.len() % 4) as u64).map_err(|_| "Could not write manifest.")?;
+
+        let mut m = Manifest {
+            received: vec![],
+            info,
+            peer: str_value("peer")?,
+            path: str_value("path")?,
+            count: 0,
+            log,
+            base,
+        };
+        for x in v.chunks_exact(4) {
+            let seq = x.iter().rev().fold(0, |r, b| (r << 8) + *b as u32);
+            m.mark(seq);
+        }
+        Ok(m)
+    }
+
+    /// Records that the part with the given sequence number has been transferred.
+    pub fn add(&mut self, seq: u32) -> Result<(), &'static str> {
+        if self.contains(seq) || seq == 0 || seq > self.info.chunks() {
+            return Ok(());
+        }
+        let mut v = vec![];
+        push_value(&mut v, seq as u64, 4);
+        self.log.write_all(&v).map_err(|_| "Could not write manifest.")?;
+        self.mark(seq);
+        Ok(())
+    }
+
+    fn mark(&mut self, seq: u32) {
+        if seq == 0 || seq > self.info.chunks() || self.contains(seq) {
+            return;
+        }
+        // The range before i ends before seq and the range at i starts after seq.
+        let i = self.received.partition_point(|r| r.1 < seq);
+        let prev = i > 0 && self.received[i - 1].1 + 1 == seq;
+        let next = i < self.received.len() && self.received[i].0 - 1 == seq;
+        match (prev, next) {
+            (true, true) => {
+                self.received[i - 1].1 = self.received[i].1;
+                self.received.remove(i);
+            },
+            (true, false) => self.received[i - 1].1 = seq,
+            (false, true) => self.received[i].0 = seq,
+            (false, false) => self.received.insert(i, (seq, seq))
+        }
+        self.count += 1;
+    }
+
+    pub fn contains(&self, seq: u32) -> bool {
+        let i = self.received.partition_point(|r| r.1 < seq);
+        self.received.get(i).is_some_and(|r| r.0 <= seq)
+    }
+
+    /// Returns the number of parts which have been transferred.
+    pub fn received(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count == self.info.chunks() as usize
+    }
+
+    /// Returns the ranges of sequence numbers (inclusive) of the parts which have not been
+    /// transferred.
+    pub fn missing(&self) -> Vec<(u32, u32)> {
+        let mut r = vec![];
+        let mut next = 1u64;
+        for &(first, last) in &self.received {
+            if first as u64 > next {
+                r.push((next as u32, first - 1));
+            }
+            next = last as u64 + 1;
+        }
+        if next <= self.info.chunks() as u64 {
+            r.push((next as u32, self.info.chunks()));
+        }
+        r
+    }
+
+    /// Deletes the manifest.
+    pub fn remove(self) {
+        let _ = fs::remove_file(format!("{}.toml", self.base));
+        let _ = fs::remove_file(format!("{}.seq", self.base));
+    }
+}
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use super::Manifest;
+    use crate::tools::random_str;
+    use crate::transfer::{FileInfo, CHUNK_SIZE};
+
+    #[test]
+    fn test_persist() {
+        let dir = std::env::temp_dir().join(format!("stealthy_test_{}", random_str(10)));
+        let info = FileInfo {
+            hash: vec![1; 32],
+            name: "a.txt".to_string(),
+            size: 5 * CHUNK_SIZE as u64,
+            mtime: 1600000000,
+            mode: 0o100644,
+        };
+
+        let mut m = Manifest::open(&dir, &info, "1.2.3.4", "/tmp/a.txt").unwrap();
+        assert_eq!(m.received(), 0);
+        assert_eq!(m.missing(), vec![(1, 5)]);
+        m.add(2).unwrap();
+        m.add(3).unwrap();
+        m.add(3).unwrap();
+        m.add(6).unwrap();
+        assert_eq!(m.missing(), vec![(1, 1), (4, 5)]);
+        drop(m);
+
+        // A partially written entry is ignored.
+        let seq = dir.join(format!("{}-1.2.3.4.seq", info.id()));
+        OpenOptions::new().append(true).open(&seq).unwrap().write_all(&[5, 0]).unwrap();
+
+        let mut m = Manifest::open(&dir, &info, "1.2.3.4", "/tmp/b.txt").unwrap();
+        assert_eq!(m.info, info);
+        assert_eq!(m.path, "/tmp/a.txt");
+        assert_eq!(m.received(), 2);
+        assert!(m.contains(2) && !m.contains(1));
+        m.add(1).unwrap();
+        assert_eq!(Manifest::list(&dir).len(), 1);
+        assert!(Manifest::load(&dir, &info.hash, "5.6.7.8").unwrap().is_none());
+
+        let m = Manifest::load(&dir, &info.hash, "1.2.3.4").unwrap().unwrap();
+        assert_eq!(m.missing(), vec![(4, 5)]);
+        m.remove();
+        assert!(Manifest::load(&dir, &info.hash, "1.2.3.4").unwrap().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_ranges() {
+        let dir = std::env::temp_dir().join(format!("stealthy_test_{}", random_str(10)));
+        let max = u32::MAX;
+        let info = FileInfo {
+            hash: vec![2; 32],
+            name: "big".to_string(),
+            size: max as u64 * CHUNK_SIZE as u64,
+            mtime: 0,
+            mode: 0o100644,
+        };
+
+        let mut m = Manifest::open(&dir, &info, "1.2.3.4", "/tmp/big").unwrap();
+        assert_eq!(m.missing(), vec![(1, max)]);
+        for seq in &[5, 3, max, 4, 1, 7, max - 1] {
+            m.add(*seq).unwrap();
+        }
+        assert_eq!(m.received(), 7);
+        assert!(m.contains(1) && m.contains(5) && m.contains(max));
+        assert!(!m.contains(0) && !m.contains(2) && !m.contains(6) && !m.contains(8));
+        assert_eq!(m.missing(), vec![(2, 2), (6, 6), (8, max - 2)]);
+
+        m.add(2).unwrap();
+        m.add(6).unwrap();
+        assert_eq!(m.missing(), vec![(8, max - 2)]);
+        assert!(!m.is_complete());
+
+        let m = Manifest::load(&dir, &info.hash, "1.2.3.4").unwrap().unwrap();
+        assert_eq!(m.received(), 9);
+        assert_eq!(m.missing(), vec![(8, max - 2)]);
+        m.remove();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -2,6 +2,7 @@
 //use crypto::digest::Digest;
 
 use crate::error::ErrorType;
+use crate::transfer::Transfer;
 
 unsafe impl Sync for IncomingMessage { } // TODO XXX is it thread safe?
 // http://doc.rust-lang.org/std/marker/trait.Sync.html
@@ -10,6 +11,10 @@ pub enum IncomingMessage {
     New(Message),
     Ack(u64),
     AckProgress(u64, usize, usize),
+    /// A part of the message with the given id has been sent again.
+    Retry(u64),
+    /// The message with the given id could not be delivered and has been given up.
+    Failed(u64),
     Error(ErrorType, String),
     FileUpload(Message),
 }
@@ -38,11 +43,9 @@ pub enum MessageType {
 }
 
 impl Message {
-    pub fn file_upload(ip: String, fname: String, data: &Vec<u8>) -> Message {
-        let mut buffer = Vec::from(fname.as_bytes());
-        buffer.push(0);
-        buffer.extend(data.iter());
-        Message::create(ip, buffer, MessageType::FileUpload)
+    /// Creates a message for a file transfer.
+    pub fn file_upload(ip: String, t: &Transfer) -> Message {
+        Message::create(ip, t.serialize(), MessageType::FileUpload)
     }
 
     pub fn new(ip: String, buf: Vec<u8>) -> Message {
@@ -65,27 +68,16 @@ impl Message {
 
     pub fn get_type(&self) -> MessageType { self.typ.clone() }
 
-    pub fn get_filename(&self) -> Option<String> {
-        let pos = self.get_payload().iter().position(|x| *x == 0 as u8);
-        if pos.is_none() {
-            // invalid format; TODO error
-            return None;
+    /// Returns the content of a message of a file transfer or None if the message has an
+    /// invalid format. The name of an offered file is sanitized.
+    pub fn get_transfer(&self) -> Option<Transfer> {
+        match Transfer::deserialize(&self.buf)? {
+            Transfer::Offer(mut info) => {
+                info.name = sanitize_filename(info.name);
+                Some(Transfer::Offer(info))
+            },
+            t => Some(t)
         }
-        let payload = self.get_payload();
-        let (fname, _) = payload.split_at(pos.unwrap());
-        let filename = String::from_utf8(fname.to_vec()).expect("XXXXXXXX"); // TODO error
-        Some(sanitize_filename(filename))
-    }
-
-    pub fn get_filedata(&self) -> Option<Vec<u8>> {
-        let pos = self.get_payload().iter().position(|x| *x == 0 as u8);
-        if pos.is_none() {
-            // invalid format; TODO error
-            return None;
-        }
-        let payload = self.get_payload();
-        let (_, data) = payload.split_at(pos.unwrap() + 1);
-        Some(data.to_vec())
     }
 
     /*
@@ -111,6 +103,6 @@ fn replace_char(c: char) -> char {
     }
 }
 
-fn sanitize_filename(s: String) -> String {
+pub fn sanitize_filename(s: String) -> String {
     s.chars().map(|c| replace_char(c)).collect()
 }
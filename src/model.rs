@@ -1,4 +1,5 @@
 use time::Tm;
+use std::fmt;
 use std::time::SystemTime;
 
 static MAX_BUF_LEN: usize = 500;
@@ -13,6 +14,8 @@ pub struct Model {
     scrambled: bool,
     pub scramble_timeout: u32,
     last_ack_progress_view_update: SystemTime,
+    /// IP of the peer to which messages are sent or None if messages are sent to all peers.
+    pub target: Option<String>,
 }
 
 impl Model {
@@ -24,9 +27,19 @@ impl Model {
             scrambled: false,
             scramble_timeout: 20,
             last_ack_progress_view_update: SystemTime::now(),
+            target: None,
         }
     }
 
+    /// Selects the next peer of the given list as target for messages. After the last peer
+    /// messages are sent to all peers again.
+    pub fn next_target(&mut self, ips: &[String]) {
+        self.target = match &self.target {
+            None => ips.first().cloned(),
+            Some(t) => ips.iter().skip_while(|ip| *ip != t).nth(1).cloned()
+        };
+    }
+
     pub fn toggle_scramble(&mut self) {
         self.scrambled = !self.scrambled;
     }
@@ -79,11 +92,27 @@ impl Model {
             let exists = item.id.iter().find(|i| **i == id).is_some();
             if exists {
                 item.acks_received += 1;
+                item.set_status(id, DeliveryStatus::Acked);
                 break;
             }
         }
     }
 
+    /// Updates the delivery status of the message with the given id. Returns true if the
+    /// status has changed.
+    pub fn set_status(&mut self, id: u64, status: DeliveryStatus) -> bool {
+        match self.buf.iter_mut().rev().find(|i| i.id.contains(&id)) {
+            Some(item) => item.set_status(id, status),
+            None => false
+        }
+    }
+
+    /// Returns the n-th last message which has been sent by the user. The last message
+    /// is n = 1.
+    pub fn sent_item(&self, n: usize) -> Option<&Item> {
+        self.buf.iter().rev().filter(|i| !i.receipts.is_empty()).nth(n - 1)
+    }
+
     pub fn ack_progress(&mut self, id: u64, done: usize, total: usize) -> bool {
         let mut exists = false;
         for item in self.buf.iter_mut().rev() {
@@ -115,6 +144,40 @@ impl Model {
     }
 }
 
+/// Delivery status of a message for a single recipient.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeliveryStatus {
+    Sent,
+    /// The message has been sent again because an ack is missing.
+    Retrying,
+    Acked,
+    Failed,
+    /// The upload has been paused by the user.
+    Paused,
+}
+
+impl fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Retrying => "retrying",
+            DeliveryStatus::Acked => "acked",
+            DeliveryStatus::Failed => "failed",
+            DeliveryStatus::Paused => "paused",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Delivery status of a message for one of its recipients.
+#[derive(Clone)]
+pub struct Receipt {
+    pub ip: String,
+    /// Id of the message sent to the recipient.
+    pub id: u64,
+    pub status: DeliveryStatus,
+}
+
 #[derive(Clone)]
 pub enum Source {
     Ip(String),
@@ -132,6 +195,10 @@ pub struct Item {
     pub tim: Tm,
     pub total_acks: usize,
     pub pending_acks: usize,
+    /// IPs of the recipients if the message was not sent to all peers.
+    pub recipients: Vec<String>,
+    /// Delivery status for each recipient.
+    pub receipts: Vec<Receipt>,
     from: Source,
 }
 
@@ -150,7 +217,9 @@ impl Item {
             tim: time::now(),
             from,
             total_acks: 0,
-            pending_acks: 0
+            pending_acks: 0,
+            recipients: vec![],
+            receipts: vec![],
         }
     }
 
@@ -171,9 +240,37 @@ impl Item {
         self
     }
 
-    /// Sets the id of the item.
-    pub fn add_id(mut self, id: u64) -> Item {
+    /// Adds the id of the message which is sent to the given recipient.
+    pub fn add_recipient(mut self, ip: String, id: u64) -> Item {
         self.id.push(id);
+        self.receipts.push(Receipt {
+            ip,
+            id,
+            status: DeliveryStatus::Sent,
+        });
+        self
+    }
+
+    /// Returns true if the message could not be delivered to one of its recipients.
+    pub fn failed(&self) -> bool {
+        self.receipts.iter().any(|r| r.status == DeliveryStatus::Failed)
+    }
+
+    /// Updates the delivery status for the recipient of the message with the given id. An
+    /// acked message keeps its status. Returns true if the status has changed.
+    fn set_status(&mut self, id: u64, status: DeliveryStatus) -> bool {
+        match self.receipts.iter_mut().find(|r| r.id == id) {
+            Some(r) if r.status != status && r.status != DeliveryStatus::Acked => {
+                r.status = status;
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// Adds a recipient of a message which is not sent to all peers.
+    pub fn to(mut self, ip: String) -> Item {
+        self.recipients.push(ip);
         self
     }
 
@@ -193,3 +290,54 @@ pub enum ItemType {
     MyMessage,
     UploadMessage,
 }
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::{DeliveryStatus, Item, ItemType, Model, Source};
+
+    #[test]
+    fn test_next_target() {
+        let ips = vec!["1.2.3.4".to_string(), "5.6.7.8".to_string()];
+        let mut m = Model::new();
+        assert_eq!(m.target, None);
+
+        m.next_target(&ips);
+        assert_eq!(m.target, Some("1.2.3.4".to_string()));
+        m.next_target(&ips);
+        assert_eq!(m.target, Some("5.6.7.8".to_string()));
+        m.next_target(&ips);
+        assert_eq!(m.target, None);
+    }
+
+    #[test]
+    fn test_delivery_status() {
+        let mut m = Model::new();
+        m.add_message(Item::new("first".to_string(), ItemType::MyMessage, Source::You)
+            .add_recipient("1.2.3.4".to_string(), 1)
+            .add_recipient("5.6.7.8".to_string(), 2));
+        m.add_message(Item::new("hello".to_string(), ItemType::Received, Source::Ip("1.2.3.4".to_string())));
+        m.add_message(Item::new("second".to_string(), ItemType::MyMessage, Source::You)
+            .add_recipient("1.2.3.4".to_string(), 3));
+
+        assert!(m.set_status(1, DeliveryStatus::Retrying));
+        assert!(!m.set_status(1, DeliveryStatus::Retrying));
+        assert!(!m.set_status(4, DeliveryStatus::Retrying));
+        m.ack(1);
+        assert!(!m.set_status(1, DeliveryStatus::Failed));
+        assert!(m.set_status(2, DeliveryStatus::Failed));
+
+        assert_eq!(m.sent_item(1).unwrap().msg, "second");
+        let i = m.sent_item(2).unwrap();
+        assert_eq!(i.msg, "first");
+        assert_eq!(i.receipts[0].status, DeliveryStatus::Acked);
+        assert_eq!(i.receipts[1].status, DeliveryStatus::Failed);
+        assert!(i.failed());
+        assert!(!m.sent_item(1).unwrap().failed());
+        assert!(m.sent_item(3).is_none());
+    }
+}
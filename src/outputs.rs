@@ -31,8 +31,17 @@ pub fn help_message(o: Console) {
         " ",
         "/help                 - this help message",
         "/uptime, /up          - uptime",
+        "/stats                - dropped packets and round-trip time of each peer",
+        "/msg <peer> <text>    - send a message only to one peer (IP or name)",
+        "/status [n]           - delivery status of the n-th last message (default: 1)",
         "/cat <filename>       - send content of an UTF-8 encoded text file",
-        "/upload <filename>    - send binary file",
+        "/upload <path>        - send a file, a directory or all files matching a pattern",
+        "/accept <n>           - receive the n-th offered file (see option -a)",
+        "/reject <n>           - reject the n-th offered file",
+        "/transfers            - list active uploads with their numbers and progress",
+        "/pause <n>            - pause the upload with the number n",
+        "/resume <n>           - continue the paused upload with the number n",
+        "/cancel <n>           - cancel the upload with the number n",
         "/set scramble <value> - set timeout in seconds when to scramble content (default: 20)",
         " ",
         "Keys:",
@@ -44,6 +53,7 @@ pub fn help_message(o: Console) {
         "end          - scroll to last message in buffer",
         "ctrl+r       - switch to plain messages and back to normal view",
         "ctrl+s       - toggle scrambling",
+        "tab          - select the peer to which messages are sent",
         "esc | ctrl+d - quit",
         " "
     ], ItemType::Info, Source::System);
@@ -51,7 +61,8 @@ pub fn help_message(o: Console) {
 
 pub struct WelcomeData {
     pub hybrid_mode: bool,
-    pub hashed_hybrid_encryption_key: String,
+    /// IP and hash of the public key of each peer.
+    pub hashed_hybrid_encryption_keys: Vec<(String, String)>,
     pub hashed_hybrid_public_key: String,
 }
 
@@ -86,8 +97,15 @@ pub fn welcome(args: &Arguments, o: Console, data: WelcomeData, dstips: &IpAddre
     );
 
     if data.hybrid_mode {
-        o.raw(format!("Hash of encryption key : {}", data.hashed_hybrid_encryption_key), ItemType::Introduction, Source::System);
-        o.raw(format!("Hash of your public key: {}", data.hashed_hybrid_public_key), ItemType::Introduction, Source::System);
+        for (ip, h) in &data.hashed_hybrid_encryption_keys {
+            o.raw(format!("{:<30}: {}", format!("Hash of key of {}", ip), h), ItemType::Introduction, Source::System);
+        }
+        o.raw(format!("{:<30}: {}", "Hash of your public key", data.hashed_hybrid_public_key), ItemType::Introduction, Source::System);
+    } else if args.legacy_mode {
+        o.raw("Warning: legacy mode, messages are not authenticated.".to_string(), ItemType::Error, Source::System);
+        if args.compress {
+            o.raw("Warning: messages are not compressed in legacy mode.".to_string(), ItemType::Error, Source::System);
+        }
     }
     o.raw(format!(" "), ItemType::Introduction, Source::System);
     o.raw(format!("Happy chatting..."), ItemType::Introduction, Source::System);
@@ -1,12 +1,31 @@
 //extern crate rand;
 //extern crate time;
 
+use crypto::chacha20::ChaCha20;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+
+use crate::aead;
+use crate::auth;
+
 pub type IdType = u64;
 
+/// Version of packets without authentication tag.
+const VERSION_PLAIN: u8 = 1;
+/// Version of packets which are followed by an authentication tag.
+const VERSION_AUTH: u8 = 2;
+
+/// Number of bytes which a serialized packet with authentication tag adds to the payload.
+pub const OVERHEAD: usize = 10 + auth::TAG_LEN;
+
 pub enum PacketType {
     NewMessage = 16,
     AckMessage = 17,
 	FileUpload = 18,
+	/// Allows the receiver to send a packet in the echo reply (see `binding::NatMode`).
+	Poll = 19,
+	/// Determines the maximum size of a packet which reaches the receiver and comes back
+	/// in the echo reply.
+	Probe = 20,
 }
 
 pub struct Packet {
@@ -33,6 +52,36 @@ impl Packet {
 		self.typ == (PacketType::FileUpload as u8)
 	}
 
+	pub fn is_poll(&self) -> bool {
+		self.typ == (PacketType::Poll as u8)
+	}
+
+	/// Creates a poll without payload.
+	pub fn poll(ip: String) -> Packet {
+		Packet {
+			data: vec![],
+			id: rand::random::<u64>(),
+			created: time::PreciseTime::now(),
+			ip,
+			typ: PacketType::Poll as u8,
+		}
+	}
+
+	pub fn is_probe(&self) -> bool {
+		self.typ == (PacketType::Probe as u8)
+	}
+
+	/// Creates a probe with `n` random bytes of payload.
+	pub fn probe(ip: String, id: u64, n: usize) -> Packet {
+		Packet {
+			data: (0..n).map(|_| rand::random::<u8>()).collect(),
+			id,
+			created: time::PreciseTime::now(),
+			ip,
+			typ: PacketType::Probe as u8,
+		}
+	}
+
 	pub fn file_upload(data: Vec<u8>, ip: String, r: u64) -> Packet {
 		Packet {
 			data: data,
@@ -64,25 +113,66 @@ impl Packet {
 		}
 	}
 
-	pub fn serialize(&self) -> Vec<u8> {
+	/// Serializes the packet. If a key is given an authentication tag over the whole
+	/// packet is appended.
+	///
+	/// u64     : id
+	/// u8      : version { 1 = without tag, 2 = with tag } (masked)
+	/// u8      : type (masked)
+	/// Vec<u8> : payload
+	/// [u8; 16]: authentication tag (only for version 2)
+	///
+	/// All bytes between the id and the tag are masked with a keystream derived from the
+	/// key and the id, so no byte of a packet has a fixed value.
+	pub fn serialize(&self, key: Option<&[u8]>) -> Vec<u8> {
 
 		// if you change someting check delivery::send_msg
 
-		// version + type
-		let mut v: Vec<u8> = vec![1, self.typ];         // 2B
+		let ver = match key {
+			Some(_) => VERSION_AUTH,
+			None => VERSION_PLAIN
+		};
+
 		// id
+		let mut v: Vec<u8> = vec![];
 		let mut t = self.id;
 		for _ in 0..8 {                                // 8B
 			v.push(t as u8);
-			t = t >> 8;
+			t >>= 8;
 		}
+		// version + type                              // 2B
+		v.push(ver);
+		v.push(self.typ);
 		// data / payload                              // data
-		for k in self.data.clone() {
-			v.push(k);
+		v.extend_from_slice(&self.data);
+		let (id, rest) = v.split_at_mut(8);
+		mask(key, id, rest);
+		// authentication tag                          // 16B
+		if let Some(key) = key {
+			let t = auth::tag(key, &v);
+			v.extend(t);
 		}
 		v
 	}
 
+	/// Checks that the serialized packet is authenticated with the given key. If no key
+	/// is given the packet does not need to be authenticated.
+	pub fn verify(buf: &[u8], key: Option<&[u8]>) -> bool {
+
+		match key {
+			Some(key) => {
+				if buf.len() < 10 + auth::TAG_LEN {
+					return false;
+				}
+				let (data, tag) = buf.split_at(buf.len() - auth::TAG_LEN);
+				let mut ver = [data[8]];
+				mask(Some(key), &data[..8], &mut ver);
+				auth::verify(key, data, tag) && ver[0] == VERSION_AUTH
+			},
+			None => true
+		}
+	}
+
     pub fn create_ack(p: Packet) -> Packet {
 
         Packet {
@@ -97,38 +187,131 @@ impl Packet {
     fn valid_type(typ: u8) -> bool {
 		typ == (PacketType::NewMessage as u8) ||
 			typ == (PacketType::AckMessage as u8) ||
-			typ == (PacketType::FileUpload as u8)
+			typ == (PacketType::FileUpload as u8) ||
+			typ == (PacketType::Poll as u8) ||
+			typ == (PacketType::Probe as u8)
     }
 
-	pub fn deserialize(buf: *const u8, len: u32, ip: String) -> Option<Packet> {
+	/// Deserializes a packet which has been serialized with the given key. The
+	/// authentication tag is not verified. Use `verify` to check that the packet is
+	/// authentic.
+	pub fn deserialize(buf: &[u8], ip: String, key: Option<&[u8]>) -> Option<Packet> {
 
-		if len < 10 {
+		if buf.len() < 10 {
 			return None;
 		}
 
-		let mut raw = Packet{ 
-			id: 0, 
-			data: vec![], 
-			created: time::PreciseTime::now(),
-			ip: ip,
-            typ: 0
+		let mut v = buf[8..].to_vec();
+		mask(key, &buf[..8], &mut v);
+		let ver : u8 = v[0];
+		let typ : u8 = v[1];
+
+		let end = match (ver, key) {
+			(VERSION_PLAIN, None) => buf.len(),
+			(VERSION_AUTH, Some(_)) if buf.len() >= 10 + auth::TAG_LEN => buf.len() - auth::TAG_LEN,
+			_ => return None
 		};
 
-		unsafe {
-			let ver : u8 = *buf.offset(0);
-			let typ : u8 = *buf.offset(1);
-
-			if ver != 1 || !Packet::valid_type(typ) {
-				return None;
-			}
-			for i in 0..8 {
-				raw.id = (raw.id << 8) + (*buf.offset(2 + 7 - i) as u64);
-			}
-			for i in 10..len {
-				raw.data.push(*buf.offset(i as isize));
-			}
-            raw.typ = typ;
-			Some(raw)
+		if !Packet::valid_type(typ) {
+			return None;
+		}
+
+		let mut id: u64 = 0;
+		for i in 0..8 {
+			id = (id << 8) + (buf[7 - i] as u64);
 		}
+
+		Some(Packet {
+			id,
+			data: v[2..end - 8].to_vec(),
+			created: time::PreciseTime::now(),
+			ip,
+			typ,
+		})
+	}
+}
+
+/// Masks or unmasks the bytes after the serialized id of a packet with a keystream. Packets
+/// without key are masked with a fixed key, which hides their headers from simple
+/// signatures but not from someone who knows this code.
+fn mask(key: Option<&[u8]>, id: &[u8], data: &mut [u8]) {
+	let k = aead::derive_key(key.unwrap_or(b"stealthy"), "stealthy packet mask", 32);
+	let input = data.to_vec();
+	ChaCha20::new(&k, id).process(&input, data);
+}
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+	use super::Packet;
+
+	#[test]
+	fn test_de_and_serialize() {
+
+		let p = Packet::new(vec![1, 2, 3], "1.2.3.4".to_string(), 258);
+
+		let v = p.serialize(None);
+		assert_eq!(v.len(), 10 + 3);
+		assert_eq!(v[..8], [2, 1, 0, 0, 0, 0, 0, 0]);
+		assert!(v[10..] != [1, 2, 3]);
+		assert!(Packet::verify(&v, None));
+		assert!(!Packet::verify(&v, Some(b"key")));
+		assert!(Packet::deserialize(&v, "1.2.3.4".to_string(), Some(b"key")).is_none());
+
+		let r = Packet::deserialize(&v, "1.2.3.4".to_string(), None).unwrap();
+		assert_eq!(r.id, 258);
+		assert_eq!(r.data, vec![1, 2, 3]);
+		assert!(r.is_new_message());
+	}
+
+	#[test]
+	fn test_authenticated() {
+
+		let p = Packet::new(vec![1, 2, 3], "1.2.3.4".to_string(), 258);
+
+		let v = p.serialize(Some(b"key"));
+		assert_eq!(v.len(), 10 + 3 + 16);
+		assert!(Packet::verify(&v, Some(b"key")));
+		assert!(!Packet::verify(&v, Some(b"other key")));
+
+		// Each modification is detected.
+		for i in 0..v.len() {
+			let mut x = v.clone();
+			x[i] ^= 1;
+			assert!(!Packet::verify(&x, Some(b"key")));
+		}
+
+		let r = Packet::deserialize(&v, "1.2.3.4".to_string(), Some(b"key")).unwrap();
+		assert_eq!(r.id, 258);
+		assert_eq!(r.data, vec![1, 2, 3]);
+		assert!(r.is_new_message());
+	}
+
+	#[test]
+	fn test_masked_header() {
+
+		// No byte after the id is the same in all packets. The bytes differ between keys.
+		let header = |id: u64, key: &[u8]| Packet::new(vec![1; 30], "1.2.3.4".to_string(), id).serialize(Some(key))[8..40].to_vec();
+		let v = (0..16).map(|id| header(id, b"key")).collect::<Vec<_>>();
+		for i in 0..32 {
+			assert!(v.iter().any(|h| h[i] != v[0][i]));
+		}
+		assert!((0..16).any(|id| header(id, b"key") != header(id, b"other key")));
+
+		let p = Packet::deserialize(&Packet::new(vec![1; 30], "1.2.3.4".to_string(), 5).serialize(Some(b"key")), "1.2.3.4".to_string(), Some(b"key"));
+		assert_eq!(p.unwrap().data, vec![1; 30]);
+	}
+
+	#[test]
+	fn test_forged_ack() {
+
+		let ack = Packet::create_ack(Packet::new(vec![], "1.2.3.4".to_string(), 99));
+		let v = ack.serialize(None);
+		assert!(!Packet::verify(&v, Some(b"key")));
+		assert!(Packet::verify(&ack.serialize(Some(b"key")), Some(b"key")));
 	}
 }
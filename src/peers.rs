@@ -0,0 +1,236 @@
+use std::net::IpAddr;
+
+use toml::Value;
+
+use crate::tools::read_file;
+
+/// Name of the peer directory in the directory `.stealthy` of the home directory.
+const PEERS_FILE: &str = "peers.toml";
+
+/// Smallest allowed value for the maximum payload of a packet. Smaller packets would not
+/// have room for data besides the headers of stealthy and of the ping profile.
+const MIN_PAYLOAD: i64 = 128;
+
+/// A contact of the peer directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Peer {
+    /// Name which is used to refer to the peer, e.g. on the command line.
+    pub name: String,
+    pub ip: String,
+    /// Name which is shown for messages of the peer. Defaults to the name.
+    pub nickname: Option<String>,
+    /// Symmetric key in hex which is used instead of the global key.
+    pub key: Option<String>,
+    /// File which contains the public key of the peer in PEM format.
+    pub public_key: Option<String>,
+    /// Maximum number of bytes of the payload of a single ICMP packet sent to the peer. The
+    /// headers of stealthy and of the traffic profile are part of this payload.
+    pub max_payload: Option<usize>,
+    /// Name of the relay in the directory via which messages are exchanged with the peer.
+    pub relay: Option<String>,
+}
+
+/// Peer directory with the contacts of the user.
+///
+/// The directory is a TOML file with one table for each peer. The name of the table is the
+/// name of the peer:
+///
+/// ```toml
+/// [bob]
+/// ip = "1.2.3.4"
+/// nickname = "Bob"
+/// key = "0011223344556677"
+/// public_key = "/home/alice/keys/bob.pem"
+/// max_payload = 1024
+/// relay = "hub"
+///
+/// [hub]
+/// ip = "9.9.9.9"
+/// key = "8899aabbccddeeff"
+/// ```
+///
+/// Only `ip` is required. Messages for a peer with a relay are left at the relay, i.e. at
+/// another entry of the directory which runs `stealthy relay`. The key of the relay is
+/// only used for the messages exchanged with the relay.
+#[derive(Clone)]
+pub struct Peers {
+    peers: Vec<Peer>,
+}
+
+impl Peers {
+
+    /// Creates an empty peer directory.
+    pub fn new() -> Peers {
+        Peers {
+            peers: vec![],
+        }
+    }
+
+    /// Loads the peer directory from the given file.
+    pub fn load(filename: &str) -> Result<Peers, &'static str> {
+        Peers::parse(&read_file(filename)?)
+    }
+
+    /// Loads the peer directory from the home directory of the user. If there is no such
+    /// file an empty directory is returned.
+    pub fn from_home() -> Result<Peers, &'static str> {
+        match dirs::home_dir() {
+            Some(mut path) => {
+                path.push(".stealthy");
+                path.push(PEERS_FILE);
+                match path.exists() {
+                    true => Peers::load(path.to_str().ok_or("Invalid path of peers file.")?),
+                    false => Ok(Peers::new())
+                }
+            },
+            None => Ok(Peers::new())
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Peers, &'static str> {
+
+        let table = match s.parse::<Value>() {
+            Ok(Value::Table(t)) => t,
+            _ => return Err("Could not parse peers file.")
+        };
+
+        let mut peers = vec![];
+        for (name, v) in table.iter() {
+            let str_value = |key: &str| v.get(key).and_then(|x| x.as_str()).map(|x| x.to_string());
+
+            // IPv6 addresses are stored in their canonical form so that they can be compared
+            // with the source addresses of received packets.
+            let ip = str_value("ip").ok_or("Peers file: each peer requires an IP.")?
+                .parse::<IpAddr>()
+                .map_err(|_| "Peers file: found invalid IP address.")?
+                .to_string();
+            let max_payload = match v.get("max_payload") {
+                Some(n) => Some(n.as_integer().filter(|n| *n >= MIN_PAYLOAD).ok_or("Peers file: invalid max_payload.")? as usize),
+                None => None
+            };
+
+            peers.push(Peer {
+                name: name.clone(),
+                ip,
+                nickname: str_value("nickname"),
+                key: str_value("key"),
+                public_key: str_value("public_key"),
+                max_payload,
+                relay: str_value("relay"),
+            });
+        }
+
+        let r = Peers { peers };
+        if r.peers.iter().filter_map(|p| p.relay.as_ref()).any(|name| r.find(name).is_none_or(|x| x.relay.is_some())) {
+            return Err("Peers file: found unknown relay.");
+        }
+        Ok(r)
+    }
+
+    /// Returns the peer with the given IP.
+    pub fn get(&self, ip: &str) -> Option<&Peer> {
+        self.peers.iter().find(|p| p.ip == ip)
+    }
+
+    /// Returns the peer with the given name.
+    pub fn find(&self, name: &str) -> Option<&Peer> {
+        self.peers.iter().find(|p| p.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Peer> {
+        self.peers.iter()
+    }
+
+    /// Returns the IPs of all peers which are not relays.
+    pub fn ips(&self) -> Vec<String> {
+        self.peers.iter().filter(|p| !self.is_relay(&p.name)).map(|p| p.ip.clone()).collect()
+    }
+
+    fn is_relay(&self, name: &str) -> bool {
+        self.peers.iter().any(|p| p.relay.as_ref().is_some_and(|r| r == name))
+    }
+
+    /// Returns the IP of the relay via which messages are exchanged with the given IP.
+    pub fn relay(&self, ip: &str) -> Option<String> {
+        let name = self.get(ip)?.relay.as_ref()?;
+        self.find(name).map(|r| r.ip.clone())
+    }
+
+    /// Returns the name which is shown for the given IP.
+    pub fn display_name(&self, ip: &str) -> String {
+        match self.get(ip) {
+            Some(p) => p.nickname.clone().unwrap_or(p.name.clone()),
+            None => ip.to_string()
+        }
+    }
+}
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::Peers;
+
+    const PEERS: &str = r#"
+        [bob]
+        ip = "1.2.3.4"
+        nickname = "Bob"
+        key = "0011"
+        public_key = "keys/bob.pem"
+        max_payload = 512
+
+        [carol]
+        ip = "5.6.7.8"
+        relay = "hub"
+
+        [hub]
+        ip = "8.8.8.8"
+    "#;
+
+    #[test]
+    fn test_parse() {
+        let p = Peers::parse(PEERS).unwrap();
+
+        let bob = p.find("bob").unwrap();
+        assert_eq!(bob.ip, "1.2.3.4");
+        assert_eq!(bob.key, Some("0011".to_string()));
+        assert_eq!(bob.public_key, Some("keys/bob.pem".to_string()));
+        assert_eq!(bob.max_payload, Some(512));
+        assert_eq!(p.get("1.2.3.4"), Some(bob));
+
+        let carol = p.find("carol").unwrap();
+        assert_eq!(carol.nickname, None);
+        assert_eq!(carol.key, None);
+        assert_eq!(carol.max_payload, None);
+
+        assert_eq!(p.relay("5.6.7.8"), Some("8.8.8.8".to_string()));
+        assert_eq!(p.relay("1.2.3.4"), None);
+
+        // Relays are not used for chatting.
+        assert_eq!(p.ips(), vec!["1.2.3.4", "5.6.7.8"]);
+        assert_eq!(p.display_name("1.2.3.4"), "Bob");
+        assert_eq!(p.display_name("5.6.7.8"), "carol");
+        assert_eq!(p.display_name("9.9.9.9"), "9.9.9.9");
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Peers::parse("[bob]\nnickname = \"Bob\"").is_err());
+        assert!(Peers::parse("[bob]\nip = \"1.2.3\"").is_err());
+        assert!(Peers::parse("[bob]\nip = \"1.2.3.4\"\nmax_payload = 10").is_err());
+        assert!(Peers::parse("[bob").is_err());
+        assert!(Peers::parse("[bob]\nip = \"1.2.3.4\"\nrelay = \"hub\"").is_err());
+        assert!(Peers::parse("[bob]\nip = \"fd00::1::2\"").is_err());
+        assert_eq!(Peers::parse("").unwrap().ips().len(), 0);
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let p = Peers::parse("[bob]\nip = \"FD00:0:0::0001\"").unwrap();
+        assert_eq!(p.ips(), vec!["fd00::1"]);
+        assert_eq!(p.display_name("fd00::1"), "bob");
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::delivery::push_value;
+use crate::transport::{Datagram, Transport};
+
+/// Sizes of the data of echo requests sent by ping: the default size of 56 bytes and
+/// sizes which are often chosen with `-s`.
+const SIZES: [usize; 6] = [56, 120, 248, 504, 1016, 1472];
+/// Size of the header in front of a datagram: the timestamp of ping (struct timeval with
+/// seconds and microseconds) and the length of the datagram.
+const HEADER_SIZE: usize = 16 + 2;
+/// Time in milliseconds between two echo requests of ping.
+const PING_INTERVAL: u64 = 1000;
+
+/// Describes how the packets sent to the peers look on the network.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Profile {
+    /// Packets are sent as fast as the peer accepts them and are as large as needed.
+    Default,
+    /// Packets look like the echo requests of the ping utility (see `PingTransport`).
+    Ping,
+}
+
+/// A datagram which waits until it is sent to a peer.
+struct Queued {
+    id: u16,
+    buf: Vec<u8>,
+    sent: Box<dyn FnOnce() + Send>,
+}
+
+/// A transport which makes the datagrams of another transport resemble the echo
+/// requests of the ping utility of Linux (iputils).
+///
+/// Each datagram is preceded by a timestamp like the data of ping and padded to one of the
+/// sizes which are typical for ping. Only the timestamp, the sizes, the padding and the
+/// timing resemble ping: the bytes after the timestamp which ping fills with a fixed
+/// pattern hold the length of the datagram and the datagram, so the data does not pass a
+/// check of the whole pattern. The echo requests for a peer are sent with incrementing
+/// sequence numbers at the interval of ping, i.e. this trades a lot of throughput for
+/// stealth. They are queued for each peer and sent by a thread of the peer, so the caller
+/// does not wait. Replies are sent right away. Both peers must use this transport.
+pub struct PingTransport {
+    inner: Arc<dyn Transport>,
+    interval: Duration,
+    queues: Mutex<HashMap<String, Sender<Queued>>>,
+}
+
+impl PingTransport {
+    pub fn new(inner: Box<dyn Transport>) -> PingTransport {
+        PingTransport::with_interval(inner, Duration::from_millis(PING_INTERVAL))
+    }
+
+    /// Creates a transport which sends an echo request to a peer at most once per interval.
+    pub fn with_interval(inner: Box<dyn Transport>, interval: Duration) -> PingTransport {
+        PingTransport {
+            inner: Arc::from(inner),
+            interval,
+            queues: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts the thread which sends the queued datagrams for the given IP. The thread
+    /// stops when the transport is dropped.
+    fn start_queue(&self, ip: String) -> Sender<Queued> {
+        let (tx, rx) = channel::<Queued>();
+        let inner = self.inner.clone();
+        let interval = self.interval;
+        thread::spawn(move || {
+            let mut seq: u16 = 0;
+            let mut next = Instant::now();
+            for q in rx {
+                let now = Instant::now();
+                if next > now {
+                    thread::sleep(next - now);
+                }
+                // Like ping the first request has the sequence number 1.
+                seq = seq.wrapping_add(1);
+                // The timestamp is taken when the request is sent.
+                if let Some(data) = shape(&q.buf) {
+                    inner.send_echo(&ip, q.id, seq, &data);
+                }
+                next = Instant::now() + interval;
+                (q.sent)();
+            }
+        });
+        tx
+    }
+}
+
+/// Returns the size of the data of an echo request which carries a datagram with the given
+/// number of bytes or None if the datagram is too large.
+fn padded_size(n: usize) -> Option<usize> {
+    SIZES.iter().find(|&&s| s >= HEADER_SIZE + n).cloned()
+}
+
+/// Puts the datagram into data which starts like the data of an echo request of ping.
+/// The timestamp is followed by the length (u16) and the datagram. Only the padding after
+/// the datagram follows the pattern of ping. Returns None if the datagram is too large.
+fn shape(buf: &[u8]) -> Option<Vec<u8>> {
+    let n = HEADER_SIZE + buf.len();
+    let size = padded_size(buf.len())?;
+    let t = time::get_time();
+    let mut v = Vec::with_capacity(size);
+    push_value(&mut v, t.sec as u64, 8);
+    push_value(&mut v, (t.nsec / 1000) as u64, 8);
+    push_value(&mut v, buf.len() as u64, 2);
+    v.extend_from_slice(buf);
+    // Ping fills the data after the timestamp with the offset of each byte. Only the
+    // bytes after the datagram are filled like this.
+    v.extend((n..size).map(|i| i as u8));
+    Some(v)
+}
+
+/// Returns the datagram contained in the data created by `shape`.
+fn unshape(buf: &[u8]) -> Option<Vec<u8>> {
+    if buf.len() < HEADER_SIZE {
+        return None;
+    }
+    let n = buf[16] as usize | (buf[17] as usize) << 8;
+    buf.get(HEADER_SIZE..HEADER_SIZE + n).map(|b| b.to_vec())
+}
+
+impl Transport for PingTransport {
+
+    fn send(&self, ip: &str, buf: &[u8]) -> bool {
+        self.send_echo(ip, rand::random::<u16>(), 0, buf)
+    }
+
+    /// Sends the datagram with the given identifier. The sequence number is replaced by
+    /// the sequence number of ping.
+    fn send_echo(&self, ip: &str, id: u16, seq: u16, buf: &[u8]) -> bool {
+        self.send_echo_notify(ip, id, seq, buf, Box::new(|| { }))
+    }
+
+    /// Queues the datagram. `sent` is called when the echo request has been sent.
+    fn send_echo_notify(&self, ip: &str, id: u16, _seq: u16, buf: &[u8], sent: Box<dyn FnOnce() + Send>) -> bool {
+        if padded_size(buf.len()).is_none() {
+            return false;
+        }
+        let mut queues = self.queues.lock().expect("Lock failed.");
+        let tx = queues.entry(ip.to_string()).or_insert_with(|| self.start_queue(ip.to_string()));
+        tx.send(Queued { id, buf: buf.to_vec(), sent }).is_ok()
+    }
+
+    fn reply(&self, ip: &str, token: u32, buf: &[u8]) -> bool {
+        match shape(buf) {
+            Some(data) => self.inner.reply(ip, token, &data),
+            None => false
+        }
+    }
+
+    fn listen(&self, tx: Sender<Datagram>) -> Result<(), &'static str> {
+        let (inner_tx, inner_rx) = channel::<Datagram>();
+        self.inner.listen(inner_tx)?;
+        thread::spawn(move || {
+            for d in inner_rx {
+                // Echo requests of other programs are ignored.
+                if let Some(buf) = unshape(&d.buf) {
+                    if tx.send(Datagram::new(buf, d.ip, d.reply, d.token)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn max_payload(&self) -> usize {
+        self.datagram_size(self.inner.max_payload())
+    }
+
+    fn datagram_size(&self, n: usize) -> usize {
+        let n = self.inner.datagram_size(n);
+        SIZES.iter().rev().find(|&&s| s <= n).map_or(0, |s| s - HEADER_SIZE)
+    }
+
+    fn interval(&self) -> i64 {
+        self.interval.as_millis() as i64
+    }
+}
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{channel, Sender};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{shape, unshape, PingTransport, HEADER_SIZE};
+    use crate::console::Console;
+    use crate::iptools::IpAddresses;
+    use crate::layer::Layers;
+    use crate::loopback::LoopbackBus;
+    use crate::message::{IncomingMessage, Message};
+    use crate::peers::Peers;
+    use crate::transport::{Datagram, Transport};
+
+    /// IP, identifier, sequence number, data and time of a sent echo request.
+    type Sent = (String, u16, u16, Vec<u8>, Instant);
+
+    /// Records the sent datagrams.
+    struct Recorder {
+        sent: Arc<Mutex<Vec<Sent>>>,
+    }
+
+    impl Transport for Recorder {
+        fn send(&self, ip: &str, buf: &[u8]) -> bool {
+            self.send_echo(ip, 0, 0, buf)
+        }
+
+        fn send_echo(&self, ip: &str, id: u16, seq: u16, buf: &[u8]) -> bool {
+            self.sent.lock().unwrap().push((ip.to_string(), id, seq, buf.to_vec(), Instant::now()));
+            true
+        }
+
+        fn reply(&self, _ip: &str, _token: u32, _buf: &[u8]) -> bool {
+            true
+        }
+
+        fn listen(&self, _tx: Sender<Datagram>) -> Result<(), &'static str> {
+            Ok(())
+        }
+
+        fn max_payload(&self) -> usize {
+            1200
+        }
+    }
+
+    #[test]
+    fn test_shape() {
+        // The default size of ping is used for small datagrams.
+        let v = shape(&[0xff; 38]).unwrap();
+        assert_eq!(v.len(), 56);
+        let v = shape(&[0xff; 10]).unwrap();
+        assert_eq!(v.len(), 56);
+        assert_eq!(v[HEADER_SIZE + 10..].to_vec(), (28..56).collect::<Vec<u8>>());
+        let sec = v[..8].iter().rev().fold(0, |a, &b| (a << 8) + b as i64);
+        assert!((sec - time::get_time().sec).abs() < 5);
+        assert_eq!(unshape(&v).unwrap(), vec![0xff; 10]);
+
+        assert_eq!(shape(&[0xff; 39]).unwrap().len(), 120);
+        assert_eq!(unshape(&shape(&[7; 1454]).unwrap()).unwrap(), vec![7; 1454]);
+        assert!(shape(&[7; 1455]).is_none());
+        assert_eq!(unshape(&shape(&[]).unwrap()).unwrap(), vec![]);
+
+        assert!(unshape(&[0; 17]).is_none());
+        let mut v = shape(&[1, 2, 3]).unwrap();
+        v[16] = 100;
+        assert!(unshape(&v).is_none());
+    }
+
+    #[test]
+    fn test_pacing() {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let t = PingTransport::with_interval(Box::new(Recorder { sent: sent.clone() }), Duration::from_millis(100));
+        assert_eq!(t.max_payload(), 1016 - HEADER_SIZE);
+        assert!(!t.send_echo("1.2.3.4", 7, 100, &[0; 1455]));
+
+        // The caller does not wait until the requests are sent.
+        let start = Instant::now();
+        let (tx, rx) = channel();
+        for (ip, id, buf) in [("1.2.3.4", 7, b"a"), ("1.2.3.4", 7, b"b"), ("5.6.7.8", 8, b"c"), ("1.2.3.4", 7, b"d")] {
+            let tx = tx.clone();
+            assert!(t.send_echo_notify(ip, id, 100, buf, Box::new(move || tx.send((buf.to_vec(), Instant::now())).unwrap())));
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+        let notified = (0..4).map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap()).collect::<Vec<_>>();
+
+        let sent = sent.lock().unwrap();
+        let seqs = |ip: &str| sent.iter().filter(|x| x.0 == ip).map(|x| (x.1, x.2, unshape(&x.3).unwrap())).collect::<Vec<_>>();
+        assert_eq!(seqs("1.2.3.4"), vec![(7, 1, b"a".to_vec()), (7, 2, b"b".to_vec()), (7, 3, b"d".to_vec())]);
+        assert_eq!(seqs("5.6.7.8"), vec![(8, 1, b"c".to_vec())]);
+        assert!(sent.iter().all(|(_, _, _, buf, _)| buf.len() == 56));
+        // Requests for the same peer are sent at the interval, other peers do not wait.
+        let at = |buf: &[u8]| sent.iter().find(|x| unshape(&x.3).unwrap() == buf).unwrap().4;
+        assert!(at(b"b") - at(b"a") >= Duration::from_millis(95));
+        assert!(at(b"d") - at(b"b") >= Duration::from_millis(95));
+        assert!(at(b"c") - start < Duration::from_millis(50));
+        // The caller is notified after a request has been sent.
+        for (buf, time) in notified {
+            assert!(time >= at(&buf));
+        }
+    }
+
+    #[test]
+    fn test_paced_acks() {
+        let bus = LoopbackBus::new();
+        let layer = |ip: &str, peer: &str| {
+            let (tx, rx) = channel();
+            thread::spawn(move || for _ in rx { });
+            let t = PingTransport::with_interval(Box::new(bus.connect(ip)), Duration::from_millis(1000));
+            Layers::symmetric(
+                "11111111111111111111111111111111",
+                &Peers::new(),
+                Box::new(t),
+                Console::new(tx),
+                &IpAddresses::from_comma_list(peer)
+            ).unwrap()
+        };
+        let alice = layer("10.0.0.1", "10.0.0.2");
+        let _bob = layer("10.0.0.2", "10.0.0.1");
+
+        // The messages wait in the queue of Alice for several seconds. They are not sent
+        // again as the acks of Bob arrive within the timeout after each has gone out.
+        for id in 1..4 {
+            alice.layers.send(Message::new("10.0.0.2".to_string(), vec![id as u8]), id, false);
+        }
+        let mut acked = vec![];
+        while acked.len() < 3 {
+            match alice.rx.recv_timeout(Duration::from_secs(20)).expect("Timeout.") {
+                IncomingMessage::Ack(id) => acked.push(id),
+                IncomingMessage::Retry(id) => panic!("Message {} has been sent again.", id),
+                _ => { }
+            }
+        }
+        acked.sort();
+        assert_eq!(acked, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_layers() {
+        let bus = LoopbackBus::new();
+        let mut consoles = vec![];
+        let mut layer = |ip: &str, peer: &str| {
+            let (tx, rx) = channel();
+            consoles.push(rx);
+            let t = PingTransport::with_interval(Box::new(bus.connect(ip)), Duration::from_millis(20));
+            Layers::symmetric(
+                "11111111111111111111111111111111",
+                &Peers::new(),
+                Box::new(t),
+                Console::new(tx),
+                &IpAddresses::from_comma_list(peer)
+            ).unwrap()
+        };
+        let alice = layer("10.0.0.1", "10.0.0.2");
+        let bob = layer("10.0.0.2", "10.0.0.1");
+
+        let text = (0..3000).map(|i| (i % 256) as u8).collect::<Vec<_>>();
+        alice.layers.send(Message::new("10.0.0.2".to_string(), text.clone()), 1, false);
+        loop {
+            if let IncomingMessage::New(m) = bob.rx.recv_timeout(Duration::from_secs(20)).expect("Timeout.") {
+                assert_eq!(m.buf, text);
+                break;
+            }
+        }
+        loop {
+            if let IncomingMessage::Ack(1) = alice.rx.recv_timeout(Duration::from_secs(20)).expect("Timeout.") {
+                break;
+            }
+        }
+    }
+}
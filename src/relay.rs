@@ -0,0 +1,569 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+
+use crate::delivery::{push_slice, push_value};
+use crate::iptools::IpAddresses;
+use crate::layer::Layers;
+use crate::message::{IncomingMessage, Message};
+use crate::tools::to_hex;
+use crate::transfer::Reader;
+use crate::Console;
+
+const VERSION: u8 = 1;
+
+const FORWARD: u8 = 1;
+const FETCH: u8 = 2;
+const DELIVER: u8 = 3;
+const REJECTED: u8 = 4;
+
+/// Time in seconds after which a client asks its relays again for messages.
+pub const FETCH_INTERVAL: u64 = 60;
+
+/// A message exchanged with a relay. It is encrypted with the key of the relay and sent
+/// like a normal message. The messages which are kept by the relay are encrypted with
+/// the key of the recipient, i.e. the relay cannot read them.
+///
+/// u8 : version { 1 }
+/// u8 : type { 1 = forward, 2 = fetch, 3 = deliver, 4 = rejected }
+///
+/// forward, deliver:
+/// u8 : 1 if the message belongs to a file transfer, 0 otherwise
+/// u8 : length of the IP
+/// IP : IP of the recipient (forward) or of the sender (deliver)
+/// message encrypted for the recipient
+///
+/// rejected:
+/// IP of the recipient
+#[derive(Debug, PartialEq)]
+pub enum Envelope {
+    /// Asks the relay to keep the message for the peer with the given IP.
+    Forward { to: String, file: bool, data: Vec<u8> },
+    /// Asks the relay for the messages which it keeps for the sender.
+    Fetch,
+    /// A message which the peer with the given IP has left at the relay.
+    Deliver { from: String, file: bool, data: Vec<u8> },
+    /// The relay has not kept a message for the peer with the given IP.
+    Rejected(String),
+}
+
+impl Envelope {
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut v = vec![VERSION];
+        match self {
+            Envelope::Forward { to, file, data } => {
+                v.push(FORWARD);
+                push_message(&mut v, to, *file, data);
+            },
+            Envelope::Fetch => v.push(FETCH),
+            Envelope::Deliver { from, file, data } => {
+                v.push(DELIVER);
+                push_message(&mut v, from, *file, data);
+            },
+            Envelope::Rejected(to) => {
+                v.push(REJECTED);
+                push_slice(&mut v, to.as_bytes());
+            }
+        }
+        v
+    }
+
+    pub fn deserialize(buf: &[u8]) -> Option<Envelope> {
+        let mut r = Reader::new(buf);
+        if r.value(1)? != VERSION as u64 {
+            return None;
+        }
+        match r.value(1)? as u8 {
+            FORWARD => {
+                let (to, file, data) = read_message(&mut r)?;
+                Some(Envelope::Forward { to, file, data })
+            },
+            FETCH => Some(Envelope::Fetch),
+            DELIVER => {
+                let (from, file, data) = read_message(&mut r)?;
+                Some(Envelope::Deliver { from, file, data })
+            },
+            REJECTED => Some(Envelope::Rejected(String::from_utf8(r.buf.to_vec()).ok()?)),
+            _ => None
+        }
+    }
+}
+
+fn push_message(v: &mut Vec<u8>, ip: &str, file: bool, data: &[u8]) {
+    push_value(v, file as u64, 1);
+    push_value(v, ip.len() as u64, 1);
+    push_slice(v, ip.as_bytes());
+    push_slice(v, data);
+}
+
+fn read_message(r: &mut Reader) -> Option<(String, bool, Vec<u8>)> {
+    let file = r.value(1)? == 1;
+    let n = r.value(1)? as usize;
+    let ip = String::from_utf8(r.take(n)?.to_vec()).ok()?;
+    Some((ip, file, r.buf.to_vec()))
+}
+
+/// Limits of the messages which are kept by a relay.
+#[derive(Clone)]
+pub struct RelayOptions {
+    /// Maximum number of bytes of the messages which are kept for each sender.
+    pub max_size: u64,
+    /// Time in seconds after which a message which has not been fetched is dropped.
+    pub max_age: i64,
+}
+
+impl Default for RelayOptions {
+    fn default() -> RelayOptions {
+        RelayOptions {
+            max_size: 10 << 20,
+            max_age: 7 * 24 * 3600,
+        }
+    }
+}
+
+/// A message kept by a relay.
+struct Letter {
+    from: String,
+    to: String,
+    file: bool,
+    data: Vec<u8>,
+    /// Time in seconds when the message has been received.
+    time: i64,
+    /// Id of the message with which the letter is being sent to the recipient.
+    sending: Option<u64>,
+    /// File in which the letter is stored.
+    path: Option<PathBuf>,
+}
+
+impl Letter {
+
+    /// Serializes the letter for the file in which it is stored.
+    ///
+    /// u64 : time
+    /// u8  : length of the IP of the sender
+    /// IP  : IP of the sender
+    /// message like in forward
+    fn serialize(&self) -> Vec<u8> {
+        let mut v = vec![];
+        push_value(&mut v, self.time as u64, 8);
+        push_value(&mut v, self.from.len() as u64, 1);
+        push_slice(&mut v, self.from.as_bytes());
+        push_message(&mut v, &self.to, self.file, &self.data);
+        v
+    }
+
+    fn deserialize(buf: &[u8], path: PathBuf) -> Option<Letter> {
+        let mut r = Reader::new(buf);
+        let time = r.value(8)? as i64;
+        let n = r.value(1)? as usize;
+        let from = String::from_utf8(r.take(n)?.to_vec()).ok()?;
+        let (to, file, data) = read_message(&mut r)?;
+        Some(Letter { from, to, file, data, time, sending: None, path: Some(path) })
+    }
+
+    /// Writes the letter into a new file in the given directory.
+    fn store(&mut self, dir: &Path) -> Result<(), &'static str> {
+        let path = dir.join(to_hex(&rand::random::<[u8; 8]>()));
+        let mut f = File::create(&path).map_err(|_| "Could not write message.")?;
+        if f.write_all(&self.serialize()).and_then(|_| f.sync_data()).is_err() {
+            let _ = fs::remove_file(&path);
+            return Err("Could not write message.");
+        }
+        self.path = Some(path);
+        Ok(())
+    }
+
+    fn remove(&self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Returns the directory in which a relay stores the messages.
+pub fn mailbox_dir() -> PathBuf {
+    let mut path = dirs::home_dir().unwrap_or(std::env::temp_dir());
+    path.push(".stealthy");
+    path.push("relay");
+    path
+}
+
+/// The messages kept by a relay.
+///
+/// Each sender may leave messages up to the maximum size, so a peer cannot fill the
+/// mailbox for the other peers. If the mailbox has a directory each message is stored in
+/// a file until it has been delivered, so the messages are not lost when the relay is
+/// restarted.
+pub struct Mailbox {
+    letters: Vec<Letter>,
+    /// Number of bytes of the kept messages indexed by the IPs of their senders.
+    sizes: HashMap<String, u64>,
+    options: RelayOptions,
+    dir: Option<PathBuf>,
+}
+
+impl Mailbox {
+
+    /// Creates a mailbox which keeps the messages in memory only.
+    #[cfg(test)]
+    pub fn new(options: RelayOptions) -> Mailbox {
+        Mailbox {
+            letters: vec![],
+            sizes: HashMap::new(),
+            options,
+            dir: None,
+        }
+    }
+
+    /// Creates a mailbox which stores the messages in the given directory. The messages
+    /// which have been stored before are loaded. Files which cannot be read are skipped.
+    pub fn open(dir: &Path, options: RelayOptions) -> Result<Mailbox, &'static str> {
+        fs::create_dir_all(dir).map_err(|_| "Could not create directory for messages.")?;
+        let mut paths = fs::read_dir(dir).map_err(|_| "Could not read directory for messages.")?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .collect::<Vec<_>>();
+        paths.sort();
+        let mut letters = paths.into_iter()
+            .filter_map(|path| Letter::deserialize(&fs::read(&path).ok()?, path))
+            .collect::<Vec<_>>();
+        letters.sort_by_key(|l| l.time);
+
+        let mut m = Mailbox {
+            letters,
+            sizes: HashMap::new(),
+            options,
+            dir: Some(dir.to_path_buf()),
+        };
+        m.expire(time::get_time().sec);
+        Ok(m)
+    }
+
+    /// Keeps a message. Returns false if the message does not fit into the space which is
+    /// left for its sender or if it could not be stored.
+    pub fn push(&mut self, from: String, to: String, file: bool, data: Vec<u8>, now: i64) -> bool {
+        self.expire(now);
+        let size = self.sizes.entry(from.clone()).or_insert(0);
+        if *size + data.len() as u64 > self.options.max_size {
+            return false;
+        }
+        let mut l = Letter { from, to, file, data, time: now, sending: None, path: None };
+        if let Some(dir) = &self.dir {
+            if l.store(dir).is_err() {
+                return false;
+            }
+        }
+        *size += l.data.len() as u64;
+        self.letters.push(l);
+        true
+    }
+
+    /// Returns the messages for the given IP which are not being sent already. Each
+    /// message is marked with a new message id with which it has to be sent.
+    pub fn take(&mut self, to: &str, now: i64) -> Vec<(u64, Envelope)> {
+        self.expire(now);
+        self.letters.iter_mut()
+            .filter(|l| l.to == to && l.sending.is_none())
+            .map(|l| {
+                let id = rand::random::<u64>();
+                l.sending = Some(id);
+                (id, Envelope::Deliver { from: l.from.clone(), file: l.file, data: l.data.clone() })
+            })
+            .collect()
+    }
+
+    /// Removes the message which has been sent with the given id. Returns false if there
+    /// is no such message.
+    pub fn delivered(&mut self, id: u64) -> bool {
+        match self.letters.iter().position(|l| l.sending == Some(id)) {
+            Some(i) => {
+                let l = self.letters.remove(i);
+                l.remove();
+                if let Some(size) = self.sizes.get_mut(&l.from) {
+                    *size -= l.data.len() as u64;
+                }
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Keeps the message which could not be sent with the given id for the next fetch.
+    pub fn failed(&mut self, id: u64) {
+        for l in self.letters.iter_mut().filter(|l| l.sending == Some(id)) {
+            l.sending = None;
+        }
+    }
+
+    /// Returns the number of messages.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.letters.len()
+    }
+
+    /// Drops the messages which are older than allowed.
+    fn expire(&mut self, now: i64) {
+        let max_age = self.options.max_age;
+        self.letters.retain(|l| {
+            let keep = now - l.time <= max_age;
+            if !keep {
+                l.remove();
+            }
+            keep
+        });
+        self.sizes.clear();
+        for l in &self.letters {
+            *self.sizes.entry(l.from.clone()).or_insert(0) += l.data.len() as u64;
+        }
+    }
+}
+
+/// A node which keeps messages for peers which are offline or unreachable.
+///
+/// Registered peers leave messages for other registered peers at the relay. The relay
+/// hands them over when the recipient asks for its messages.
+#[derive(Clone)]
+pub struct Relay {
+    mailbox: Arc<Mutex<Mailbox>>,
+    /// IPs of the registered peers.
+    peers: Vec<String>,
+    layers: Layers,
+    console: Console,
+}
+
+impl Relay {
+
+    pub fn new(mailbox: Mailbox, peers: &IpAddresses, layers: Layers, console: Console) -> Relay {
+        Relay {
+            mailbox: Arc::new(Mutex::new(mailbox)),
+            peers: peers.as_strings(),
+            layers,
+            console,
+        }
+    }
+
+    /// Processes the messages received by the relay until the channel is closed.
+    pub fn run(&self, rx: Receiver<IncomingMessage>) {
+        for m in rx {
+            self.handle(m);
+        }
+    }
+
+    fn handle(&self, m: IncomingMessage) {
+        match m {
+            IncomingMessage::New(msg) => match Envelope::deserialize(&msg.buf) {
+                Some(Envelope::Forward { to, file, data }) => self.keep(msg.ip, to, file, data),
+                Some(Envelope::Fetch) => self.fetch(msg.ip),
+                _ => self.console.error(format!("Ignored invalid message from {}.", msg.ip))
+            },
+            IncomingMessage::Ack(id) if self.mailbox.lock().expect("Lock failed.").delivered(id) => {
+                self.console.status(format!("Handed over message {}.", id));
+            },
+            IncomingMessage::Failed(id) => self.mailbox.lock().expect("Lock failed.").failed(id),
+            IncomingMessage::Error(_, s) => self.console.error(s),
+            _ => { }
+        }
+    }
+
+    fn keep(&self, from: String, to: String, file: bool, data: Vec<u8>) {
+        let kept = self.peers.contains(&to) &&
+            self.mailbox.lock().expect("Lock failed.").push(from.clone(), to.clone(), file, data, now());
+        if kept {
+            self.console.status(format!("Keeping message from {} for {}.", from, to));
+        } else {
+            self.console.error(format!("Rejected message from {} for {}.", from, to));
+            let m = Message::new(from, Envelope::Rejected(to).serialize());
+            self.layers.send(m, rand::random::<u64>(), true);
+        }
+    }
+
+    fn fetch(&self, ip: String) {
+        let letters = self.mailbox.lock().expect("Lock failed.").take(&ip, now());
+        for (id, e) in letters {
+            self.layers.send(Message::new(ip.clone(), e.serialize()), id, true);
+        }
+    }
+}
+
+fn now() -> i64 {
+    time::get_time().sec
+}
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{Envelope, Mailbox, Relay, RelayOptions};
+    use crate::console::Console;
+    use crate::iptools::IpAddresses;
+    use crate::layer::Layers;
+    use crate::loopback::LoopbackBus;
+    use crate::message::{IncomingMessage, Message};
+    use crate::peers::Peers;
+    use crate::tools::random_str;
+
+    #[test]
+    fn test_envelope() {
+        let v = vec![
+            Envelope::Forward { to: "1.2.3.4".to_string(), file: true, data: vec![1, 2, 3] },
+            Envelope::Fetch,
+            Envelope::Deliver { from: "5.6.7.8".to_string(), file: false, data: vec![] },
+            Envelope::Rejected("1.2.3.4".to_string()),
+        ];
+        for e in v {
+            assert_eq!(Envelope::deserialize(&e.serialize()).unwrap(), e);
+        }
+        assert!(Envelope::deserialize(&[]).is_none());
+        assert!(Envelope::deserialize(&[2, 2]).is_none());
+        assert!(Envelope::deserialize(&[1, 1, 0, 10, 1]).is_none());
+    }
+
+    #[test]
+    fn test_mailbox() {
+        let mut m = Mailbox::new(RelayOptions { max_size: 10, max_age: 100 });
+        assert!(m.push("a".to_string(), "b".to_string(), false, vec![1; 6], 0));
+        // The space of the sender is used up.
+        assert!(!m.push("a".to_string(), "b".to_string(), false, vec![2; 6], 0));
+        assert!(m.push("a".to_string(), "c".to_string(), false, vec![3; 4], 50));
+        // Other senders still have their own space.
+        assert!(m.push("d".to_string(), "b".to_string(), false, vec![5; 10], 50));
+        assert!(!m.push("d".to_string(), "c".to_string(), false, vec![6; 1], 50));
+
+        let v = m.take("b", 50);
+        assert_eq!(v.len(), 2);
+        assert_eq!(v[0].1, Envelope::Deliver { from: "a".to_string(), file: false, data: vec![1; 6] });
+        // A message which is being sent is not taken again.
+        assert!(m.take("b", 50).is_empty());
+        m.failed(v[0].0);
+        assert!(m.delivered(v[1].0));
+        let v = m.take("b", 50);
+        assert_eq!(v.len(), 1);
+        assert!(m.delivered(v[0].0));
+        assert!(!m.delivered(v[0].0));
+        assert_eq!(m.len(), 1);
+        // Delivered messages free the space of their senders.
+        assert!(m.push("d".to_string(), "c".to_string(), false, vec![6; 10], 50));
+        assert!(m.push("a".to_string(), "b".to_string(), false, vec![7; 6], 50));
+        assert_eq!(m.take("b", 50).len(), 1);
+        assert_eq!(m.len(), 3);
+
+        // Old messages are dropped.
+        assert!(m.take("c", 151).is_empty());
+        assert_eq!(m.len(), 0);
+        assert!(m.push("a".to_string(), "b".to_string(), false, vec![4; 10], 151));
+    }
+
+    #[test]
+    fn test_stored_mailbox() {
+        let dir = std::env::temp_dir().join(format!("stealthy_test_{}", random_str(10)));
+        let options = RelayOptions { max_size: 10, max_age: 100 };
+        let now = time::get_time().sec;
+        let mut m = Mailbox::open(&dir, options.clone()).unwrap();
+        assert!(m.push("a".to_string(), "b".to_string(), true, vec![1; 6], now));
+        assert!(m.push("d".to_string(), "c".to_string(), false, vec![2; 4], now));
+        assert!(m.push("d".to_string(), "b".to_string(), false, vec![3; 5], now - 90));
+        let v = m.take("c", now);
+        assert!(m.delivered(v[0].0));
+        m.take("b", now);
+        drop(m);
+
+        // The messages which have not been delivered are kept after a restart. Messages
+        // which are being sent are sent again.
+        let mut m = Mailbox::open(&dir, options.clone()).unwrap();
+        assert_eq!(m.len(), 2);
+        let v = m.take("b", now);
+        assert_eq!(v[0].1, Envelope::Deliver { from: "d".to_string(), file: false, data: vec![3; 5] });
+        assert_eq!(v[1].1, Envelope::Deliver { from: "a".to_string(), file: true, data: vec![1; 6] });
+        assert!(m.delivered(v[1].0));
+        // The space of the senders is restored.
+        assert!(!m.push("d".to_string(), "b".to_string(), false, vec![4; 6], now));
+
+        // Old messages are removed.
+        m.take("b", now + 11);
+        assert_eq!(m.len(), 0);
+        assert_eq!(Mailbox::open(&dir, options).unwrap().len(), 0);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn layers(bus: &LoopbackBus, ip: &str, peers: &str, key: &str, directory: &str) -> (Layers, std::sync::mpsc::Receiver<IncomingMessage>) {
+        let (tx, rx) = channel();
+        // The console must be able to send.
+        thread::spawn(move || for _ in rx { });
+        let l = Layers::symmetric(
+            key,
+            &Peers::parse(directory).unwrap(),
+            Box::new(bus.connect(ip)),
+            Console::new(tx),
+            &IpAddresses::from_comma_list(peers)
+        ).unwrap();
+        (l.layers, l.rx)
+    }
+
+    #[test]
+    fn test_relay() {
+        const RELAY_KEY: &str = "33333333333333333333333333333333";
+        let bus = LoopbackBus::new();
+        let (hub, rx) = layers(&bus, "10.0.0.9", "10.0.0.1,10.0.0.2", RELAY_KEY, "");
+        let (tx, _console) = channel();
+        let relay = Relay::new(Mailbox::new(RelayOptions::default()), &IpAddresses::from_comma_list("10.0.0.1,10.0.0.2"), hub, Console::new(tx));
+        let r = relay.clone();
+        thread::spawn(move || r.run(rx));
+
+        let directory = |name: &str, ip: &str| format!(r#"
+            [{}]
+            ip = "{}"
+            key = "22222222222222222222222222222222"
+            relay = "hub"
+            [hub]
+            ip = "10.0.0.9"
+            key = "{}"
+        "#, name, ip, RELAY_KEY);
+
+        // Alice leaves a message for Bob who is offline. The relay acknowledges it.
+        let (alice, alice_rx) = layers(&bus, "10.0.0.1", "10.0.0.2,10.0.0.9", "11111111111111111111111111111111", &directory("bob", "10.0.0.2"));
+        alice.send(Message::new("10.0.0.2".to_string(), b"hello".to_vec()), 1, false);
+        loop {
+            if let IncomingMessage::Ack(1) = alice_rx.recv_timeout(Duration::from_secs(10)).expect("Timeout.") {
+                break;
+            }
+        }
+        assert_eq!(relay.mailbox.lock().unwrap().len(), 1);
+
+        // Bob comes online and fetches the message.
+        let (bob, bob_rx) = layers(&bus, "10.0.0.2", "10.0.0.1,10.0.0.9", "11111111111111111111111111111111", &directory("alice", "10.0.0.1"));
+        bob.fetch();
+        loop {
+            match bob_rx.recv_timeout(Duration::from_secs(10)).expect("Timeout.") {
+                IncomingMessage::New(m) => {
+                    assert_eq!(m.ip, "10.0.0.1");
+                    assert_eq!(m.buf, b"hello".to_vec());
+                    break;
+                },
+                IncomingMessage::Ack(_) => panic!("Unexpected ack."),
+                _ => { }
+            }
+        }
+
+        // The message is removed when Bob has acknowledged it.
+        for _ in 0..100 {
+            if relay.mailbox.lock().unwrap().len() == 0 {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("Message has not been removed.");
+    }
+}
@@ -1,6 +1,6 @@
-use rand::rngs::OsRng;
-use rand::Rng;
-use std::{iter, ptr};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::ptr;
 
 pub enum BIO {}
 pub enum EvpPkey {}
@@ -33,21 +33,23 @@ extern {
     // https://www.openssl.org/docs/crypto/EvpPkey_new.html
     fn EVP_PKEY_free(pkey: *mut EvpPkey) -> libc::c_void;
 
-    // https://www.openssl.org/docs/crypto/RSA_public_encrypt.html
-    fn RSA_public_encrypt(
-        flen: libc::c_int,
-        from: *mut u8,
-        to: *mut u8,
-        rsa: *mut RSA_,
-        padding: libc::c_int) -> libc::c_int;
-
-    // https://www.openssl.org/docs/crypto/RSA_public_encrypt.html
-    pub fn RSA_private_decrypt(
-        flen: libc::c_int,
-        from: *mut u8,
-        to: *mut u8,
-        rsa: *mut RSA_,
-        padding: libc::c_int) -> libc::c_int;
+    // https://www.openssl.org/docs/crypto/RSA_sign.html
+    fn RSA_sign(
+        typ: libc::c_int,
+        m: *const u8,
+        m_len: libc::c_uint,
+        sigret: *mut u8,
+        siglen: *mut libc::c_uint,
+        rsa: *mut RSA_) -> libc::c_int;
+
+    // https://www.openssl.org/docs/crypto/RSA_sign.html
+    fn RSA_verify(
+        typ: libc::c_int,
+        m: *const u8,
+        m_len: libc::c_uint,
+        sigbuf: *const u8,
+        siglen: libc::c_uint,
+        rsa: *mut RSA_) -> libc::c_int;
 
     // https://www.openssl.org/docs/crypto/RSA_size.html
     fn RSA_size(rsa: *const RSA_) -> libc::c_int;
@@ -55,11 +57,11 @@ extern {
     // https://www.openssl.org/docs/crypto/RSA_new.html
     fn RSA_free(rsa: *mut RSA_);
 
-    // https://www.openssl.org/docs/crypto/rand.html
-    fn RAND_seed(buf: *const libc::c_void, len: libc::c_int);
+    // https://www.openssl.org/docs/crypto/d2i_RSAPublicKey.html
+    fn i2d_RSA_PUBKEY(rsa: *mut RSA_, pp: *mut *mut u8) -> libc::c_int;
 }
 
-const RSA_PKCS1_OAEP_PADDING: libc::c_int = 4;   // openssl/rsa.h
+const NID_SHA256: libc::c_int = 672;   // openssl/obj_mac.h
 
 
 enum KeyType {
@@ -125,72 +127,91 @@ impl RSA {
         RSA::pem(pem, KeyType::PrivateKey)
     }
 
-    fn seed_rand() -> Result<(), &'static str> {
-
-        match OsRng::new() {
-            Ok(mut r) => {
-                let mut seed: [u8; 32] = [0; 32];
-                r.fill(&mut seed);
-                unsafe {
-                    RAND_seed(
-                        seed.as_ptr() as *const libc::c_void,
-                        seed.len() as libc::c_int
-                    );
-                    Ok(())
-                }
+    /// Returns the public key of the RSA structure in DER format.
+    fn der(rsa: *mut RSA_) -> Result<Vec<u8>, &'static str> {
+
+        unsafe {
+            let n = i2d_RSA_PUBKEY(rsa, ptr::null_mut());
+            if n <= 0 {
+                return Err("Could not encode public key.");
+            }
+            let mut buf = vec![0; n as usize];
+            // i2d_RSA_PUBKEY moves the pointer behind the written data.
+            let mut p = buf.as_mut_ptr();
+            match i2d_RSA_PUBKEY(rsa, &mut p) == n {
+                true => Ok(buf),
+                false => Err("Could not encode public key.")
             }
-            _ => Err("Could not get OsRng.")
         }
     }
 
-    fn crypt(f: unsafe extern "C" fn(
-                flen: libc::c_int, from: *mut u8, to: *mut u8, rsa: *mut RSA_, padding: libc::c_int) -> libc::c_int,
-             msg: &[u8],
-             key: *mut RSA_) -> Result<Vec<u8>, &'static str> {
+    /// Returns the public key in DER format.
+    pub fn public_key(&self) -> Result<Vec<u8>, &'static str> {
+        RSA::der(self.rsapub)
+    }
+
+    /// Returns the public key which belongs to the given private key in DER format.
+    pub fn public_key_of(privkey: &String) -> Result<Vec<u8>, &'static str> {
+
+        let rsa = RSA::rsa_privkey(privkey)?;
+        let r = RSA::der(rsa);
+        unsafe {
+            RSA_free(rsa);
+        }
+        r
+    }
+
+    fn sha256(msg: &[u8]) -> Vec<u8> {
+        let mut h = Sha256::new();
+        h.input(msg);
+        let mut digest = vec![0; h.output_bytes()];
+        h.result(&mut digest);
+        digest
+    }
+
+    /// Signs the SHA-256 hash of the message with the private key.
+    pub fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, &'static str> {
 
+        let digest = Self::sha256(msg);
         unsafe {
-            let siz = RSA_size(key) as usize;
-            let mut buf = iter::repeat(0).take(siz).collect::<Vec<u8>>();
-
-            let ret = f(
-                msg.len()    as libc::c_int,
-                msg.as_ptr() as *mut u8,
-                buf.as_ptr() as *mut u8,
-                key,
-                RSA_PKCS1_OAEP_PADDING
+            let mut buf = vec![0u8; RSA_size(self.rsapriv) as usize];
+            let mut siglen: libc::c_uint = 0;
+
+            let ret = RSA_sign(
+                NID_SHA256,
+                digest.as_ptr(),
+                digest.len() as libc::c_uint,
+                buf.as_mut_ptr(),
+                &mut siglen,
+                self.rsapriv
             );
 
             match ret {
-                -1 => Err("Encryption or decryption with RSA failed."),
-                _  => {
-                    buf.truncate(ret as usize);
+                1 => {
+                    buf.truncate(siglen as usize);
                     Ok(buf)
-                }
+                },
+                _ => Err("Signing with RSA failed.")
             }
         }
     }
 
-    pub fn encrypt(&self, msg: &[u8]) -> Result<Vec<u8>, &'static str> {
-
-        // "rng must be seeded prior to calling this method"
-        Self::seed_rand()?;
+    /// Verifies the signature of the message with the public key.
+    pub fn verify(&self, msg: &[u8], sig: &[u8]) -> bool {
 
+        let digest = Self::sha256(msg);
         unsafe {
-            // For encryption message length must be less than siz - 41
-            // for RSA_PKCS1_OAEP_PADDING.
-            let siz = RSA_size(self.rsapub) as usize;
-            if msg.len() >= siz - 41 {
-                return Err("Message too large for RSA_PKCS1_OAEP_PADDING");
-            }
-
-            Self::crypt(RSA_public_encrypt, msg, self.rsapub)
+            RSA_verify(
+                NID_SHA256,
+                digest.as_ptr(),
+                digest.len() as libc::c_uint,
+                sig.as_ptr(),
+                sig.len() as libc::c_uint,
+                self.rsapub
+            ) == 1
         }
     }
 
-    pub fn decrypt(&self, cipher: &[u8]) -> Result<Vec<u8>, &'static str> {
-        Self::crypt(RSA_private_decrypt, cipher, self.rsapriv)
-    }
-
     pub fn new(pubkey: &String, privkey: &String) -> Result<RSA, &'static str> {
 
         Ok(RSA {
@@ -208,8 +229,8 @@ impl RSA {
 #[cfg(test)]
 mod tests {
 
-    use ::cryp::read_file;
-    use ::rsa::RSA;
+    use crate::tools::read_file;
+    use crate::rsa::RSA;
 
     #[test]
     fn test_new() {
@@ -228,22 +249,29 @@ mod tests {
     }
 
     #[test]
-    fn test_encrypt_decrypt() {
-        // use cargo test -- --nocapture to see output of print
+    fn test_sign_verify() {
 
         let pubkey = read_file("tests/keys/rsa_pub.pem").unwrap();
         let privkey = read_file("tests/keys/rsa_priv.pem").unwrap();
-
         let rsa = RSA::new(&pubkey, &privkey).unwrap();
-        let plain = "hello".to_string();
 
-        let cipher = rsa.encrypt(&plain.clone().into_bytes()).unwrap();
+        let sig = rsa.sign(b"hello").unwrap();
+        assert_eq!(sig.len(), 256);
+        assert!(rsa.verify(b"hello", &sig));
+        assert!(!rsa.verify(b"hellO", &sig));
+        assert!(!rsa.verify(b"hello", &sig[1..]));
+    }
+
+    #[test]
+    fn test_public_key() {
 
-        assert!(cipher != plain.clone().into_bytes());
-        assert!(cipher.len() >= 256);
+        let pubkey = read_file("tests/keys/rsa_pub.pem").unwrap();
+        let privkey = read_file("tests/keys/rsa_priv.pem").unwrap();
+        let der = std::fs::read("tests/keys/rsa_pub.der").unwrap();
 
-        let p = String::from_utf8(rsa.decrypt(&cipher).unwrap()).unwrap();
-        assert_eq!(p, plain);
+        assert_eq!(RSA::new(&pubkey, &privkey).unwrap().public_key().unwrap(), der);
+        assert_eq!(RSA::public_key_of(&privkey).unwrap(), der);
+        assert!(RSA::public_key_of(&pubkey).is_err());
     }
 
     #[test]
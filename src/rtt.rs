@@ -0,0 +1,129 @@
+/// Lower bound of the retransmission timeout in milliseconds.
+pub const MIN_RTO: i64 = 1000;
+/// Upper bound of the retransmission timeout in milliseconds.
+pub const MAX_RTO: i64 = 60000;
+/// Number of times a packet is sent again before it is given up. With the lower bound of
+/// the retransmission timeout a packet is given up after about five minutes.
+pub const MAX_RETRIES: u32 = 9;
+/// Clock granularity in milliseconds.
+const GRANULARITY: i64 = 1;
+
+/// Estimates the round-trip time of a peer and computes the retransmission timeout (RTO)
+/// as described in RFC 6298.
+pub struct RttEstimator {
+    /// Smoothed round-trip time in milliseconds.
+    srtt: Option<i64>,
+    /// Round-trip time variation in milliseconds.
+    rttvar: i64,
+    /// Lower bound of the retransmission timeout in milliseconds.
+    min_rto: i64,
+}
+
+impl RttEstimator {
+
+    /// Creates a new estimator. Until the first round-trip time has been measured the
+    /// retransmission timeout is `min_rto`.
+    pub fn new(min_rto: i64) -> RttEstimator {
+        RttEstimator {
+            srtt: None,
+            rttvar: 0,
+            min_rto,
+        }
+    }
+
+    /// Adds the round-trip time of a packet which has been acknowledged. Must not be called
+    /// for packets which have been sent more than once (Karn's algorithm).
+    pub fn sample(&mut self, rtt: i64) {
+        let rtt = rtt.max(0);
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            },
+            Some(srtt) => {
+                // alpha = 1/8, beta = 1/4
+                self.rttvar = (3 * self.rttvar + (srtt - rtt).abs()) / 4;
+                self.srtt = Some((7 * srtt + rtt) / 8);
+            }
+        }
+    }
+
+    /// Returns the smoothed round-trip time or None if no round-trip time has been measured.
+    pub fn srtt(&self) -> Option<i64> {
+        self.srtt
+    }
+
+    pub fn rttvar(&self) -> i64 {
+        self.rttvar
+    }
+
+    /// Returns the retransmission timeout in milliseconds.
+    pub fn rto(&self) -> i64 {
+        match self.srtt {
+            Some(srtt) => (srtt + GRANULARITY.max(4 * self.rttvar)).max(self.min_rto).min(MAX_RTO),
+            None => self.min_rto
+        }
+    }
+}
+
+/// Returns the time in milliseconds to wait for an ack after the given number of retries.
+/// Like in RFC 6298 the timeout is doubled with each retry up to `MAX_RTO`.
+pub fn backoff(rto: i64, retries: u32) -> i64 {
+    (rto << retries.min(16)).min(MAX_RTO)
+}
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::{backoff, RttEstimator, MIN_RTO, MAX_RTO, MAX_RETRIES};
+
+    #[test]
+    fn test_rto() {
+        let mut r = RttEstimator::new(MIN_RTO);
+        assert_eq!(r.srtt(), None);
+        assert_eq!(r.rto(), MIN_RTO);
+
+        r.sample(2000);
+        assert_eq!(r.srtt(), Some(2000));
+        assert_eq!(r.rttvar(), 1000);
+        assert_eq!(r.rto(), 6000);
+
+        r.sample(1200);
+        assert_eq!(r.rttvar(), 950);
+        assert_eq!(r.srtt(), Some(1900));
+        assert_eq!(r.rto(), 1900 + 4 * 950);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let mut r = RttEstimator::new(MIN_RTO);
+        r.sample(10);
+        assert_eq!(r.rto(), MIN_RTO);
+
+        let mut r = RttEstimator::new(100);
+        for _ in 0..10 {
+            r.sample(10);
+        }
+        assert_eq!(r.rto(), 100);
+
+        r.sample(100000);
+        assert_eq!(r.rto(), MAX_RTO);
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(MIN_RTO, 0), MIN_RTO);
+        assert_eq!(backoff(MIN_RTO, 1), 2 * MIN_RTO);
+        assert_eq!(backoff(MIN_RTO, 5), 32 * MIN_RTO);
+        assert_eq!(backoff(MIN_RTO, 6), MAX_RTO);
+        assert_eq!(backoff(MIN_RTO, 100), MAX_RTO);
+
+        // A peer which never answers is given up after about five minutes.
+        let total = (0..=MAX_RETRIES).map(|r| backoff(MIN_RTO, r)).sum::<i64>();
+        assert!((290000..=310000).contains(&total));
+    }
+}
@@ -0,0 +1,597 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crypto::curve25519::{curve25519, curve25519_base};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use crate::aead::{self, Aead, ResultVec};
+use crate::delivery::{push_slice, push_value, pop_value};
+use crate::rsa::RSA;
+
+/// Version of the envelope of handshake messages.
+///
+/// A handshake message has the following format:
+/// u8       : version { 4 }
+/// u8       : type { 1 = init, 2 = response }
+/// u64      : time in seconds since the epoch
+/// [u8; 32] : ephemeral X25519 public key of the sender
+/// [u8; 32] : ephemeral X25519 public key of the initiator (only for responses)
+/// Vec<u8>  : RSA signature of the sender over all previous fields and the SHA-256
+///            fingerprint of the public key of the recipient
+pub const HANDSHAKE: u8 = 4;
+
+/// Version of the envelope of data encrypted with a session key.
+///
+/// The envelope has the following format:
+/// u8      : version { 3 }
+/// [u8; 8] : session id
+/// u32     : epoch of the key
+/// Vec<u8> : envelope of ChaCha20-Poly1305 (see aead.rs)
+pub const SESSION: u8 = 3;
+
+const INIT: u8 = 1;
+const RESPONSE: u8 = 2;
+
+const KEY_LEN: usize = 32;
+const ID_LEN: usize = 8;
+
+/// Number of messages which are encrypted with the same key before the key is ratcheted.
+const RATCHET_MESSAGES: u32 = 50;
+/// Seconds after which a new session with new ephemeral keys is negotiated.
+const REKEY_INTERVAL: i64 = 3600;
+/// Seconds after which a handshake without response is sent again.
+const HANDSHAKE_RETRY: i64 = 30;
+/// Maximum difference in seconds between the clocks of two peers.
+const MAX_CLOCK_SKEW: i64 = 300;
+/// Number of sessions per peer which are kept to decrypt messages still in transit.
+const MAX_SESSIONS: usize = 3;
+/// Maximum number of epochs a received message may be ahead of the receiving chain.
+const MAX_SKIP: u32 = 1000;
+/// Number of message keys of older epochs which are kept by a receiving chain for messages
+/// which have been delayed or reordered, e.g. parts of a file which are sent at once.
+const MAX_SKIPPED_KEYS: usize = 20;
+
+/// A chain of keys for one direction of a session. After each step of the ratchet the
+/// previous key is deleted so that a compromised key does not reveal older messages. Only
+/// the message keys of the last epochs are kept to decrypt delayed messages.
+#[derive(Clone)]
+struct Chain {
+    epoch: u32,
+    key: Vec<u8>,
+    /// Number of messages encrypted with the key of the current epoch.
+    used: u32,
+    /// Message keys of older epochs indexed by their epoch.
+    skipped: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Chain {
+    fn new(key: Vec<u8>) -> Chain {
+        Chain {
+            epoch: 0,
+            key,
+            used: 0,
+            skipped: BTreeMap::new(),
+        }
+    }
+
+    fn message_key(&self) -> Vec<u8> {
+        aead::derive_key(&self.key, "stealthy message key", KEY_LEN)
+    }
+
+    fn ratchet(&mut self) {
+        self.skipped.insert(self.epoch, self.message_key());
+        while self.skipped.len() > MAX_SKIPPED_KEYS {
+            self.skipped.pop_first();
+        }
+        self.key = aead::derive_key(&self.key, "stealthy ratchet", KEY_LEN);
+        self.epoch += 1;
+        self.used = 0;
+    }
+
+    /// Returns the epoch and the key for the next message which is sent.
+    fn next(&mut self) -> (u32, Vec<u8>) {
+        if self.used >= RATCHET_MESSAGES {
+            self.ratchet();
+            self.skipped.clear();
+        }
+        self.used += 1;
+        (self.epoch, self.message_key())
+    }
+
+    /// Returns the key for a received message of the given epoch.
+    fn get(&mut self, epoch: u32) -> ResultVec {
+        if epoch < self.epoch {
+            return self.skipped.get(&epoch).cloned().ok_or("Key of message has already been deleted.");
+        }
+        if epoch - self.epoch > MAX_SKIP {
+            return Err("Invalid epoch.");
+        }
+        while self.epoch < epoch {
+            self.ratchet();
+        }
+        Ok(self.message_key())
+    }
+}
+
+/// A session with a peer established via a handshake.
+struct Session {
+    id: Vec<u8>,
+    created: i64,
+    send: Chain,
+    recv: Chain,
+    /// Key used to authenticate the headers of the packets exchanged in this session.
+    header_key: Vec<u8>,
+    /// True if the peer is known to have established the session, i.e. if we have
+    /// initiated the session or if the peer has already used it.
+    confirmed: bool,
+}
+
+impl Session {
+    /// Derives the keys of a session from the shared secret and the public keys of the
+    /// initiator and the responder.
+    fn new(shared: &[u8], init_pub: &[u8], resp_pub: &[u8], initiator: bool, now: i64) -> Session {
+        let mut secret = shared.to_vec();
+        secret.extend(init_pub);
+        secret.extend(resp_pub);
+        let root = aead::derive_key(&secret, "stealthy session", KEY_LEN);
+
+        let i2r = Chain::new(aead::derive_key(&root, "stealthy chain initiator", KEY_LEN));
+        let r2i = Chain::new(aead::derive_key(&root, "stealthy chain responder", KEY_LEN));
+        let (send, recv) = if initiator { (i2r, r2i) } else { (r2i, i2r) };
+
+        Session {
+            id: aead::derive_key(&root, "stealthy session id", ID_LEN),
+            created: now,
+            send,
+            recv,
+            header_key: aead::derive_key(&root, "stealthy header key", KEY_LEN),
+            confirmed: initiator,
+        }
+    }
+}
+
+/// Ephemeral key of a handshake which has been sent but not yet answered.
+struct Pending {
+    secret: Vec<u8>,
+    public: Vec<u8>,
+    sent: i64,
+}
+
+#[derive(Default)]
+struct Peer {
+    pending: Option<Pending>,
+    /// Established sessions. The newest session is the first one and is used to encrypt.
+    sessions: Vec<Session>,
+    /// Time of the last accepted handshake initiated by the peer.
+    last_init: i64,
+}
+
+impl Peer {
+    fn add(&mut self, s: Session) {
+        self.sessions.insert(0, s);
+        // Keys of old sessions are dropped.
+        self.sessions.truncate(MAX_SESSIONS);
+    }
+}
+
+/// Handshake message.
+struct Handshake {
+    typ: u8,
+    time: i64,
+    public: Vec<u8>,
+    initiator: Vec<u8>,
+    signed: Vec<u8>,
+    sig: Vec<u8>,
+}
+
+/// Forward secret sessions with peers.
+///
+/// Peers negotiate a session key via ephemeral X25519 keys which are authenticated by
+/// signatures with the RSA keys of the peers. The ephemeral keys are deleted once the
+/// session is established. Hence, a compromised RSA private key does not reveal messages
+/// of earlier sessions.
+pub struct Sessions {
+    /// Public keys of the peers in PEM format used to verify handshakes. The keys are
+    /// indexed by the IP of the peer.
+    pub_keys: HashMap<String, String>,
+    /// Own private key in PEM format used to sign handshakes.
+    priv_key: String,
+    /// Fingerprint of the own public key. Handshakes are only accepted if they are
+    /// signed for this key, i.e. a handshake for another peer cannot be replayed to us.
+    fingerprint: Vec<u8>,
+    peers: Mutex<HashMap<String, Peer>>,
+    established: Condvar,
+}
+
+impl Sessions {
+
+    pub fn new(pub_keys: HashMap<String, String>, priv_key: String) -> Result<Sessions, &'static str> {
+        // Check that the keys are valid.
+        for k in pub_keys.values() {
+            RSA::new(k, &priv_key)?;
+        }
+        Ok(Sessions {
+            pub_keys,
+            fingerprint: fingerprint(&RSA::public_key_of(&priv_key)?),
+            priv_key,
+            peers: Mutex::new(HashMap::new()),
+            established: Condvar::new(),
+        })
+    }
+
+    /// Returns a handshake message if a new session with the given peer is required, i.e.
+    /// if there is no session yet or if the current session is too old.
+    pub fn request(&self, ip: &str) -> Result<Option<Vec<u8>>, &'static str> {
+        self.request_at(ip, now())
+    }
+
+    fn request_at(&self, ip: &str, now: i64) -> Result<Option<Vec<u8>>, &'static str> {
+        let mut peers = self.peers.lock().expect("Lock failed.");
+        let peer = peers.entry(ip.to_string()).or_default();
+
+        let fresh = peer.sessions.first().is_some_and(|s| now - s.created < REKEY_INTERVAL);
+        let waiting = peer.pending.as_ref().is_some_and(|p| now - p.sent < HANDSHAKE_RETRY);
+        if fresh || waiting {
+            return Ok(None);
+        }
+
+        let secret = aead::random_u8(KEY_LEN)?;
+        let public = curve25519_base(&secret).to_vec();
+        let msg = self.handshake_message(ip, INIT, now, &public, &[])?;
+        peer.pending = Some(Pending { secret, public, sent: now });
+        Ok(Some(msg))
+    }
+
+    /// Waits until a session with the given peer has been established. Returns false if
+    /// no session could be established within the given time.
+    pub fn wait(&self, ip: &str, timeout: Duration) -> bool {
+        let peers = self.peers.lock().expect("Lock failed.");
+        let (peers, _) = self.established.wait_timeout_while(peers, timeout, |p| {
+            p.get(ip).is_none_or(|p| p.sessions.is_empty())
+        }).expect("Lock failed.");
+        peers.get(ip).is_some_and(|p| !p.sessions.is_empty())
+    }
+
+    /// Processes a handshake message received from a peer. Returns the response which has
+    /// to be sent back to the peer, if any.
+    pub fn handshake(&self, ip: &str, buf: &[u8]) -> Result<Option<Vec<u8>>, &'static str> {
+        self.handshake_at(ip, buf, now())
+    }
+
+    fn handshake_at(&self, ip: &str, buf: &[u8], now: i64) -> Result<Option<Vec<u8>>, &'static str> {
+        let h = Sessions::parse(buf, &self.fingerprint)?;
+        if !self.rsa(ip)?.verify(&h.signed, &h.sig) {
+            return Err("Invalid signature of handshake.");
+        }
+        if (now - h.time).abs() > MAX_CLOCK_SKEW {
+            return Err("Handshake is too old. Maybe the clocks are not synchronized.");
+        }
+
+        let mut peers = self.peers.lock().expect("Lock failed.");
+        let peer = peers.entry(ip.to_string()).or_default();
+
+        let r = match h.typ {
+            INIT => {
+                // Prevent replays of older handshakes and of the last one.
+                if h.time <= peer.last_init {
+                    return Err("Replayed handshake.");
+                }
+                let secret = aead::random_u8(KEY_LEN)?;
+                let public = curve25519_base(&secret).to_vec();
+                let shared = Sessions::shared(&secret, &h.public)?;
+                let msg = self.handshake_message(ip, RESPONSE, now, &public, &h.public)?;
+                peer.last_init = h.time;
+                peer.add(Session::new(&shared, &h.public, &public, false, now));
+                Some(msg)
+            },
+            _ => {
+                let pending = match &peer.pending {
+                    Some(p) if p.public == h.initiator => p,
+                    _ => return Err("Unexpected handshake response.")
+                };
+                let shared = Sessions::shared(&pending.secret, &h.public)?;
+                let s = Session::new(&shared, &pending.public, &h.public, true, now);
+                peer.pending = None;
+                peer.add(s);
+                None
+            }
+        };
+        self.established.notify_all();
+        Ok(r)
+    }
+
+    /// Encrypts the data with the current session key for the given peer. If `compress` is
+    /// true the data is compressed before if it shrinks.
+    pub fn encrypt(&self, ip: &str, data: &[u8], compress: bool) -> ResultVec {
+        let mut peers = self.peers.lock().expect("Lock failed.");
+        let s = match peers.get_mut(ip).and_then(|p| p.sessions.first_mut()) {
+            Some(s) => s,
+            None => return Err("No session with peer.")
+        };
+        let (epoch, key) = s.send.next();
+
+        let mut v = vec![SESSION];
+        push_slice(&mut v, &s.id);
+        push_value(&mut v, epoch as u64, 4);
+        let aead = Aead::from_key(key)?;
+        push_slice(&mut v, &match compress {
+            true => aead.encrypt_compressed(data)?,
+            false => aead.encrypt(data)?
+        });
+        Ok(v)
+    }
+
+    /// Decrypts data received from the given peer.
+    pub fn decrypt(&self, ip: &str, data: &[u8]) -> ResultVec {
+        if data.len() < 1 + ID_LEN + 4 || data[0] != SESSION {
+            return Err("Invalid session envelope.");
+        }
+        let (id, rest) = data[1..].split_at(ID_LEN);
+        let mut epoch = rest[..4].to_vec();
+        let epoch = pop_value(&mut epoch, 4)? as u32;
+
+        let mut peers = self.peers.lock().expect("Lock failed.");
+        let s = match peers.get_mut(ip).and_then(|p| p.sessions.iter_mut().find(|s| s.id == id)) {
+            Some(s) => s,
+            None => return Err("Unknown session. The peer might have been restarted.")
+        };
+
+        // The chain is only updated if the message is authentic.
+        let mut chain = s.recv.clone();
+        let plain = Aead::from_key(chain.get(epoch)?)?.decrypt(&rest[4..])?;
+        s.recv = chain;
+        s.confirmed = true;
+        Ok(plain)
+    }
+
+    /// Returns the key which authenticates the headers of packets sent to the given peer.
+    /// It is the key of the newest session which the peer is known to have. Returns None
+    /// if there is no such session.
+    pub fn header_key(&self, ip: &str) -> Option<Vec<u8>> {
+        let peers = self.peers.lock().expect("Lock failed.");
+        peers.get(ip)?.sessions.iter().find(|s| s.confirmed).map(|s| s.header_key.clone())
+    }
+
+    /// Checks whether the header of a packet received from the given peer is authenticated
+    /// with the key of one of the sessions with the peer and returns this key. Returns
+    /// Some(None) if no key matches and None if there is no session with the peer.
+    pub fn verify_header<F: Fn(&[u8]) -> bool>(&self, ip: &str, verify: F) -> Option<Option<Vec<u8>>> {
+        let mut peers = self.peers.lock().expect("Lock failed.");
+        let sessions = &mut peers.get_mut(ip)?.sessions;
+        if sessions.is_empty() {
+            return None;
+        }
+        match sessions.iter_mut().find(|s| verify(&s.header_key)) {
+            Some(s) => {
+                // The peer has used the session.
+                s.confirmed = true;
+                Some(Some(s.header_key.clone()))
+            },
+            None => Some(None)
+        }
+    }
+
+    fn shared(secret: &[u8], public: &[u8]) -> ResultVec {
+        let shared = curve25519(secret, public);
+        // Reject public keys of low order which result in a predictable secret.
+        match shared.iter().all(|x| *x == 0) {
+            true => Err("Invalid public key."),
+            false => Ok(shared.to_vec())
+        }
+    }
+
+    /// Returns the RSA keys used for handshakes with the given peer.
+    fn rsa(&self, ip: &str) -> Result<RSA, &'static str> {
+        match self.pub_keys.get(ip) {
+            Some(k) => RSA::new(k, &self.priv_key),
+            None => Err("No public key for peer.")
+        }
+    }
+
+    fn handshake_message(&self, ip: &str, typ: u8, now: i64, public: &[u8], initiator: &[u8]) -> ResultVec {
+        let mut v = vec![HANDSHAKE, typ];
+        push_value(&mut v, now as u64, 8);
+        push_slice(&mut v, public);
+        push_slice(&mut v, initiator);
+        let rsa = self.rsa(ip)?;
+        let sig = rsa.sign(&Sessions::signed_data(&fingerprint(&rsa.public_key()?), &v))?;
+        push_slice(&mut v, &sig);
+        Ok(v)
+    }
+
+    /// Returns the data which is signed. A context string makes sure that a signature of a
+    /// handshake cannot be used for anything else. The fingerprint of the public key of
+    /// the recipient binds the handshake to the recipient.
+    fn signed_data(recipient: &[u8], v: &[u8]) -> Vec<u8> {
+        let mut r = b"stealthy handshake".to_vec();
+        r.extend(recipient);
+        r.extend(v);
+        r
+    }
+
+    /// Parses a handshake message sent to the recipient with the given fingerprint.
+    fn parse(buf: &[u8], recipient: &[u8]) -> Result<Handshake, &'static str> {
+        if buf.len() < 2 + 8 + KEY_LEN || buf[0] != HANDSHAKE {
+            return Err("Invalid handshake.");
+        }
+        let typ = buf[1];
+        let n = match typ {
+            INIT => 2 + 8 + KEY_LEN,
+            RESPONSE => 2 + 8 + 2 * KEY_LEN,
+            _ => return Err("Invalid handshake type.")
+        };
+        if buf.len() <= n {
+            return Err("Invalid handshake.");
+        }
+        let mut time = buf[2..10].to_vec();
+        Ok(Handshake {
+            typ,
+            time: pop_value(&mut time, 8)? as i64,
+            public: buf[10..10 + KEY_LEN].to_vec(),
+            initiator: buf[10 + KEY_LEN..n].to_vec(),
+            signed: Sessions::signed_data(recipient, &buf[..n]),
+            sig: buf[n..].to_vec(),
+        })
+    }
+}
+
+/// Returns the SHA-256 fingerprint of a public key in DER format.
+fn fingerprint(der: &[u8]) -> Vec<u8> {
+    let mut h = Sha256::new();
+    h.input(der);
+    let mut digest = vec![0; h.output_bytes()];
+    h.result(&mut digest);
+    digest
+}
+
+fn now() -> i64 {
+    time::get_time().sec
+}
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::{Sessions, HANDSHAKE, MAX_SKIPPED_KEYS, RATCHET_MESSAGES, REKEY_INTERVAL, MAX_CLOCK_SKEW};
+    use crate::tools::read_file;
+
+    /// Creates the sessions of two peers a (10.0.0.1) and b (10.0.0.2).
+    fn peers() -> (Sessions, Sessions) {
+        let key = |f: &str| read_file(&format!("tests/keys/{}", f)).unwrap();
+        let keys = |ip: &str, f: &str| vec![(ip.to_string(), key(f))].into_iter().collect::<HashMap<_, _>>();
+        (
+            Sessions::new(keys("10.0.0.2", "rsa_pub2.pem"), key("rsa_priv.pem")).unwrap(),
+            Sessions::new(keys("10.0.0.1", "rsa_pub.pem"), key("rsa_priv2.pem")).unwrap(),
+        )
+    }
+
+    /// Runs a handshake initiated by a at the given time.
+    fn connect(a: &Sessions, b: &Sessions, now: i64) {
+        let init = a.request_at("10.0.0.2", now).unwrap().unwrap();
+        let resp = b.handshake_at("10.0.0.1", &init, now).unwrap().unwrap();
+        assert!(a.handshake_at("10.0.0.2", &resp, now).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (a, b) = peers();
+        assert!(a.encrypt("10.0.0.2", b"hello", false).is_err());
+        assert!(!a.wait("10.0.0.2", Duration::from_millis(10)));
+
+        connect(&a, &b, super::now());
+        assert!(a.wait("10.0.0.2", Duration::from_millis(10)));
+        assert!(b.wait("10.0.0.1", Duration::from_millis(10)));
+
+        let c = a.encrypt("10.0.0.2", b"hello", false).unwrap();
+        assert_eq!(b.decrypt("10.0.0.1", &c).unwrap(), b"hello".to_vec());
+        let c = b.encrypt("10.0.0.1", b"hi", false).unwrap();
+        assert_eq!(a.decrypt("10.0.0.2", &c).unwrap(), b"hi".to_vec());
+
+        // Keys are bound to the peer.
+        assert!(b.decrypt("10.0.0.3", &c).is_err());
+        // Handshakes require a public key of the peer.
+        assert!(a.request("10.0.0.3").is_err());
+        // A session does not need a new handshake.
+        assert!(a.request("10.0.0.2").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_forged_handshake() {
+        let (a, b) = peers();
+        let mut init = a.request("10.0.0.2").unwrap().unwrap();
+        assert_eq!(init[0], HANDSHAKE);
+
+        // The handshake is signed by a and cannot be verified with another key.
+        assert!(a.handshake("10.0.0.2", &init).is_err());
+        // The ephemeral key must not be replaced.
+        init[20] ^= 1;
+        assert!(b.handshake("10.0.0.1", &init).is_err());
+    }
+
+    #[test]
+    fn test_handshake_for_other_peer() {
+        let (a, b) = peers();
+        let key = |f: &str| read_file(&format!("tests/keys/{}", f)).unwrap();
+        // c knows the public key of a as well.
+        let c = Sessions::new(vec![("10.0.0.1".to_string(), key("rsa_pub.pem"))].into_iter().collect(), key("rsa_priv3.pem")).unwrap();
+
+        // A handshake of a for b cannot be replayed to c.
+        let init = a.request("10.0.0.2").unwrap().unwrap();
+        assert!(c.handshake("10.0.0.1", &init).is_err());
+        assert!(b.handshake("10.0.0.1", &init).is_ok());
+    }
+
+    #[test]
+    fn test_replayed_handshake() {
+        let (a, b) = peers();
+        let now = super::now();
+
+        let old = a.request_at("10.0.0.2", now - MAX_CLOCK_SKEW - 1).unwrap().unwrap();
+        assert!(b.handshake_at("10.0.0.1", &old, now).is_err());
+
+        let first = a.request_at("10.0.0.2", now - 100).unwrap().unwrap();
+        let second = a.request_at("10.0.0.2", now).unwrap().unwrap();
+        let resp = b.handshake_at("10.0.0.1", &second, now).unwrap().unwrap();
+        assert!(b.handshake_at("10.0.0.1", &first, now).is_err());
+        assert!(a.handshake_at("10.0.0.2", &resp, now).unwrap().is_none());
+
+        // Replays of the last handshake do not replace the session.
+        for _ in 0..5 {
+            assert!(b.handshake_at("10.0.0.1", &second, now).is_err());
+        }
+        let c = a.encrypt("10.0.0.2", b"hello", false).unwrap();
+        assert_eq!(b.decrypt("10.0.0.1", &c).unwrap(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn test_ratchet() {
+        let (a, b) = peers();
+        connect(&a, &b, super::now());
+
+        let v = (0..3 * RATCHET_MESSAGES).map(|i| a.encrypt("10.0.0.2", &[i as u8], false).unwrap()).collect::<Vec<_>>();
+
+        // The epoch increases after some messages.
+        assert_eq!(v[0][9..13], [0, 0, 0, 0]);
+        assert_eq!(v[v.len() - 1][9..13], [2, 0, 0, 0]);
+
+        // Messages can be received in a different order as long as they are not too old.
+        let last = (RATCHET_MESSAGES * 2) as usize;
+        assert!(b.decrypt("10.0.0.1", &v[last]).is_ok());
+        assert!(b.decrypt("10.0.0.1", &v[last - 1]).is_ok());
+        // A message from two epochs back is decrypted after newer ones.
+        assert_eq!(b.decrypt("10.0.0.1", &v[0]).unwrap(), vec![0]);
+        assert_eq!(b.decrypt("10.0.0.1", &v[1]).unwrap(), vec![1]);
+
+        // Keys of epochs which are too old have been deleted.
+        let n = (MAX_SKIPPED_KEYS as u32 + 1) * RATCHET_MESSAGES;
+        let w = (0..n).map(|i| a.encrypt("10.0.0.2", &[i as u8], false).unwrap()).collect::<Vec<_>>();
+        assert!(b.decrypt("10.0.0.1", &w[w.len() - 1]).is_ok());
+        assert!(b.decrypt("10.0.0.1", &v[last]).is_err());
+        assert!(b.decrypt("10.0.0.1", &w[0]).is_ok());
+    }
+
+    #[test]
+    fn test_rekey() {
+        let (a, b) = peers();
+        let now = super::now();
+        connect(&a, &b, now - REKEY_INTERVAL - 1);
+        let old = a.encrypt("10.0.0.2", b"old", false).unwrap();
+
+        // A new session is negotiated after some time.
+        connect(&a, &b, now);
+        let new = a.encrypt("10.0.0.2", b"new", false).unwrap();
+        assert!(old[1..9] != new[1..9]);
+
+        // Messages of the previous session which are still in transit can be decrypted.
+        assert_eq!(b.decrypt("10.0.0.1", &new).unwrap(), b"new".to_vec());
+        assert_eq!(b.decrypt("10.0.0.1", &old).unwrap(), b"old".to_vec());
+    }
+}
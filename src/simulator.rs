@@ -0,0 +1,341 @@
+use std::sync::Mutex;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::transport::{Datagram, Transport};
+
+/// Describes how hostile a simulated network is.
+#[derive(Clone)]
+pub struct Impairments {
+    /// Probability that a datagram is dropped.
+    pub drop_rate: f64,
+    /// Probability that a datagram is delivered twice.
+    pub duplicate_rate: f64,
+    /// Probability that a datagram is held back so that it overtakes later datagrams.
+    pub reorder_rate: f64,
+    /// Base delay of each datagram in milliseconds.
+    pub delay: u64,
+    /// Maximum random delay in milliseconds which is added to the base delay.
+    pub jitter: u64,
+    /// Datagrams larger than this value are dropped.
+    pub mtu: usize,
+    /// Seed of the random number generator which drives all decisions.
+    pub seed: u64,
+}
+
+impl Impairments {
+    /// Creates a network without any impairments.
+    pub fn new(seed: u64) -> Impairments {
+        Impairments {
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            reorder_rate: 0.0,
+            delay: 0,
+            jitter: 0,
+            mtu: usize::MAX,
+            seed,
+        }
+    }
+
+    pub fn drop_rate(mut self, p: f64) -> Impairments {
+        self.drop_rate = p;
+        self
+    }
+
+    pub fn duplicate_rate(mut self, p: f64) -> Impairments {
+        self.duplicate_rate = p;
+        self
+    }
+
+    pub fn reorder_rate(mut self, p: f64) -> Impairments {
+        self.reorder_rate = p;
+        self
+    }
+
+    pub fn delay(mut self, millis: u64, jitter: u64) -> Impairments {
+        self.delay = millis;
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn mtu(mut self, n: usize) -> Impairments {
+        self.mtu = n;
+        self
+    }
+}
+
+/// A transport which impairs all outgoing datagrams of another transport.
+pub struct ImpairedTransport {
+    inner: Arc<dyn Transport>,
+    impairments: Impairments,
+    rng: Mutex<StdRng>,
+}
+
+impl ImpairedTransport {
+    pub fn new(inner: Box<dyn Transport>, impairments: Impairments) -> ImpairedTransport {
+        ImpairedTransport {
+            inner: Arc::from(inner),
+            rng: Mutex::new(StdRng::seed_from_u64(impairments.seed)),
+            impairments,
+        }
+    }
+
+    /// Returns the delays in milliseconds for each copy of a datagram of the given size
+    /// which is sent over the network. An empty vector means that the datagram is lost.
+    fn schedule(&self, len: usize) -> Vec<u64> {
+        let i = &self.impairments;
+        let mut rng = self.rng.lock().expect("Lock failed.");
+
+        if len > i.mtu || rng.gen::<f64>() < i.drop_rate {
+            return vec![];
+        }
+
+        let copies = if rng.gen::<f64>() < i.duplicate_rate { 2 } else { 1 };
+
+        (0..copies).map(|_| {
+            let mut delay = i.delay;
+            if i.jitter > 0 {
+                delay += rng.gen_range(0, i.jitter + 1);
+            }
+            if rng.gen::<f64>() < i.reorder_rate {
+                // Hold the datagram back long enough that following datagrams overtake it.
+                delay += i.jitter + 50;
+            }
+            delay
+        }).collect()
+    }
+
+    /// Passes each copy of the datagram to `f` after its delay.
+    fn transmit<F>(&self, ip: &str, buf: &[u8], f: F)
+        where F: Fn(&dyn Transport, &str, &[u8]) + Send + Clone + 'static {
+
+        for delay in self.schedule(buf.len()) {
+            if delay == 0 {
+                f(&*self.inner, ip, buf);
+            } else {
+                let inner = self.inner.clone();
+                let ip = ip.to_string();
+                let buf = buf.to_vec();
+                let f = f.clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(delay));
+                    f(&*inner, &ip, &buf);
+                });
+            }
+        }
+    }
+}
+
+impl Transport for ImpairedTransport {
+
+    fn send(&self, ip: &str, buf: &[u8]) -> bool {
+        self.transmit(ip, buf, |t, ip, buf| { t.send(ip, buf); });
+        // The sender cannot see whether a datagram gets lost.
+        true
+    }
+
+    fn reply(&self, ip: &str, token: u32, buf: &[u8]) -> bool {
+        self.transmit(ip, buf, move |t, ip, buf| { t.reply(ip, token, buf); });
+        true
+    }
+
+    fn listen(&self, tx: Sender<Datagram>) -> Result<(), &'static str> {
+        self.inner.listen(tx)
+    }
+
+    fn max_payload(&self) -> usize {
+        self.inner.max_payload()
+    }
+}
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::mpsc::{channel, Receiver};
+    use std::time::Duration;
+
+    use super::{Impairments, ImpairedTransport};
+    use crate::console::{Console, ConsoleMessage};
+    use crate::iptools::IpAddresses;
+    use crate::layer::{Layer, Layers};
+    use crate::loopback::LoopbackBus;
+    use crate::message::{IncomingMessage, Message};
+    use crate::peers::Peers;
+    use crate::transfer::Transfer;
+    use crate::transport::Transport;
+
+    const KEY: &str = "11111111111111111111111111111111";
+
+    fn receiver(bus: &LoopbackBus, ip: &str) -> Receiver<crate::transport::Datagram> {
+        let (tx, rx) = channel();
+        bus.connect(ip).listen(tx).unwrap();
+        rx
+    }
+
+    #[test]
+    fn test_same_seed_same_decisions() {
+        let i = Impairments::new(42).drop_rate(0.5).duplicate_rate(0.3).delay(1, 20).reorder_rate(0.2);
+        let bus = LoopbackBus::new();
+        let a = ImpairedTransport::new(Box::new(bus.connect("10.0.0.1")), i.clone());
+        let b = ImpairedTransport::new(Box::new(bus.connect("10.0.0.1")), i);
+
+        let x = (0..100).map(|_| a.schedule(10)).collect::<Vec<_>>();
+        let y = (0..100).map(|_| b.schedule(10)).collect::<Vec<_>>();
+        assert_eq!(x, y);
+        // Some datagrams are lost, some are duplicated.
+        assert!(x.iter().any(|v| v.is_empty()));
+        assert!(x.iter().any(|v| v.len() == 2));
+    }
+
+    #[test]
+    fn test_drop_and_duplicate() {
+        let bus = LoopbackBus::new();
+        let rx = receiver(&bus, "10.0.0.2");
+
+        let t = ImpairedTransport::new(Box::new(bus.connect("10.0.0.1")), Impairments::new(1).drop_rate(1.0));
+        assert!(t.send("10.0.0.2", b"lost"));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        let t = ImpairedTransport::new(Box::new(bus.connect("10.0.0.1")), Impairments::new(1).duplicate_rate(1.0));
+        t.send("10.0.0.2", b"twice");
+        assert_eq!(rx.recv_timeout(Duration::from_millis(100)).unwrap().buf, b"twice".to_vec());
+        assert_eq!(rx.recv_timeout(Duration::from_millis(100)).unwrap().buf, b"twice".to_vec());
+    }
+
+    #[test]
+    fn test_mtu() {
+        let bus = LoopbackBus::new();
+        let rx = receiver(&bus, "10.0.0.2");
+
+        let t = ImpairedTransport::new(Box::new(bus.connect("10.0.0.1")), Impairments::new(1).mtu(4));
+        t.send("10.0.0.2", b"abcde");
+        t.send("10.0.0.2", b"abcd");
+        assert_eq!(rx.recv_timeout(Duration::from_millis(100)).unwrap().buf, b"abcd".to_vec());
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_reorder() {
+        let bus = LoopbackBus::new();
+        let rx = receiver(&bus, "10.0.0.2");
+
+        let t = ImpairedTransport::new(Box::new(bus.connect("10.0.0.1")), Impairments::new(1).reorder_rate(1.0));
+        t.send("10.0.0.2", b"first");
+        ImpairedTransport::new(Box::new(bus.connect("10.0.0.1")), Impairments::new(1)).send("10.0.0.2", b"second");
+        assert_eq!(rx.recv_timeout(Duration::from_millis(500)).unwrap().buf, b"second".to_vec());
+        assert_eq!(rx.recv_timeout(Duration::from_millis(500)).unwrap().buf, b"first".to_vec());
+    }
+
+    // ------------------------------------------------------------------------
+    // Tests of the full layer stack on a hostile network.
+
+    struct Peer {
+        layer: Layer,
+        _console: Receiver<ConsoleMessage>,
+    }
+
+    fn peer(bus: &LoopbackBus, ip: &str, peers: &str, i: Impairments) -> Peer {
+        let (tx, rx) = channel();
+        let layer = Layers::symmetric(
+            KEY,
+            &Peers::new(),
+            Box::new(ImpairedTransport::new(Box::new(bus.connect(ip)), i)),
+            Console::new(tx),
+            &IpAddresses::from_comma_list(peers)
+        ).unwrap();
+        layer.layers.set_min_rto(200);
+        // Packets are lost often on this network.
+        layer.layers.set_max_retries(20);
+
+        Peer {
+            layer,
+            _console: rx,
+        }
+    }
+
+    fn hostile(seed: u64) -> Impairments {
+        Impairments::new(seed)
+            .drop_rate(0.2)
+            .duplicate_rate(0.1)
+            .reorder_rate(0.2)
+            .delay(1, 10)
+    }
+
+    /// Collects all messages received by the peer within the given time.
+    fn collect(p: &Peer, millis: u64) -> Vec<IncomingMessage> {
+        let mut r = vec![];
+        while let Ok(m) = p.layer.rx.recv_timeout(Duration::from_millis(millis)) {
+            match m {
+                IncomingMessage::AckProgress(_, _, _) | IncomingMessage::Retry(_) => { },
+                m => r.push(m)
+            }
+        }
+        r
+    }
+
+    #[test]
+    fn test_messages_on_lossy_network() {
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", hostile(1));
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1", hostile(2));
+
+        for id in 0..20 {
+            let txt = format!("message {}", id);
+            a.layer.layers.send(Message::new("10.0.0.2".to_string(), txt.into_bytes()), id, true);
+        }
+
+        // Each message is received exactly once.
+        let mut received = collect(&b, 3000).into_iter().map(|m| match m {
+            IncomingMessage::New(m) => String::from_utf8(m.buf).unwrap(),
+            _ => panic!("Expected new message.")
+        }).collect::<Vec<_>>();
+        received.sort();
+        let mut expected = (0..20).map(|id| format!("message {}", id)).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(received, expected);
+
+        // Each message is acknowledged exactly once.
+        let mut acks = collect(&a, 3000).into_iter().map(|m| match m {
+            IncomingMessage::Ack(id) => id,
+            _ => panic!("Expected ack.")
+        }).collect::<Vec<_>>();
+        acks.sort();
+        assert_eq!(acks, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_file_upload_on_lossy_network() {
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", hostile(3).mtu(1000));
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1", hostile(4).mtu(1000));
+
+        let data = (0..5000).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+        let chunk = Transfer::Chunk(vec![1; 32], 1, data);
+        a.layer.layers.send(Message::file_upload("10.0.0.2".to_string(), &chunk), 1, true);
+
+        let r = collect(&b, 10000);
+        assert_eq!(r.len(), 1);
+        match &r[0] {
+            IncomingMessage::FileUpload(m) => {
+                assert_eq!(m.get_transfer().unwrap(), chunk);
+            },
+            _ => panic!("Expected file upload.")
+        }
+
+        let acks = collect(&a, 3000);
+        assert_eq!(acks.len(), 1);
+        match acks[0] {
+            IncomingMessage::Ack(id) => assert_eq!(id, 1),
+            _ => panic!("Expected ack.")
+        }
+    }
+}
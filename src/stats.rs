@@ -0,0 +1,34 @@
+/// Statistics about the packets received from the network.
+pub struct Stats {
+    /// Number of packets which have been dropped because they are not authentic.
+    pub rejected_packets: u64,
+    /// Number of parts of messages which have been dropped because of an invalid header.
+    pub rejected_fragments: u64,
+    /// Quality of the connection to each peer to which packets have been sent.
+    pub links: Vec<LinkStats>,
+}
+
+/// Quality of the connection to a peer. All times are in milliseconds.
+pub struct LinkStats {
+    pub ip: String,
+    /// Smoothed round-trip time or None if no round-trip time has been measured.
+    pub srtt: Option<i64>,
+    /// Variation of the round-trip time.
+    pub rttvar: i64,
+    /// Retransmission timeout.
+    pub rto: i64,
+    /// Congestion window in packets.
+    pub window: usize,
+    /// Number of packets which are not acknowledged.
+    pub in_flight: usize,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            rejected_packets: 0,
+            rejected_fragments: 0,
+            links: vec![],
+        }
+    }
+}
@@ -3,7 +3,6 @@ use crypto::digest::Digest;
 
 use std::io::Read;
 use std::io::Write;
-use rand::{thread_rng, Rng};
 use std::fs::{File, OpenOptions};
 
 #[allow(dead_code)]
@@ -16,15 +15,6 @@ pub fn log_to_file(s: String) {
     }
 }
 
-pub fn without_dirs(fname: &str) -> String {
-
-    let mut parts: Vec<&str> = fname.split("/").collect();
-    parts
-        .pop()
-        .expect("expected one element in vector")
-        .to_string()
-}
-
 pub fn decode_uptime(t: i64) -> String {
 
     let days = t / 86400;
@@ -39,32 +29,6 @@ pub fn decode_uptime(t: i64) -> String {
     }
 }
 
-pub fn write_data(fname: &str, data: Vec<u8>) -> bool {
-    match File::create(fname) {
-        Ok(mut f) => {
-            f.write_all(&data).is_ok()
-        },
-        _ => false
-    }
-}
-
-pub fn read_bin_file(fname: &str) -> Result<Vec<u8>, String> {
-
-    let r = File::open(fname);
-    match r {
-        Ok(mut file) => {
-            let mut v : Vec<u8> = vec![];
-            match file.read_to_end(&mut v) {
-                Ok(_) => Ok(v),
-                _ => Err(format!("Could not read file {}", fname))
-            }
-
-        }
-        _ => Err(format!("Could not open file '{}' for reading.", fname))
-    }
-}
-
-
 pub fn read_file(fname: &str) -> Result<String, &'static str> {
 
     let r = File::open(fname);
@@ -100,7 +64,9 @@ pub fn rot13(c: char) -> char {
     x.find(c).map_or(' ', |p| y.chars().nth(p).expect("ROT13 error"))
 }
 
+#[cfg(test)]
 pub fn random_str(n: usize) -> String {
+    use rand::{thread_rng, Rng};
     let chars = b"abcdefghijklmnopqrstuvwxyz0123456789";
     let mut rng = thread_rng();
     String::from_utf8(
@@ -108,6 +74,10 @@ pub fn random_str(n: usize) -> String {
     ).unwrap()
 }
 
+pub fn to_hex(v: &[u8]) -> String {
+    v.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
 pub fn sha1(s: &[u8]) -> String {
     let mut h = Sha1::new();
     h.input(s);
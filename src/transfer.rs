@@ -0,0 +1,862 @@
+use std::convert::TryFrom;
+use std::fs::{self, File, Metadata};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use crate::archive::Archive;
+use crate::delivery::{push_slice, push_value};
+use crate::download::{DownloadOptions, Downloads};
+use crate::layer::Layers;
+use crate::message::IncomingMessage;
+use crate::tools::to_hex;
+use crate::upload::Uploads;
+use crate::Console;
+
+/// Number of bytes of a file which are sent in a single message. An interrupted transfer is
+/// resumed at the granularity of these parts.
+pub const CHUNK_SIZE: usize = 4096;
+
+const VERSION: u8 = 1;
+const HASH_LEN: usize = 32;
+
+const OFFER: u8 = 1;
+const MISSING: u8 = 2;
+const CHUNK: u8 = 3;
+const REJECT: u8 = 4;
+const CANCEL: u8 = 5;
+const PAUSE: u8 = 6;
+
+/// File type bits of a mode which mark a directory.
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+/// Description of a file which is transferred.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileInfo {
+    /// SHA-256 hash of the content. It identifies the transfer, i.e. sending the same
+    /// content again to a peer resumes the previous transfer.
+    pub hash: Vec<u8>,
+    pub name: String,
+    pub size: u64,
+    /// Time of the last modification in seconds since the epoch.
+    pub mtime: u64,
+    /// Permissions of the file. An archive of several files is offered with the mode of a
+    /// directory.
+    pub mode: u32,
+}
+
+impl FileInfo {
+
+    /// Describes the given regular file. The file is read in parts to compute the hash.
+    fn read(f: &mut File, name: String) -> Result<FileInfo, &'static str> {
+        let meta = f.metadata().map_err(|_| "Could not read file.")?;
+        if !meta.is_file() {
+            return Err("Not a regular file.");
+        }
+        let (hash, size) = sha256(f)?;
+        chunk_count(size).ok_or("File is too large.")?;
+        Ok(FileInfo {
+            hash,
+            name,
+            size,
+            mtime: mtime(&meta),
+            mode: meta.permissions().mode(),
+        })
+    }
+
+    /// Returns true if the file is an archive of several files and directories.
+    pub fn is_archive(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    /// Returns the hash in hex.
+    pub fn id(&self) -> String {
+        to_hex(&self.hash)
+    }
+
+    /// Returns the number of parts of the file. An empty file consists of one empty part.
+    /// The size of a file with more parts than fit into a sequence number is rejected when
+    /// the file is read or offered, hence the number does not overflow.
+    pub fn chunks(&self) -> u32 {
+        chunk_count(self.size).unwrap_or(u32::MAX)
+    }
+
+    /// Returns the offset and the length of the part with the given sequence number or None
+    /// if there is no such part. The first part has the sequence number 1.
+    pub fn chunk_range(&self, seq: u32) -> Option<(u64, usize)> {
+        if seq == 0 || seq > self.chunks() {
+            return None;
+        }
+        let offset = (seq - 1) as u64 * CHUNK_SIZE as u64;
+        Some((offset, (self.size - offset).min(CHUNK_SIZE as u64) as usize))
+    }
+}
+
+/// Returns the number of parts of a file with the given size or None if the sequence
+/// numbers of the parts do not fit into a u32.
+pub fn chunk_count(size: u64) -> Option<u32> {
+    let n = size.div_ceil(CHUNK_SIZE as u64);
+    u32::try_from(n.max(1)).ok()
+}
+
+/// Returns the time of the last modification in seconds since the epoch.
+pub fn mtime(meta: &Metadata) -> u64 {
+    meta.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Returns the SHA-256 hash and the number of bytes of the data returned by the reader.
+pub fn sha256(r: &mut dyn Read) -> Result<(Vec<u8>, u64), &'static str> {
+    let mut sha2 = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        match r.read(&mut buf).map_err(|_| "Could not read file.")? {
+            0 => break,
+            n => {
+                sha2.input(&buf[..n]);
+                size += n as u64;
+            }
+        }
+    }
+    let mut hash = vec![0; HASH_LEN];
+    sha2.result(&mut hash);
+    Ok((hash, size))
+}
+
+/// The content which is sent by a file transfer.
+///
+/// A regular file is sent as it is. A directory or all files and directories which match a
+/// pattern with the wildcards `*`, `?` or `[` are sent as an archive. A pattern is named
+/// after the directory in which it is expanded.
+pub struct Content {
+    name: String,
+    data: Data,
+}
+
+enum Data {
+    File(File),
+    Archive(Archive, u64, u32),
+}
+
+impl Content {
+
+    pub fn open(path: &str) -> Result<Content, &'static str> {
+        let p = Path::new(path);
+        if !p.exists() && path.contains(['*', '?', '[']) {
+            return Content::pattern(path);
+        }
+
+        let meta = fs::metadata(p).map_err(|_| "Could not open file for reading.")?;
+        let data = match meta.is_dir() {
+            true => Data::Archive(Archive::from_dir(p)?, mtime(&meta), meta.permissions().mode()),
+            false => Data::File(File::open(p).map_err(|_| "Could not open file for reading.")?)
+        };
+        Ok(Content {
+            name: base_name(p)?,
+            data,
+        })
+    }
+
+    fn pattern(pattern: &str) -> Result<Content, &'static str> {
+        let paths = glob::glob(pattern).map_err(|_| "Invalid pattern.")?
+            .filter_map(|p| p.ok())
+            .collect::<Vec<_>>();
+        if paths.is_empty() {
+            return Err("No file matches the pattern.");
+        }
+        let latest = paths.iter()
+            .filter_map(|p| fs::metadata(p).ok())
+            .map(|m| mtime(&m))
+            .max()
+            .unwrap_or(0);
+        let dir = match Path::new(pattern).parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new(".")
+        };
+        Ok(Content {
+            name: base_name(dir)?,
+            data: Data::Archive(Archive::new(&paths)?, latest, S_IFDIR | 0o755),
+        })
+    }
+
+    /// Describes the content. The content is read completely to compute its hash.
+    pub fn info(&mut self) -> Result<FileInfo, &'static str> {
+        match &mut self.data {
+            Data::File(f) => FileInfo::read(f, self.name.clone()),
+            Data::Archive(a, mtime, mode) => {
+                let (hash, size) = sha256(&mut a.reader())?;
+                Ok(FileInfo { hash, name: self.name.clone(), size, mtime: *mtime, mode: *mode })
+            }
+        }
+    }
+
+    /// Reads `len` bytes at the given offset.
+    pub fn read_chunk(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, &'static str> {
+        let mut buf = vec![0; len];
+        match &mut self.data {
+            Data::File(f) => f.seek(SeekFrom::Start(offset))
+                .and_then(|_| f.read_exact(&mut buf))
+                .map_err(|_| "Could not read file.")?,
+            Data::Archive(a, _, _) => a.read_at(offset, &mut buf)?
+        }
+        Ok(buf)
+    }
+}
+
+/// Returns the last component of the path. Paths like `.` are resolved first.
+fn base_name(p: &Path) -> Result<String, &'static str> {
+    let name = |p: &Path| p.file_name().and_then(|n| n.to_str()).map(|n| n.to_string());
+    name(p)
+        .or_else(|| fs::canonicalize(p).ok().and_then(|p| name(&p)))
+        .ok_or("Invalid file name.")
+}
+
+/// Messages exchanged for a file transfer.
+///
+/// The sender announces a file with an offer. The receiver answers with the sequence
+/// numbers of the parts it has not yet received and the sender transmits these parts.
+/// Both sides persist which parts have been transferred so that a transfer can be resumed
+/// after a restart by sending the file again. The receiver can reject an offer instead. The
+/// sender can stop a transfer at any time by canceling or pausing it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transfer {
+    Offer(FileInfo),
+    /// Ranges of sequence numbers (inclusive) of the parts of the file with the given hash
+    /// which are missing.
+    Missing(Vec<u8>, Vec<(u32, u32)>),
+    /// Part of the file with the given hash and sequence number.
+    Chunk(Vec<u8>, u32, Vec<u8>),
+    /// The receiver does not accept the file with the given hash.
+    Reject(Vec<u8>),
+    /// The sender has canceled the transfer. The ids of messages which could have been
+    /// received partially are given.
+    Cancel(Vec<u8>, Vec<u64>),
+    /// The sender has paused the transfer. The ids are the same as for a cancellation.
+    Pause(Vec<u8>, Vec<u64>),
+}
+
+impl Transfer {
+
+    /// u8      : version
+    /// u8      : type { 1 = offer, 2 = missing, 3 = chunk, 4 = reject, 5 = cancel, 6 = pause }
+    /// [u8;32] : hash
+    /// offer   : u64 size, u64 mtime, u32 mode, name
+    /// missing : pairs of u32 (first and last sequence number of a range)
+    /// chunk   : u32 seq, data
+    /// cancel  : u64 message ids
+    /// pause   : u64 message ids
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut v = vec![VERSION];
+        match self {
+            Transfer::Offer(info) => {
+                v.push(OFFER);
+                push_slice(&mut v, &info.hash);
+                push_value(&mut v, info.size, 8);
+                push_value(&mut v, info.mtime, 8);
+                push_value(&mut v, info.mode as u64, 4);
+                push_slice(&mut v, info.name.as_bytes());
+            },
+            Transfer::Missing(hash, ranges) => {
+                v.push(MISSING);
+                push_slice(&mut v, hash);
+                for (first, last) in ranges {
+                    push_value(&mut v, *first as u64, 4);
+                    push_value(&mut v, *last as u64, 4);
+                }
+            },
+            Transfer::Chunk(hash, seq, data) => {
+                v.push(CHUNK);
+                push_slice(&mut v, hash);
+                push_value(&mut v, *seq as u64, 4);
+                push_slice(&mut v, data);
+            },
+            Transfer::Reject(hash) => {
+                v.push(REJECT);
+                push_slice(&mut v, hash);
+            },
+            Transfer::Cancel(hash, ids) => {
+                v.push(CANCEL);
+                push_slice(&mut v, hash);
+                push_ids(&mut v, ids);
+            },
+            Transfer::Pause(hash, ids) => {
+                v.push(PAUSE);
+                push_slice(&mut v, hash);
+                push_ids(&mut v, ids);
+            }
+        }
+        v
+    }
+
+    pub fn deserialize(buf: &[u8]) -> Option<Transfer> {
+        let mut r = Reader::new(buf);
+        if r.value(1)? != VERSION as u64 {
+            return None;
+        }
+        let typ = r.value(1)? as u8;
+        let hash = r.take(HASH_LEN)?.to_vec();
+        match typ {
+            OFFER => {
+                let size = r.value(8)?;
+                chunk_count(size)?;
+                let mtime = r.value(8)?;
+                let mode = r.value(4)? as u32;
+                let name = String::from_utf8(r.buf.to_vec()).ok()?;
+                Some(Transfer::Offer(FileInfo { hash, name, size, mtime, mode }))
+            },
+            MISSING => {
+                let mut ranges = vec![];
+                while !r.buf.is_empty() {
+                    ranges.push((r.value(4)? as u32, r.value(4)? as u32));
+                }
+                Some(Transfer::Missing(hash, ranges))
+            },
+            CHUNK => {
+                let seq = r.value(4)? as u32;
+                Some(Transfer::Chunk(hash, seq, r.buf.to_vec()))
+            },
+            REJECT => Some(Transfer::Reject(hash)),
+            CANCEL | PAUSE => {
+                let mut ids = vec![];
+                while !r.buf.is_empty() {
+                    ids.push(r.value(8)?);
+                }
+                match typ {
+                    CANCEL => Some(Transfer::Cancel(hash, ids)),
+                    _ => Some(Transfer::Pause(hash, ids))
+                }
+            },
+            _ => None
+        }
+    }
+}
+
+fn push_ids(v: &mut Vec<u8>, ids: &[u64]) {
+    for id in ids {
+        push_value(v, *id, 8);
+    }
+}
+
+/// Reads the fields of a serialized message.
+pub struct Reader<'a> {
+    pub buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf }
+    }
+
+    pub fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (a, b) = self.buf.split_at(n);
+        self.buf = b;
+        Some(a)
+    }
+
+    /// Reads an unsigned integer of n bytes in little endian.
+    pub fn value(&mut self, n: usize) -> Option<u64> {
+        Some(self.take(n)?.iter().rev().fold(0, |r, x| (r << 8) + *x as u64))
+    }
+}
+
+/// Returns the directory in which the state of file transfers is stored.
+pub fn state_dir(name: &str) -> PathBuf {
+    let mut path = dirs::home_dir().unwrap_or(std::env::temp_dir());
+    path.push(".stealthy");
+    path.push("transfers");
+    path.push(name);
+    path
+}
+
+/// File transfers in both directions.
+#[derive(Clone)]
+pub struct Transfers {
+    pub uploads: Uploads,
+    pub downloads: Downloads,
+    console: Console,
+}
+
+impl Transfers {
+
+    /// Creates the file transfers which keep their state in the home directory of the user.
+    pub fn new(options: DownloadOptions, layers: &Layers, console: Console) -> Transfers {
+        Transfers::with_dirs(state_dir("outgoing"), state_dir("incoming"), options, layers, console)
+    }
+
+    pub fn with_dirs(outgoing: PathBuf, incoming: PathBuf, options: DownloadOptions, layers: &Layers, console: Console) -> Transfers {
+        Transfers {
+            uploads: Uploads::new(outgoing, layers.clone(), console.clone()),
+            downloads: Downloads::new(incoming, options, layers.clone(), console.clone()),
+            console,
+        }
+    }
+
+    /// Processes received messages which belong to file transfers. All other messages are
+    /// returned.
+    pub fn handle(&self, msg: IncomingMessage) -> Option<IncomingMessage> {
+        match msg {
+            IncomingMessage::FileUpload(m) => {
+                match m.get_transfer() {
+                    Some(Transfer::Missing(hash, ranges)) => self.uploads.missing(&m.ip, &hash, ranges),
+                    Some(Transfer::Reject(hash)) => self.uploads.rejected(&m.ip, &hash),
+                    Some(t) => self.downloads.receive(m.get_ip(), t),
+                    None => self.console.error("Received invalid file transfer message.".to_string())
+                }
+                None
+            },
+            IncomingMessage::Ack(id) if self.uploads.ack(id) => None,
+            IncomingMessage::AckProgress(id, _, _) if self.uploads.contains(id) => None,
+            IncomingMessage::Retry(id) if self.uploads.retry(id) => None,
+            IncomingMessage::Failed(id) if self.uploads.failed(id) => None,
+            m => Some(m)
+        }
+    }
+}
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::Metadata;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{chunk_count, sha256, Content, FileInfo, Transfer, Transfers, CHUNK_SIZE};
+    use crate::console::{Console, ConsoleMessage};
+    use crate::download::{DownloadOptions, DEFAULT_MAX_SIZE};
+    use crate::iptools::IpAddresses;
+    use crate::layer::Layers;
+    use crate::loopback::LoopbackBus;
+    use crate::peers::Peers;
+    use crate::tools::random_str;
+
+    const KEY: &str = "11111111111111111111111111111111";
+
+    fn info(size: u64) -> FileInfo {
+        FileInfo { hash: vec![7; 32], name: "a.txt".to_string(), size, mtime: 0, mode: 0o644 }
+    }
+
+    #[test]
+    fn test_chunks() {
+        assert_eq!(info(0).chunks(), 1);
+        assert_eq!(info(0).chunk_range(1), Some((0, 0)));
+        assert_eq!(info(CHUNK_SIZE as u64).chunks(), 1);
+
+        let i = info(2 * CHUNK_SIZE as u64 + 10);
+        assert_eq!(i.chunks(), 3);
+        assert_eq!(i.chunk_range(0), None);
+        assert_eq!(i.chunk_range(2), Some((CHUNK_SIZE as u64, CHUNK_SIZE)));
+        assert_eq!(i.chunk_range(3), Some((2 * CHUNK_SIZE as u64, 10)));
+        assert_eq!(i.chunk_range(4), None);
+
+        assert_eq!(chunk_count(u32::MAX as u64 * CHUNK_SIZE as u64), Some(u32::MAX));
+        assert_eq!(chunk_count(u32::MAX as u64 * CHUNK_SIZE as u64 + 1), None);
+        assert_eq!(info(u64::MAX).chunks(), u32::MAX);
+    }
+
+    #[test]
+    fn test_de_and_serialize() {
+        let (hash, size) = sha256(&mut &b"hello"[..]).unwrap();
+        assert_eq!(size, 5);
+        let i = FileInfo { hash, name: "a.txt".to_string(), size, mtime: 1600000000, mode: 0o100640 };
+        assert_eq!(i.id(), "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+
+        let v = vec![
+            Transfer::Offer(i.clone()),
+            Transfer::Missing(i.hash.clone(), vec![(1, 3), (7, 70000)]),
+            Transfer::Missing(i.hash.clone(), vec![]),
+            Transfer::Chunk(i.hash.clone(), 5, vec![1, 2, 3]),
+            Transfer::Reject(i.hash.clone()),
+            Transfer::Cancel(i.hash.clone(), vec![1, u64::MAX]),
+            Transfer::Pause(i.hash.clone(), vec![]),
+        ];
+        for t in v {
+            assert_eq!(Transfer::deserialize(&t.serialize()), Some(t));
+        }
+
+        let mut buf = Transfer::Chunk(i.hash.clone(), 5, vec![]).serialize();
+        // Invalid type.
+        buf[1] = 9;
+        assert!(Transfer::deserialize(&buf).is_none());
+        // Too short.
+        assert!(Transfer::deserialize(&buf[..20]).is_none());
+        buf = Transfer::Missing(i.hash.clone(), vec![(1, 2)]).serialize();
+        assert!(Transfer::deserialize(&buf[..buf.len() - 1]).is_none());
+        // Offer of a file with more parts than sequence numbers.
+        let big = FileInfo { size: u64::MAX, ..i.clone() };
+        assert!(Transfer::deserialize(&Transfer::Offer(big).serialize()).is_none());
+    }
+
+    fn tmp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("stealthy_test_{}", random_str(10)))
+    }
+
+    struct Peer {
+        transfers: Transfers,
+        console: Receiver<ConsoleMessage>,
+    }
+
+    /// Creates a peer which handles file transfers and keeps their state and received files
+    /// in the given directory.
+    fn peer(bus: &LoopbackBus, ip: &str, peers: &str, dir: &Path) -> Peer {
+        peer_with_options(bus, ip, peers, dir, DownloadOptions { dir: dir.join("files"), ..Default::default() })
+    }
+
+    fn peer_with_options(bus: &LoopbackBus, ip: &str, peers: &str, dir: &Path, options: DownloadOptions) -> Peer {
+        let (tx, rx) = channel();
+        let console = Console::new(tx);
+        let layer = Layers::symmetric(
+            KEY,
+            &Peers::new(),
+            Box::new(bus.connect(ip)),
+            console.clone(),
+            &IpAddresses::from_comma_list(peers)
+        ).unwrap();
+        let transfers = Transfers::with_dirs(dir.join("outgoing"), dir.join("incoming"), options, &layer.layers, console);
+
+        let t = transfers.clone();
+        let rx_network = layer.rx;
+        thread::spawn(move || {
+            while let Ok(m) = rx_network.recv() {
+                t.handle(m);
+            }
+        });
+
+        Peer {
+            transfers,
+            console: rx,
+        }
+    }
+
+    /// Returns the next message shown on the console of the peer.
+    fn next(p: &Peer) -> ConsoleMessage {
+        p.console.recv_timeout(Duration::from_secs(20)).expect("Timeout.")
+    }
+
+    /// Waits until the peer has received a file and returns its content and its metadata.
+    fn received_file(p: &Peer) -> (Vec<u8>, Metadata) {
+        loop {
+            if let ConsoleMessage::TextMessage(i) = next(p) {
+                if i.msg.starts_with("File written to '") {
+                    let fname = i.msg.split('\'').nth(1).unwrap().to_string();
+                    let data = std::fs::read(&fname).unwrap();
+                    let meta = std::fs::metadata(&fname).unwrap();
+                    std::fs::remove_file(fname).unwrap();
+                    return (data, meta);
+                }
+            }
+        }
+    }
+
+    /// Writes random data into a file in the given directory. Returns the path of the file
+    /// and the data.
+    fn random_file(dir: &PathBuf, n: usize) -> (String, Vec<u8>) {
+        let data = (0..n).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+        std::fs::create_dir_all(dir).unwrap();
+        let path = dir.join("data.bin").to_str().unwrap().to_string();
+        std::fs::write(&path, &data).unwrap();
+        (path, data)
+    }
+
+    /// Waits until a message which contains the given text is shown on the console.
+    fn expect_text(p: &Peer, text: &str) -> String {
+        loop {
+            if let ConsoleMessage::TextMessage(i) = next(p) {
+                if i.msg.contains(text) {
+                    return i.msg;
+                }
+            }
+        }
+    }
+
+    fn file_info(path: &str) -> FileInfo {
+        Content::open(path).unwrap().info().unwrap()
+    }
+
+    fn expect_ack(p: &Peer, id: u64) {
+        loop {
+            if let ConsoleMessage::Ack(i) = next(p) {
+                assert_eq!(i, id);
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn test_upload() {
+        let (da, db) = (tmp_dir(), tmp_dir());
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", &da);
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1", &db);
+
+        let (path, data) = random_file(&da, 20000);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o4750)).unwrap();
+        let info = file_info(&path);
+        assert_eq!(info.size, 20000);
+        a.transfers.uploads.start(1, "10.0.0.2".to_string(), info.clone(), &path).unwrap();
+
+        let (received, meta) = received_file(&b);
+        assert_eq!(received, data);
+        // The setuid bit is not set.
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o750);
+        let mtime = |m: Metadata| m.modified().unwrap().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(mtime(meta), mtime(std::fs::metadata(&path).unwrap()));
+        expect_ack(&a, 1);
+
+        // The state of the transfer is removed on both sides.
+        assert_eq!(std::fs::read_dir(da.join("outgoing")).unwrap().count(), 0);
+        assert_eq!(std::fs::read_dir(db.join("incoming")).unwrap().count(), 0);
+        std::fs::remove_dir_all(da).unwrap();
+        std::fs::remove_dir_all(db).unwrap();
+    }
+
+    #[test]
+    fn test_resume() {
+        let (da, db) = (tmp_dir(), tmp_dir());
+        let bus = LoopbackBus::new();
+
+        let (path, data) = random_file(&da, 5 * CHUNK_SIZE);
+        let info = file_info(&path);
+        let chunk = |seq: usize| data[(seq - 1) * CHUNK_SIZE..seq * CHUNK_SIZE].to_vec();
+
+        // The receiver got two parts before it was restarted.
+        {
+            let b = peer(&bus, "10.0.0.2", "10.0.0.1", &db);
+            b.transfers.downloads.receive("10.0.0.1".to_string(), Transfer::Offer(info.clone()));
+            b.transfers.downloads.receive("10.0.0.1".to_string(), Transfer::Chunk(info.hash.clone(), 1, chunk(1)));
+            b.transfers.downloads.receive("10.0.0.1".to_string(), Transfer::Chunk(info.hash.clone(), 4, chunk(4)));
+        }
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1", &db);
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", &da);
+
+        a.transfers.uploads.start(2, "10.0.0.2".to_string(), info, &path).unwrap();
+
+        // Only the three missing parts are sent.
+        loop {
+            if let ConsoleMessage::AckProgress(id, pending, total) = next(&a) {
+                assert_eq!((id, pending, total), (2, 3, 5));
+                break;
+            }
+        }
+        assert_eq!(received_file(&b).0, data);
+        expect_ack(&a, 2);
+        std::fs::remove_dir_all(da).unwrap();
+        std::fs::remove_dir_all(db).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_file() {
+        let db = tmp_dir();
+        let bus = LoopbackBus::new();
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1", &db);
+
+        // The content does not match the hash of the offer.
+        let info = FileInfo { hash: vec![7; 32], name: "a.txt".to_string(), size: 5, mtime: 0, mode: 0o644 };
+        b.transfers.downloads.receive("10.0.0.1".to_string(), Transfer::Offer(info.clone()));
+        b.transfers.downloads.receive("10.0.0.1".to_string(), Transfer::Chunk(info.hash.clone(), 1, b"hello".to_vec()));
+        loop {
+            if let ConsoleMessage::TextMessage(i) = next(&b) {
+                if i.msg.contains("discarded") {
+                    assert!(i.msg.contains("checksum"));
+                    break;
+                }
+                assert!(!i.msg.starts_with("File written"));
+            }
+        }
+        // The partial file and the manifest are removed.
+        assert_eq!(std::fs::read_dir(db.join("incoming")).unwrap().count(), 0);
+        std::fs::remove_dir_all(db).unwrap();
+    }
+
+    #[test]
+    fn test_max_size() {
+        assert_eq!(DownloadOptions::default().max_size, Some(DEFAULT_MAX_SIZE));
+
+        let (da, db) = (tmp_dir(), tmp_dir());
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", &da);
+        let options = DownloadOptions { dir: db.join("files"), max_size: Some(10000), ask: false };
+        let b = peer_with_options(&bus, "10.0.0.2", "10.0.0.1", &db, options);
+
+        let (path, _) = random_file(&da, 20000);
+        let info = file_info(&path);
+        a.transfers.uploads.start(1, "10.0.0.2".to_string(), info, &path).unwrap();
+
+        assert!(expect_text(&b, "Rejected file 'data.bin'").contains("more than 10000 bytes"));
+        expect_text(&a, "has been rejected");
+        // Nothing has been written by the receiver and the upload cannot be resumed.
+        assert!(!db.exists());
+        assert_eq!(std::fs::read_dir(da.join("outgoing")).unwrap().count(), 0);
+        std::fs::remove_dir_all(da).unwrap();
+    }
+
+    #[test]
+    fn test_ask() {
+        let (da, db) = (tmp_dir(), tmp_dir());
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", &da);
+        let options = DownloadOptions { dir: db.join("files"), max_size: None, ask: true };
+        let b = peer_with_options(&bus, "10.0.0.2", "10.0.0.1", &db, options);
+
+        let (path, data) = random_file(&da, 20000);
+        let info = file_info(&path);
+        a.transfers.uploads.start(1, "10.0.0.2".to_string(), info, &path).unwrap();
+
+        // Nothing is received until the file has been accepted.
+        expect_text(&b, "Type /accept 1 or /reject 1.");
+        assert!(!db.join("incoming").exists());
+        assert!(b.transfers.downloads.accept(2).is_err());
+        b.transfers.downloads.accept(1).unwrap();
+        assert!(b.transfers.downloads.accept(1).is_err());
+        let (received, _) = received_file(&b);
+        assert_eq!(received, data);
+        expect_ack(&a, 1);
+
+        // A rejected file is not received.
+        std::fs::write(&path, b"hello").unwrap();
+        let info = file_info(&path);
+        a.transfers.uploads.start(2, "10.0.0.2".to_string(), info, &path).unwrap();
+        expect_text(&b, "Type /accept 1 or /reject 1.");
+        b.transfers.downloads.reject(1).unwrap();
+        expect_text(&a, "has been rejected");
+        assert_eq!(std::fs::read_dir(db.join("incoming")).unwrap().count(), 0);
+        assert_eq!(std::fs::read_dir(db.join("files")).unwrap().count(), 0);
+        std::fs::remove_dir_all(da).unwrap();
+        std::fs::remove_dir_all(db).unwrap();
+    }
+
+    #[test]
+    fn test_content() {
+        let dir = tmp_dir();
+        let (path, data) = random_file(&dir, 5000);
+        let mut c = Content::open(&path).unwrap();
+        let info = c.info().unwrap();
+        assert_eq!((info.name.as_str(), info.size, info.is_archive()), ("data.bin", 5000, false));
+        assert_eq!(c.read_chunk(4000, 1000).unwrap(), data[4000..].to_vec());
+
+        std::fs::write(dir.join("a.txt"), b"a").unwrap();
+        std::fs::write(dir.join("b.txt"), b"b").unwrap();
+        let info = file_info(dir.to_str().unwrap());
+        assert!(info.is_archive());
+        assert_eq!(info.name, dir.file_name().unwrap().to_str().unwrap());
+
+        // A pattern is named after its directory and contains only the matching files.
+        let pattern = format!("{}/*.txt", dir.to_str().unwrap());
+        let i = file_info(&pattern);
+        assert!(i.is_archive());
+        assert_eq!(i.name, info.name);
+        assert!(i.size < info.size);
+        assert_eq!(file_info(&pattern), i);
+        assert!(Content::open(&format!("{}/*.none", dir.to_str().unwrap())).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_upload_directory() {
+        let (da, db) = (tmp_dir(), tmp_dir());
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", &da);
+        let b = peer(&bus, "10.0.0.2", "10.0.0.1", &db);
+
+        let src = da.join("project");
+        let (path, data) = random_file(&src.join("src"), 3 * CHUNK_SIZE + 1);
+        std::fs::write(src.join("README"), b"hello").unwrap();
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o750)).unwrap();
+        let info = file_info(src.to_str().unwrap());
+        a.transfers.uploads.start(1, "10.0.0.2".to_string(), info, src.to_str().unwrap()).unwrap();
+
+        let msg = expect_text(&b, "Files written to '");
+        let dst = PathBuf::from(msg.split('\'').nth(1).unwrap());
+        assert_eq!(dst, db.join("files/project"));
+        assert_eq!(std::fs::metadata(&dst).unwrap().permissions().mode() & 0o777, 0o750);
+        assert_eq!(std::fs::read(dst.join("README")).unwrap(), b"hello");
+        assert_eq!(std::fs::read(dst.join("src/data.bin")).unwrap(), data);
+        assert_eq!(std::fs::metadata(dst.join("src/data.bin")).unwrap().permissions().mode(),
+                   std::fs::metadata(&path).unwrap().permissions().mode());
+        expect_ack(&a, 1);
+        assert_eq!(std::fs::read_dir(db.join("incoming")).unwrap().count(), 0);
+        std::fs::remove_dir_all(da).unwrap();
+        std::fs::remove_dir_all(db).unwrap();
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        let (da, db) = (tmp_dir(), tmp_dir());
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", &da);
+        let options = DownloadOptions { dir: db.join("files"), max_size: None, ask: true };
+        let b = peer_with_options(&bus, "10.0.0.2", "10.0.0.1", &db, options);
+
+        let (path, data) = random_file(&da, 3 * CHUNK_SIZE);
+        a.transfers.uploads.start(7, "10.0.0.2".to_string(), file_info(&path), &path).unwrap();
+        expect_text(&b, "Type /accept 1");
+
+        // The answer of the receiver is ignored while the upload is paused.
+        a.transfers.uploads.pause(1).unwrap();
+        assert!(a.transfers.uploads.pause(1).is_err());
+        assert!(a.transfers.uploads.pause(2).is_err());
+        b.transfers.downloads.accept(1).unwrap();
+        let l = a.transfers.uploads.list();
+        assert_eq!(l.len(), 1);
+        assert_eq!((l[0].number, l[0].delivered, l[0].total, l[0].paused), (1, 0, 3, true));
+
+        a.transfers.uploads.resume(1).unwrap();
+        assert!(a.transfers.uploads.resume(1).is_err());
+        assert_eq!(received_file(&b).0, data);
+        expect_ack(&a, 7);
+        assert!(a.transfers.uploads.list().is_empty());
+        std::fs::remove_dir_all(da).unwrap();
+        std::fs::remove_dir_all(db).unwrap();
+    }
+
+    #[test]
+    fn test_cancel() {
+        let (da, db) = (tmp_dir(), tmp_dir());
+        let bus = LoopbackBus::new();
+        let a = peer(&bus, "10.0.0.1", "10.0.0.2", &da);
+        let options = DownloadOptions { dir: db.join("files"), max_size: None, ask: true };
+        let b = peer_with_options(&bus, "10.0.0.2", "10.0.0.1", &db, options);
+
+        let (path, data) = random_file(&da, 3 * CHUNK_SIZE);
+        let info = file_info(&path);
+        a.transfers.uploads.start(7, "10.0.0.2".to_string(), info.clone(), &path).unwrap();
+        expect_text(&b, "Type /accept 1");
+
+        // The sender forgets the upload and the receiver the offer.
+        a.transfers.uploads.cancel(1).unwrap();
+        assert!(a.transfers.uploads.cancel(1).is_err());
+        assert!(a.transfers.uploads.list().is_empty());
+        assert_eq!(std::fs::read_dir(da.join("outgoing")).unwrap().count(), 0);
+        expect_text(&b, "has been canceled by the sender");
+        assert!(b.transfers.downloads.accept(1).is_err());
+
+        // A partially received file is deleted.
+        let ip = "10.0.0.1".to_string();
+        b.transfers.downloads.receive(ip.clone(), Transfer::Offer(info.clone()));
+        expect_text(&b, "Type /accept 1");
+        b.transfers.downloads.accept(1).unwrap();
+        b.transfers.downloads.receive(ip.clone(), Transfer::Chunk(info.hash.clone(), 2, data[CHUNK_SIZE..2 * CHUNK_SIZE].to_vec()));
+        assert_eq!(std::fs::read_dir(db.join("incoming")).unwrap().count(), 3);
+        b.transfers.downloads.receive(ip.clone(), Transfer::Cancel(info.hash.clone(), vec![]));
+        assert_eq!(std::fs::read_dir(db.join("incoming")).unwrap().count(), 0);
+        std::fs::remove_dir_all(da).unwrap();
+        std::fs::remove_dir_all(db).unwrap();
+    }
+}
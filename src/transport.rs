@@ -0,0 +1,74 @@
+use std::sync::mpsc::Sender;
+
+/// A datagram received from a peer.
+pub struct Datagram {
+    /// The payload of the datagram.
+    pub buf: Vec<u8>,
+    /// The source IP of the datagram.
+    pub ip: String,
+    /// True if the datagram is a reply to a datagram we have sent (e.g. an ICMP echo reply).
+    pub reply: bool,
+    /// Identifies the datagram so that it can be answered with `Transport::reply` (e.g. the
+    /// identifier and the sequence number of an ICMP echo request).
+    pub token: u32,
+}
+
+impl Datagram {
+    pub fn new(buf: Vec<u8>, ip: String, reply: bool, token: u32) -> Datagram {
+        Datagram {
+            buf,
+            ip,
+            reply,
+            token,
+        }
+    }
+}
+
+/// A covert channel which is able to transmit datagrams between peers.
+///
+/// The network layer uses a transport to send packets to peers and to receive
+/// packets from peers. It does not make any assumption about how the datagrams
+/// are transmitted.
+pub trait Transport : Send + Sync {
+    /// Sends the datagram `buf` to the peer with the given IP. Returns false if the
+    /// datagram could not be sent.
+    fn send(&self, ip: &str, buf: &[u8]) -> bool;
+
+    /// Sends the datagram like `send` with the given identifier and sequence number (e.g.
+    /// those of an ICMP echo request). Transports without them ignore both.
+    fn send_echo(&self, ip: &str, _id: u16, _seq: u16, buf: &[u8]) -> bool {
+        self.send(ip, buf)
+    }
+
+    /// Sends the datagram like `send_echo` and calls `sent` once the datagram has gone out.
+    /// Transports which hold datagrams back, e.g. to pace them, return before that.
+    fn send_echo_notify(&self, ip: &str, id: u16, seq: u16, buf: &[u8], sent: Box<dyn FnOnce() + Send>) -> bool {
+        let r = self.send_echo(ip, id, seq, buf);
+        sent();
+        r
+    }
+
+    /// Sends the datagram `buf` as reply to the datagram with the given token which has
+    /// been received from the peer with the given IP. Unlike datagrams sent with `send`
+    /// replies pass NAT gateways on the way back to a peer which sits behind a NAT.
+    fn reply(&self, ip: &str, token: u32, buf: &[u8]) -> bool;
+
+    /// Starts receiving datagrams. Each received datagram is delivered together with its
+    /// source address to the given sender.
+    fn listen(&self, tx: Sender<Datagram>) -> Result<(), &'static str>;
+
+    /// Returns the maximum number of bytes a single datagram can carry.
+    fn max_payload(&self) -> usize;
+
+    /// Returns the maximum size of a datagram which is sent in an ICMP payload of at most
+    /// `n` bytes.
+    fn datagram_size(&self, n: usize) -> usize {
+        n
+    }
+
+    /// Returns the time in milliseconds between two datagrams which are sent to the same
+    /// peer or 0 if datagrams are sent right away.
+    fn interval(&self) -> i64 {
+        0
+    }
+}
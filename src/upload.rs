@@ -1,26 +1,389 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+
+use crate::layer::Layers;
+use crate::manifest::Manifest;
+use crate::message::Message;
+use crate::model::DeliveryStatus;
+use crate::transfer::{Content, FileInfo, Transfer};
 use crate::Console;
-use crate::Message;
-use crate::tools;
-use crate::write_data;
 
-pub fn save_upload(o: Console, msg: Message) {
+/// Time to wait for the peer to answer an offer before the offer is sent again.
+const OFFER_TIMEOUT: Duration = Duration::from_secs(60);
+/// Number of times an offer is sent before the upload fails.
+const OFFER_ATTEMPTS: usize = 3;
+
+/// A file which is sent to a peer.
+struct Upload {
+    /// Number which is shown to the user to refer to the upload.
+    number: u32,
+    manifest: Manifest,
+    /// Receives the ranges of parts which the peer is missing. None if the upload has been
+    /// paused.
+    missing: Option<Sender<Vec<(u32, u32)>>>,
+    /// Number of parts which have not been acknowledged.
+    remaining: usize,
+    /// Identifies the thread which sends the file. A thread which has been started before
+    /// the upload was paused stops when the upload is resumed.
+    run: u64,
+}
+
+struct State {
+    uploads: HashMap<u64, Upload>,
+    /// Maps the ids of messages sent for an upload to the id of the upload and, for parts of
+    /// the file, to the sequence number of the part.
+    messages: HashMap<u64, (u64, Option<u32>)>,
+    /// Number of the next upload.
+    next: u32,
+}
+
+impl State {
+
+    /// Returns the id of the upload with the given number.
+    fn find(&self, number: u32) -> Result<u64, &'static str> {
+        self.uploads.iter()
+            .find(|(_, u)| u.number == number)
+            .map(|(id, _)| *id)
+            .ok_or("No such upload.")
+    }
+
+    /// Removes the messages of the upload and returns their ids.
+    fn remove_messages(&mut self, id: u64) -> Vec<u64> {
+        let ids = self.messages.iter()
+            .filter(|(_, (i, _))| *i == id)
+            .map(|(msg_id, _)| *msg_id)
+            .collect::<Vec<_>>();
+        for msg_id in &ids {
+            self.messages.remove(msg_id);
+        }
+        ids
+    }
+}
+
+/// Progress of an upload.
+pub struct Progress {
+    pub number: u32,
+    pub name: String,
+    pub peer: String,
+    /// Number of parts which have been delivered.
+    pub delivered: usize,
+    pub total: usize,
+    pub paused: bool,
+}
+
+/// Files which are sent to peers.
+///
+/// Each upload is identified by the id of the item which is shown to the user. The
+/// progress of an upload is persisted so that an interrupted upload can be resumed by
+/// sending the same file again.
+#[derive(Clone)]
+pub struct Uploads {
+    state: Arc<Mutex<State>>,
+    dir: PathBuf,
+    layers: Layers,
+    console: Console,
+}
+
+impl Uploads {
+
+    /// Creates the uploads which keep their state in the given directory. The user is
+    /// informed about uploads which have not been finished.
+    pub fn new(dir: PathBuf, layers: Layers, console: Console) -> Uploads {
+        for m in Manifest::list(&dir) {
+            console.status(format!("Unfinished upload of '{}' to {} ({} of {} parts delivered). Type /upload {} to resume.",
+                                   m.info.name, m.peer, m.received(), m.info.chunks(), m.path));
+        }
+        Uploads {
+            state: Arc::new(Mutex::new(State {
+                uploads: HashMap::new(),
+                messages: HashMap::new(),
+                next: 1,
+            })),
+            dir,
+            layers,
+            console,
+        }
+    }
+
+    /// Starts sending the file, the directory or the files matching the pattern `path`
+    /// described by `info` to the given IP in background. The content is read part by part
+    /// while it is sent.
+    pub fn start(&self, id: u64, ip: String, info: FileInfo, path: &str) -> Result<(), &'static str> {
+        let (rx, run) = {
+            let mut s = self.state.lock().expect("Lock failed.");
+            if s.uploads.values().any(|u| u.manifest.info.hash == info.hash && u.manifest.peer == ip) {
+                return Err("The file is already being sent to this peer.");
+            }
+            let manifest = Manifest::open(&self.dir, &info, &ip, path)?;
+            if manifest.received() > 0 {
+                self.console.status(format!("Resuming upload of '{}' to {}.", info.name, ip));
+            }
+            let (tx, rx) = channel();
+            let run = rand::random::<u64>();
+            let number = s.next;
+            s.next += 1;
+            s.uploads.insert(id, Upload {
+                number,
+                manifest,
+                missing: Some(tx),
+                remaining: info.chunks() as usize,
+                run,
+            });
+            (rx, run)
+        };
+
+        let u = self.clone();
+        let path = path.to_string();
+        thread::spawn(move || u.run(id, run, ip, info, path, rx));
+        Ok(())
+    }
+
+    /// Offers the file to the peer and sends the parts which the peer is missing.
+    fn run(&self, id: u64, run: u64, ip: String, info: FileInfo, path: String, rx: Receiver<Vec<(u32, u32)>>) {
+
+        let mut src = match Content::open(&path) {
+            Ok(src) => src,
+            Err(e) => return self.fail(id, e)
+        };
+
+        // The offer is sent again if the peer does not answer, e.g. because the answer
+        // has been lost. If the upload fails or is paused in the meantime the sender is
+        // dropped.
+        let mut attempts = 0;
+        let ranges = loop {
+            if attempts == OFFER_ATTEMPTS {
+                return self.fail(id, "The peer has not accepted the file.");
+            }
+            attempts += 1;
+            let offer = rand::random::<u64>();
+            if !self.register(offer, id, run, None) {
+                return;
+            }
+            self.layers.send(Message::file_upload(ip.clone(), &Transfer::Offer(info.clone())), offer, false);
+            match rx.recv_timeout(OFFER_TIMEOUT) {
+                Ok(ranges) => break ranges,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return
+            }
+        };
+        let seqs = ranges.iter()
+            .flat_map(|(first, last)| *first..=*last)
+            .filter(|seq| info.chunk_range(*seq).is_some())
+            .collect::<Vec<_>>();
+        self.set_remaining(id, seqs.len());
+
+        for seq in seqs {
+            let msg_id = rand::random::<u64>();
+            // Stop if the upload has failed, has been canceled or has been paused.
+            if !self.register(msg_id, id, run, Some(seq)) {
+                break;
+            }
+            let (offset, len) = info.chunk_range(seq).unwrap();
+            let chunk = match src.read_chunk(offset, len) {
+                Ok(chunk) => chunk,
+                Err(e) => return self.fail(id, e)
+            };
+            self.layers.send(Message::file_upload(ip.clone(), &Transfer::Chunk(info.hash.clone(), seq, chunk)), msg_id, false);
+        }
+    }
+
+    /// Assigns the id of a message to an upload. Returns false if the upload is not sent
+    /// by the given run anymore.
+    fn register(&self, msg_id: u64, id: u64, run: u64, seq: Option<u32>) -> bool {
+        let mut s = self.state.lock().expect("Lock failed.");
+        match s.uploads.get(&id) {
+            Some(u) if u.run == run && u.missing.is_some() => { },
+            _ => return false
+        }
+        s.messages.insert(msg_id, (id, seq));
+        true
+    }
+
+    fn set_remaining(&self, id: u64, n: usize) {
+        let mut s = self.state.lock().expect("Lock failed.");
+        if let Some(u) = s.uploads.get_mut(&id) {
+            u.remaining = n;
+            self.console.ack_msg_progress(id, n, u.manifest.info.chunks() as usize);
+        }
+        if n == 0 {
+            self.complete(&mut s, id);
+        }
+    }
+
+    fn complete(&self, s: &mut State, id: u64) {
+        if let Some(u) = s.uploads.remove(&id) {
+            u.manifest.remove();
+            self.console.ack_msg(id);
+        }
+    }
+
+    /// Is called when the peer has answered an offer with the parts it is missing.
+    pub fn missing(&self, ip: &str, hash: &[u8], ranges: Vec<(u32, u32)>) {
+        let s = self.state.lock().expect("Lock failed.");
+        if let Some(u) = s.uploads.values().find(|u| u.manifest.peer == ip && u.manifest.info.hash == hash) {
+            if let Some(tx) = &u.missing {
+                let _ = tx.send(ranges);
+            }
+        }
+    }
+
+    /// Is called when the peer has rejected the file. The upload is stopped and its
+    /// progress is discarded.
+    pub fn rejected(&self, ip: &str, hash: &[u8]) {
+        let mut s = self.state.lock().expect("Lock failed.");
+        let id = match s.uploads.iter().find(|(_, u)| u.manifest.peer == ip && u.manifest.info.hash == hash) {
+            Some((id, _)) => *id,
+            None => return
+        };
+        s.messages.retain(|_, (i, _)| *i != id);
+        if let Some(u) = s.uploads.remove(&id) {
+            let name = u.manifest.info.name.clone();
+            u.manifest.remove();
+            self.console.delivery_status(id, DeliveryStatus::Failed);
+            self.console.error(format!("Upload of '{}' to {} has been rejected.", name, ip));
+        }
+    }
+
+    /// Returns the progress of all uploads ordered by their numbers.
+    pub fn list(&self) -> Vec<Progress> {
+        let s = self.state.lock().expect("Lock failed.");
+        let mut r = s.uploads.values()
+            .map(|u| Progress {
+                number: u.number,
+                name: u.manifest.info.name.clone(),
+                peer: u.manifest.peer.clone(),
+                delivered: u.manifest.received(),
+                total: u.manifest.info.chunks() as usize,
+                paused: u.missing.is_none(),
+            })
+            .collect::<Vec<_>>();
+        r.sort_by_key(|p| p.number);
+        r
+    }
+
+    /// Stops the upload with the given number and discards its progress. The peer is told
+    /// to discard the parts it has received.
+    pub fn cancel(&self, number: u32) -> Result<(), &'static str> {
+        let mut s = self.state.lock().expect("Lock failed.");
+        let id = s.find(number)?;
+        let ids = s.remove_messages(id);
+        let u = s.uploads.remove(&id).unwrap();
+        self.layers.cancel(&ids);
+        self.stop(&u, Transfer::Cancel(u.manifest.info.hash.clone(), ids));
+        self.console.delivery_status(id, DeliveryStatus::Failed);
+        self.console.status(format!("Upload of '{}' to {} has been canceled.", u.manifest.info.name, u.manifest.peer));
+        u.manifest.remove();
+        Ok(())
+    }
+
+    /// Stops sending the upload with the given number until it is resumed.
+    pub fn pause(&self, number: u32) -> Result<(), &'static str> {
+        let mut s = self.state.lock().expect("Lock failed.");
+        let id = s.find(number)?;
+        if s.uploads[&id].missing.is_none() {
+            return Err("The upload has already been paused.");
+        }
+        let ids = s.remove_messages(id);
+        let u = s.uploads.get_mut(&id).unwrap();
+        u.missing = None;
+        self.layers.cancel(&ids);
+        self.stop(u, Transfer::Pause(u.manifest.info.hash.clone(), ids));
+        self.console.delivery_status(id, DeliveryStatus::Paused);
+        self.console.status(format!("Upload of '{}' to {} has been paused. Type /resume {} to continue.",
+                                    u.manifest.info.name, u.manifest.peer, number));
+        Ok(())
+    }
+
+    /// Continues a paused upload. Only the parts which the peer is missing are sent.
+    pub fn resume(&self, number: u32) -> Result<(), &'static str> {
+        let (id, run, ip, info, path, rx) = {
+            let mut s = self.state.lock().expect("Lock failed.");
+            let id = s.find(number)?;
+            let u = s.uploads.get_mut(&id).unwrap();
+            if u.missing.is_some() {
+                return Err("The upload has not been paused.");
+            }
+            let (tx, rx) = channel();
+            u.missing = Some(tx);
+            u.run = rand::random::<u64>();
+            (id, u.run, u.manifest.peer.clone(), u.manifest.info.clone(), u.manifest.path.clone(), rx)
+        };
 
-    if msg.get_filename().is_none() {
-        o.error(format!("Could not get filename of received file upload."));
-        return;
-    } else if msg.get_filedata().is_none() {
-        o.error(format!("Could not get data of received file upload."));
-        return;
+        self.console.delivery_status(id, DeliveryStatus::Sent);
+        let u = self.clone();
+        thread::spawn(move || u.run(id, run, ip, info, path, rx));
+        Ok(())
     }
 
-    let fname = msg.get_filename().unwrap();
-    let data = msg.get_filedata().unwrap();
-    let dst = format!("/tmp/stealthy_{}_{}", tools::random_str(10), &fname);
-    o.new_file(msg, fname);
+    /// Tells the peer that the upload has been stopped.
+    fn stop(&self, u: &Upload, t: Transfer) {
+        self.layers.send(Message::file_upload(u.manifest.peer.clone(), &t), rand::random::<u64>(), true);
+    }
+
+    /// Returns true if the message with the given id belongs to an upload.
+    pub fn contains(&self, msg_id: u64) -> bool {
+        self.state.lock().expect("Lock failed.").messages.contains_key(&msg_id)
+    }
+
+    /// Is called when a message has been acknowledged. Returns true if the message belongs
+    /// to an upload.
+    pub fn ack(&self, msg_id: u64) -> bool {
+        let mut s = self.state.lock().expect("Lock failed.");
+        let (id, seq) = match s.messages.remove(&msg_id) {
+            Some(x) => x,
+            None => return false
+        };
+        let mut done = false;
+        if let (Some(u), Some(seq)) = (s.uploads.get_mut(&id), seq) {
+            if let Err(e) = u.manifest.add(seq) {
+                self.console.error(e.to_string());
+            }
+            u.remaining = u.remaining.saturating_sub(1);
+            self.console.ack_msg_progress(id, u.remaining, u.manifest.info.chunks() as usize);
+            done = u.remaining == 0;
+        }
+        if done {
+            self.complete(&mut s, id);
+        }
+        true
+    }
+
+    /// Is called when a message has been sent again. Returns true if the message belongs
+    /// to an upload.
+    pub fn retry(&self, msg_id: u64) -> bool {
+        match self.state.lock().expect("Lock failed.").messages.get(&msg_id) {
+            Some((id, _)) => {
+                self.console.delivery_status(*id, DeliveryStatus::Retrying);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Is called when a message could not be delivered. Returns true if the message
+    /// belongs to an upload.
+    pub fn failed(&self, msg_id: u64) -> bool {
+        let id = match self.state.lock().expect("Lock failed.").messages.get(&msg_id) {
+            Some((id, _)) => *id,
+            None => return false
+        };
+        self.fail(id, "The peer does not answer.");
+        true
+    }
 
-    if write_data(&dst, data) {
-        o.status(format!("File written to '{}'.", dst));
-    } else {
-        o.error(format!("Could not write data of received file upload."));
+    /// Stops an upload. Its progress is kept so that it can be resumed.
+    fn fail(&self, id: u64, reason: &str) {
+        let mut s = self.state.lock().expect("Lock failed.");
+        s.messages.retain(|_, (i, _)| *i != id);
+        if let Some(u) = s.uploads.remove(&id) {
+            self.console.delivery_status(id, DeliveryStatus::Failed);
+            self.console.error(format!("Upload of '{}' to {} failed. {} Type /upload {} to resume.",
+                                       u.manifest.info.name, u.manifest.peer, reason, u.manifest.path));
+        }
     }
 }
@@ -10,9 +10,11 @@ use termion::raw::IntoRawMode;
 
 use crate::model::{Item, ItemType, Model};
 use crate::model::Source;
+use crate::peers::Peers;
 use crate::tools::rot13;
 
 static ACK: char = '✔';
+static FAILED: char = '✘';
 static NUMBERS: &str = "➀➁➂➃➄➅➆➇➈➉";
 
 /// Write messages to the terminal.
@@ -25,16 +27,19 @@ pub struct View {
     // when a new message has been added to the buffer in the model.
     scroll_offset: usize,
     raw_view: bool,
+    // Used to show the names of peers instead of their IPs.
+    peers: Peers,
 }
 
 impl View {
 
-    pub fn new(model: Arc<Mutex<Model>>) -> View {
+    pub fn new(model: Arc<Mutex<Model>>, peers: Peers) -> View {
         View {
             stdout: stdout().into_raw_mode().expect("No raw mode possible."),
             model: model,
             scroll_offset: 0,
             raw_view: false,
+            peers,
         }.init()
     }
 
@@ -197,13 +202,17 @@ impl View {
         let t = self.fm_time(&i);
         match i.source() {
             Source::Ip(ip) => {
-                format!("{} | [{}] {}", t, ip, maybe_scrambled_msg)
+                format!("{} | [{}] {}", t, self.peers.display_name(&ip), maybe_scrambled_msg)
             },
             Source::You => {
                 match i.typ {
                     ItemType::UploadMessage => {
                         format!("{} | [you] {} {}", t, maybe_scrambled_msg, self.progress_bar(i.pending_acks, i.total_acks, 40))
                     },
+                    _ if !i.recipients.is_empty() => {
+                        let names = i.recipients.iter().map(|ip| self.peers.display_name(ip)).collect::<Vec<_>>();
+                        format!("{} | [you → {}] {}", t, names.join(", "), maybe_scrambled_msg)
+                    },
                     _ => {
                         format!("{} | [you] {}", t, maybe_scrambled_msg)
                     }
@@ -265,7 +274,11 @@ impl View {
 
         // Show input field.
         if !self.raw_view {
-            write_input_field(&mut self.stdout, model.input.clone());
+            let prompt = match &model.target {
+                Some(ip) => format!("{}> ", self.peers.display_name(ip)),
+                None => String::new()
+            };
+            write_input_field(&mut self.stdout, &prompt, model.input.clone());
         }
 
         // Show scroll status.
@@ -371,6 +384,10 @@ fn symbol_for_item(item: &Item) -> String {
         return format!("");
     }
 
+    if item.failed() {
+        return format!("{}{}", Fg(termion::color::Red), FAILED);
+    }
+
     if item.acks_received >= item.id.len() {
         return format!("{}{}", Fg(termion::color::Green), ACK);
     }
@@ -404,22 +421,23 @@ fn write_at(o: &mut RawTerminal<Stdout>, x: usize, y: usize, s: &str) {
     ).expect("Error.");
 }
 
-fn write_input_field(o: &mut RawTerminal<Stdout>, input: Vec<u8>) {
+/// Shows the input field. The prompt shows the peer to which messages are sent.
+fn write_input_field(o: &mut RawTerminal<Stdout>, prompt: &str, input: Vec<u8>) {
 
     let (maxx, maxy) = View::size();
-    let input_field_len = maxx - 2 - 1;
+    let input_field_len = (maxx - 2 - 1) as usize - min(prompt.chars().count(), maxx as usize / 2);
 
     write!(o, "{}", termion::color::Bg(termion::color::Blue)).expect("Error.");
     for x in 2..maxx {
         write!(o, "{} ", termion::cursor::Goto(x, maxy - 1)).expect("Error.");
     }
     let mut s = String::from_utf8(input).unwrap();
-    while s.chars().count() > input_field_len as usize {
+    while s.chars().count() > input_field_len {
         s.remove(0);
 
     }
     s.push('▂');
-    write_at(o, 2, maxy as usize - 1, &s);
+    write_at(o, 2, maxy as usize - 1, &format!("{}{}", prompt.chars().take(maxx as usize / 2).collect::<String>(), s));
 }
 
 fn write_scroll_status(o: &mut RawTerminal<Stdout>, current: usize, len: usize) {
@@ -0,0 +1,116 @@
+/// Number of packets which can be unacknowledged when the transmission to a peer starts.
+const INITIAL_WINDOW: f64 = 8.0;
+const MIN_WINDOW: f64 = 2.0;
+const MAX_WINDOW: f64 = 128.0;
+/// The window is not increased if the round-trip time exceeds the smallest observed
+/// round-trip time by this factor, as this indicates that packets are queued on the path.
+const MAX_RTT_FACTOR: i64 = 2;
+
+/// Congestion window of a peer, i.e. the number of packets which can be unacknowledged at
+/// the same time.
+///
+/// The window is adapted with additive increase / multiplicative decrease (AIMD). It grows
+/// by about one packet per round-trip time as long as packets are acknowledged and it is
+/// halved if a packet is lost.
+pub struct Window {
+    size: f64,
+    /// Smallest round-trip time in milliseconds observed so far.
+    min_rtt: Option<i64>,
+    /// Time in milliseconds of the last decrease.
+    last_decrease: i64,
+}
+
+impl Window {
+
+    pub fn new() -> Window {
+        Window {
+            size: INITIAL_WINDOW,
+            min_rtt: None,
+            last_decrease: 0,
+        }
+    }
+
+    /// Returns the number of packets which can be unacknowledged at the same time.
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Is called when a packet has been acknowledged. `rtt` is the round-trip time of the
+    /// packet or None if the packet has been sent more than once.
+    pub fn on_ack(&mut self, rtt: Option<i64>) {
+        if let Some(rtt) = rtt {
+            let min_rtt = self.min_rtt.map_or(rtt, |m| m.min(rtt));
+            self.min_rtt = Some(min_rtt);
+            if rtt > MAX_RTT_FACTOR * min_rtt.max(1) {
+                return;
+            }
+        }
+        self.size = (self.size + 1.0 / self.size).min(MAX_WINDOW);
+    }
+
+    /// Is called at time `now` (in milliseconds) when a packet has been lost. The window
+    /// is decreased at most once per round-trip time `srtt` as several packets are usually
+    /// lost at the same time.
+    pub fn on_loss(&mut self, now: i64, srtt: i64) {
+        if now - self.last_decrease < srtt {
+            return;
+        }
+        self.last_decrease = now;
+        self.size = (self.size / 2.0).max(MIN_WINDOW);
+    }
+}
+
+// ------------------------------------------------------------------------
+// TESTS
+// ------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+    use super::Window;
+
+    #[test]
+    fn test_increase() {
+        let mut w = Window::new();
+        assert_eq!(w.size(), 8);
+
+        // One packet per round-trip.
+        for _ in 0..8 {
+            w.on_ack(Some(10));
+        }
+        assert_eq!(w.size(), 8);
+        w.on_ack(Some(10));
+        assert_eq!(w.size(), 9);
+
+        // Not more than the maximum.
+        for _ in 0..100000 {
+            w.on_ack(None);
+        }
+        assert_eq!(w.size(), 128);
+    }
+
+    #[test]
+    fn test_queueing_delay() {
+        let mut w = Window::new();
+        w.on_ack(Some(10));
+        for _ in 0..100 {
+            w.on_ack(Some(50));
+        }
+        assert_eq!(w.size(), 8);
+    }
+
+    #[test]
+    fn test_decrease() {
+        let mut w = Window::new();
+
+        w.on_loss(1000, 100);
+        assert_eq!(w.size(), 4);
+        // Several losses within one round-trip decrease the window only once.
+        w.on_loss(1050, 100);
+        assert_eq!(w.size(), 4);
+        w.on_loss(1200, 100);
+        assert_eq!(w.size(), 2);
+        w.on_loss(1400, 100);
+        assert_eq!(w.size(), 2);
+    }
+}